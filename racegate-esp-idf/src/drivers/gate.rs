@@ -1,15 +1,43 @@
-use esp_idf_hal::gpio::{AnyInputPin, Input, PinDriver};
-use racegate::hal::gate::{Gate, GateState};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::gpio::{AnyInputPin, Input, InterruptType, PinDriver};
+use racegate::hal::gate::{Gate, GateEvent, GateState};
+
+/// Maximum number of edges waiting to be taken. When full, new edges are
+/// dropped and counted.
+const QUEUE_SIZE: usize = 32;
 
 pub struct EspGate {
     input: PinDriver<'static, AnyInputPin, Input>,
+    edges: Arc<EdgeQueue>,
 }
 
 impl EspGate {
     pub fn new(pin: AnyInputPin) -> anyhow::Result<EspGate> {
         let input = PinDriver::input(pin)?;
-        let input = input.into_input()?;
-        Ok(Self { input })
+        let mut input = input.into_input()?;
+
+        let edges = Arc::new(EdgeQueue::default());
+        let isr_edges = edges.clone();
+        let pin = input.pin();
+
+        input.set_interrupt_type(InterruptType::AnyEdge)?;
+
+        // Safety: the callback runs in interrupt context, it only reads the
+        // timer and the pin level and writes atomics.
+        unsafe {
+            input.subscribe(move || {
+                let time_us = esp_idf_sys::esp_timer_get_time() as u32;
+                let active = esp_idf_sys::gpio_get_level(pin) == 0;
+                isr_edges.push(time_us, active);
+            })?;
+        }
+
+        input.enable_interrupt()?;
+
+        Ok(Self { input, edges })
     }
 }
 
@@ -21,4 +49,73 @@ impl Gate for EspGate {
             GateState::Inactive
         }
     }
+
+    fn take_event(&self) -> Option<GateEvent> {
+        let (time_us, active) = self.edges.pop()?;
+
+        // Edges are timestamped with the esp timer, which can be read in
+        // interrupt context. Here it is converted to an Instant by its age.
+        let now = Instant::now();
+        let now_us = unsafe { esp_idf_sys::esp_timer_get_time() } as u32;
+        let age = Duration::from_micros(now_us.wrapping_sub(time_us) as u64);
+
+        let state = if active {
+            GateState::Active
+        } else {
+            GateState::Inactive
+        };
+
+        Some(GateEvent {
+            state,
+            time: now.checked_sub(age)?,
+        })
+    }
+
+    fn dropped_events(&self) -> u32 {
+        self.edges.overflow_count.load(Ordering::Relaxed)
+    }
+}
+
+/// Lock free queue with a single producer (the interrupt handler) and a single
+/// consumer (the main loop). Each edge is the time in microseconds, wrapping
+/// every ~71 minutes, and the level after it.
+#[derive(Default)]
+struct EdgeQueue {
+    times_us: [AtomicU32; QUEUE_SIZE],
+    levels: [AtomicBool; QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// Edges dropped because the queue was full
+    overflow_count: AtomicU32,
+}
+
+impl EdgeQueue {
+    fn push(&self, time_us: u32, active: bool) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let next = (head + 1) % QUEUE_SIZE;
+
+        if next == tail {
+            self.overflow_count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.times_us[head].store(time_us, Ordering::Relaxed);
+        self.levels[head].store(active, Ordering::Relaxed);
+        self.head.store(next, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<(u32, bool)> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let time_us = self.times_us[tail].load(Ordering::Relaxed);
+        let active = self.levels[tail].load(Ordering::Relaxed);
+        self.tail.store((tail + 1) % QUEUE_SIZE, Ordering::Release);
+        Some((time_us, active))
+    }
 }
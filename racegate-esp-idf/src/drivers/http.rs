@@ -4,13 +4,14 @@ use std::thread::JoinHandle;
 use std::time::Instant;
 use std::{thread::sleep, time::Duration};
 
-use embedded_svc::http::Method;
-use embedded_svc::io::Write;
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::{Read, Write};
 use embedded_svc::ws::FrameType;
 use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
 use esp_idf_svc::http::server::{Configuration, EspHttpServer};
 use esp_idf_sys::EspError;
-use racegate::app::SystemState;
+use racegate::app::{OperatorCommand, SystemState};
+use racegate::svc::OperatorToken;
 
 struct StateSender {
     ws: EspHttpWsDetachedSender,
@@ -71,15 +72,41 @@ impl StateSenders {
     }
 }
 
+#[derive(Clone)]
+struct OperatorCommands(Arc<Mutex<VecDeque<OperatorCommand>>>);
+
+impl OperatorCommands {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(VecDeque::<OperatorCommand>::new())))
+    }
+
+    fn push(&self, command: OperatorCommand) {
+        if let Ok(mut commands) = self.0.lock() {
+            commands.push_back(command);
+        }
+    }
+
+    fn pop(&self) -> Option<OperatorCommand> {
+        // try_lock is used because the app loop must not wait for the http
+        // handler. The command is just taken in the next loop.
+        self.0.try_lock().ok()?.pop_front()
+    }
+}
+
 pub struct HttpServer {
     #[allow(dead_code)]
     esp_http_server: EspHttpServer,
     app_state: Arc<Mutex<SystemState>>,
+    operator_commands: OperatorCommands,
     #[allow(dead_code)]
     send_task: JoinHandle<()>,
 }
 
-fn add_handlers(server: &mut EspHttpServer) -> anyhow::Result<StateSenders> {
+fn add_handlers(
+    server: &mut EspHttpServer,
+    operator_commands: OperatorCommands,
+    operator_token: OperatorToken,
+) -> anyhow::Result<StateSenders> {
     let state_senders = StateSenders::new();
     let state_senders_copy = state_senders.clone();
 
@@ -125,6 +152,37 @@ fn add_handlers(server: &mut EspHttpServer) -> anyhow::Result<StateSenders> {
         },
     )?;
 
+    server.fn_handler("/command", Method::Post, move |mut request| {
+        // Commands can abort races and reboot gates, so only somebody knowing
+        // the network key can send them
+        if !operator_token.verify(request.header("Authorization")) {
+            request.into_response(401, Some("Unauthorized"), &[])?;
+            return Ok(());
+        }
+
+        // Big enough for the racers registry
+        let mut buf = vec![0u8; 4096];
+        let mut len = 0;
+
+        while len < buf.len() {
+            let n = request.read(&mut buf[len..])?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+
+        if let Ok(command) = serde_json::from_slice::<OperatorCommand>(&buf[..len]) {
+            log::info!("operator command: {:?}", command);
+            operator_commands.push(command);
+            request.into_ok_response()?;
+        } else {
+            request.into_response(400, Some("Invalid command"), &[])?;
+        }
+
+        Ok(())
+    })?;
+
     server.ws_handler("/test", |conn| -> Result<(), EspError> {
         let frame_type = FrameType::Binary(false);
         let data = "test".as_bytes();
@@ -181,17 +239,23 @@ fn spawn_send_task(state_senders: StateSenders, state: Arc<Mutex<SystemState>>)
 }
 
 impl HttpServer {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(operator_token: OperatorToken) -> anyhow::Result<Self> {
         let conf = Configuration::default();
         let mut esp_http_server = EspHttpServer::new(&conf)?;
         let app_state = Arc::new(Mutex::new(Default::default()));
-        let state_senders = add_handlers(&mut esp_http_server)?;
+        let operator_commands = OperatorCommands::new();
+        let state_senders = add_handlers(
+            &mut esp_http_server,
+            operator_commands.clone(),
+            operator_token,
+        )?;
 
         let send_task = spawn_send_task(state_senders.clone(), app_state.clone());
 
         Ok(HttpServer {
             esp_http_server,
             app_state,
+            operator_commands,
             send_task,
         })
     }
@@ -209,6 +273,10 @@ impl racegate::svc::HttpServer for HttpServer {
             })
             .ok();
     }
+
+    fn take_operator_command(&self) -> Option<OperatorCommand> {
+        self.operator_commands.pop()
+    }
 }
 
 fn index_html() -> &'static [u8] {
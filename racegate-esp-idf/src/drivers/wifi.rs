@@ -17,7 +17,9 @@ pub struct EspWifi {
 }
 
 fn to_esp_wifi_config(src: &WifiConfig) -> anyhow::Result<Configuration> {
-    let &WifiConfig { ap, ssid, password } = src;
+    let &WifiConfig {
+        ap, ssid, password, ..
+    } = src;
 
     if ssid.is_empty() {
         bail!("Wi-Fi SSID must be non-empty")
@@ -103,4 +105,13 @@ impl Wifi for EspWifi {
             esp_wifi.connect().expect("Cannot connect");
         }
     }
+
+    fn rssi(&self) -> Option<i8> {
+        let mut info = esp_idf_sys::wifi_ap_record_t::default();
+
+        // Fails when not connected, like in access point mode
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+
+        Some(info.rssi)
+    }
 }
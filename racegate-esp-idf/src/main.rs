@@ -1,8 +1,9 @@
 use std::time::{Duration, Instant};
 
 use esp_idf_sys as _;
-use racegate::app::App;
+use racegate::app::{node_address, App};
 use racegate::hal::wifi::WifiConfig;
+use racegate::svc::race_node::SystemId;
 
 use racegate_esp_idf::platform::{BoardType, Config, PlatformImpl};
 
@@ -14,6 +15,7 @@ fn main() -> anyhow::Result<()> {
 
     let config = Config {
         wifi: WifiConfig::from_env_var().unwrap_or_default(),
+        system_id: SystemId::from_env_var().unwrap_or_default(),
         #[cfg(feature = "m5stampc3")]
         board_type: BoardType::M5StampC3,
         #[cfg(feature = "rustdevkit")]
@@ -21,10 +23,19 @@ fn main() -> anyhow::Result<()> {
     };
 
     log::info!("Create platform");
-    let mut p = PlatformImpl::new(&config);
+    let p = PlatformImpl::new(&config);
+
+    if config.wifi.has_default_network_key() {
+        // Anybody can forge frames signed with the default key, so it can
+        // only be used to try gates on the bench
+        if node_address(&p).is_coordinator() {
+            anyhow::bail!("Coordinator needs a network key, see RACEGATE_WIFI_CONFIG");
+        }
+        log::warn!("Default network key in use, race node frames are not authenticated");
+    }
 
     log::info!("Create app");
-    let mut app = App::new(&mut p);
+    let mut app = App::new(&p);
 
     log::info!("Start loop");
 
@@ -1,12 +1,14 @@
 use esp_idf_hal::gpio::InputPin;
 use esp_idf_hal::peripherals::Peripherals;
 use racegate::hal::button::Button;
+use racegate::hal::clock::{Clock, SystemClock};
 use racegate::hal::dip_switch::DipSwitch;
 use racegate::hal::gate::Gate;
 use racegate::hal::rgb_led::RgbLed;
 use racegate::hal::wifi::{Wifi, WifiConfig};
 use racegate::hal::Platform;
-use racegate::svc::{HttpServer, RaceNode};
+use racegate::svc::race_node::{HardwareId, SystemId};
+use racegate::svc::{HttpServer, NetworkKey, NodeConfig, OperatorToken, RaceNode};
 
 use crate::drivers::button::EspButton;
 use crate::drivers::dip_switch::EspDipSwitch;
@@ -29,11 +31,14 @@ pub struct PlatformImpl {
     http_server: EspHttpServer,
     race_node: EspRaceNode,
     dip_switch: EspDipSwitch,
+    clock: SystemClock,
+    hardware_id: HardwareId,
 }
 
 pub struct Config {
     pub wifi: WifiConfig<'static>,
     pub board_type: BoardType,
+    pub system_id: SystemId,
 }
 
 impl PlatformImpl {
@@ -63,10 +68,20 @@ impl PlatformImpl {
 
         let gate = EspGate::new(gate_pin).expect("Cannot setup gate");
         let button = EspButton::new(button_pin).expect("Cannot setup button");
-        let http_server = EspHttpServer::new().expect("Cannot setup http server");
-        let race_node = EspRaceNode::new().expect("Cannot setup race node");
+        let operator_token = OperatorToken::from_passphrase(config.wifi.network_key);
+        let http_server = EspHttpServer::new(operator_token).expect("Cannot setup http server");
+        let race_node = EspRaceNode::new(NodeConfig {
+            key: NetworkKey::from_passphrase(config.wifi.network_key),
+            system_id: config.system_id,
+        })
+        .expect("Cannot setup race node");
         let dip_switch = EspDipSwitch::new(dip_switch_pins).expect("Cannot setup dip switch");
 
+        // The factory MAC address is unique to the chip
+        let mut mac = [0; 6];
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) })
+            .expect("Cannot read MAC address");
+
         Self {
             wifi,
             rgb_led,
@@ -75,6 +90,8 @@ impl PlatformImpl {
             http_server,
             race_node,
             dip_switch,
+            clock: SystemClock,
+            hardware_id: HardwareId(mac),
         }
     }
 }
@@ -96,6 +113,10 @@ impl Platform for PlatformImpl {
         &self.button
     }
 
+    fn clock(&self) -> &(dyn Clock + '_) {
+        &self.clock
+    }
+
     fn http_server(&self) -> &(dyn HttpServer + '_) {
         &self.http_server
     }
@@ -107,4 +128,18 @@ impl Platform for PlatformImpl {
     fn dip_switch(&self) -> &(dyn DipSwitch + '_) {
         &self.dip_switch
     }
+
+    fn hardware_id(&self) -> HardwareId {
+        self.hardware_id
+    }
+
+    fn supply_voltage_mv(&self) -> Option<u16> {
+        // Not measured on the supported boards
+        None
+    }
+
+    fn reboot(&self) {
+        log::info!("Rebooting");
+        unsafe { esp_idf_sys::esp_restart() };
+    }
 }
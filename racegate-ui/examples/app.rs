@@ -3,9 +3,38 @@
 use std::time::Duration;
 
 use dioxus_desktop::Config as DesktopConfig;
-use racegate::app::{Gate, Gates, Race, SystemState};
+use racegate::app::{
+    Course, CourseGate, Gate, GateRole, Gates, Laps, Race, RaceState, Racer, Split, SystemState,
+    Timing,
+};
+use racegate::svc::race_node::{
+    FirmwareVersion, GateDiagnostics, NodeAddress, NodeStats, SyncQuality, SystemId,
+};
 use racegate::svc::CoordinatedInstant;
 use racegate_ui::app::{Dashboard, DashboardProps};
+use racegate_ui::format::Precision;
+
+fn running(races: Vec<Race>) -> Timing {
+    let mut timing = Timing::default();
+    timing.running.extend(races);
+    timing
+}
+
+fn finished(races: Vec<Race>) -> Timing {
+    let mut timing = Timing::default();
+    for race in races {
+        timing.history.push(race);
+    }
+    timing
+}
+
+fn make_racer(bib: u16, name: &str) -> Racer {
+    Racer {
+        bib,
+        name: name.to_owned(),
+        category: "U16".to_owned(),
+    }
+}
 
 fn test_default() -> SystemState {
     SystemState::default()
@@ -19,16 +48,33 @@ fn test_start_gate_active() -> SystemState {
                 active: true,
                 last_activation_time: Some(CoordinatedInstant::from_millis(1000)),
                 last_beacon_time: Some(CoordinatedInstant::from_millis(1000)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                diagnostics: Some(GateDiagnostics {
+                    addr: NodeAddress::from(1),
+                    firmware_version: FirmwareVersion::current(),
+                    uptime: Duration::from_secs(4_000),
+                    rssi: Some(-67),
+                    supply_voltage_mv: Some(4_950),
+                    loop_overruns: 0,
+                    dropped_gate_events: 0,
+                    stats: NodeStats::default(),
+                }),
+                ..Default::default()
             },
             Gate::default(),
             Gate::default(),
             Gate::default(),
         ]),
-        race: Race {
-            start_time: None,
-            finish_time: None,
-            duration: None,
-        },
+        timing: running(vec![Race {
+            state: RaceState::Running,
+            start_time: Some(CoordinatedInstant::from_millis(1000)),
+            ..Default::default()
+        }]),
+        ..Default::default()
     }
 }
 
@@ -40,6 +86,12 @@ fn test_race_finished() -> SystemState {
                 active: false,
                 last_activation_time: Some(CoordinatedInstant::from_millis(1000)),
                 last_beacon_time: Some(CoordinatedInstant::from_millis(5000)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
             },
             Gate::default(),
             Gate::default(),
@@ -47,13 +99,22 @@ fn test_race_finished() -> SystemState {
                 active: true,
                 last_activation_time: Some(CoordinatedInstant::from_millis(3456)),
                 last_beacon_time: Some(CoordinatedInstant::from_millis(5000)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
             },
         ]),
-        race: Race {
-            start_time: None,
-            finish_time: None,
+        timing: finished(vec![Race {
+            state: RaceState::Finished,
+            start_time: Some(CoordinatedInstant::from_millis(1000)),
+            finish_time: Some(CoordinatedInstant::from_millis(3456)),
             duration: Some(Duration::from_millis(2456)),
-        },
+            ..Default::default()
+        }]),
+        ..Default::default()
     }
 }
 
@@ -65,6 +126,12 @@ fn test_gate_dead() -> SystemState {
                 active: false,
                 last_activation_time: Some(CoordinatedInstant::from_millis(1000)),
                 last_beacon_time: Some(CoordinatedInstant::from_millis(0)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
             },
             Gate::default(),
             Gate::default(),
@@ -72,13 +139,190 @@ fn test_gate_dead() -> SystemState {
                 active: true,
                 last_activation_time: Some(CoordinatedInstant::from_millis(3456)),
                 last_beacon_time: Some(CoordinatedInstant::from_millis(0)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
             },
         ]),
-        race: Race {
-            start_time: None,
-            finish_time: None,
+        timing: finished(vec![Race {
+            state: RaceState::Finished,
+            start_time: Some(CoordinatedInstant::from_millis(1000)),
+            finish_time: Some(CoordinatedInstant::from_millis(3456)),
             duration: Some(Duration::from_millis(2456)),
-        },
+            ..Default::default()
+        }]),
+        ..Default::default()
+    }
+}
+
+fn test_race_with_splits() -> SystemState {
+    SystemState {
+        time: CoordinatedInstant::from_millis(9000),
+        gates: Gates::new([
+            Gate {
+                active: false,
+                last_activation_time: Some(CoordinatedInstant::from_millis(1000)),
+                last_beacon_time: Some(CoordinatedInstant::from_millis(9000)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
+            },
+            Gate {
+                active: false,
+                last_activation_time: Some(CoordinatedInstant::from_millis(3210)),
+                last_beacon_time: Some(CoordinatedInstant::from_millis(9000)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
+            },
+            Gate {
+                active: false,
+                last_activation_time: Some(CoordinatedInstant::from_millis(5678)),
+                last_beacon_time: Some(CoordinatedInstant::from_millis(9000)),
+                sync: Some(SyncQuality {
+                    offset_us: 1_234_567,
+                    error: Duration::from_micros(250),
+                    loss_percent: 2,
+                }),
+                ..Default::default()
+            },
+            Gate::default(),
+        ]),
+        timing: running(vec![Race {
+            state: RaceState::Running,
+            start_time: Some(CoordinatedInstant::from_millis(1000)),
+            splits: vec![
+                Split {
+                    addr: NodeAddress::from(2),
+                    time: CoordinatedInstant::from_millis(3210),
+                    elapsed: Duration::from_millis(2210),
+                    segment: Duration::from_millis(2210),
+                },
+                Split {
+                    addr: NodeAddress::from(3),
+                    time: CoordinatedInstant::from_millis(5678),
+                    elapsed: Duration::from_millis(4678),
+                    segment: Duration::from_millis(2468),
+                },
+            ],
+            ..Default::default()
+        }]),
+        ..Default::default()
+    }
+}
+
+fn test_two_races_running() -> SystemState {
+    SystemState {
+        time: CoordinatedInstant::from_millis(9000),
+        gates: Gates::default(),
+        timing: running(vec![
+            Race {
+                id: 1,
+                state: RaceState::Running,
+                start_time: Some(CoordinatedInstant::from_millis(1000)),
+                ..Default::default()
+            },
+            Race {
+                id: 2,
+                state: RaceState::Running,
+                start_time: Some(CoordinatedInstant::from_millis(4000)),
+                ..Default::default()
+            },
+        ]),
+        ..Default::default()
+    }
+}
+
+fn test_session_history() -> SystemState {
+    let races = (1..=6)
+        .map(|id| {
+            let start_ms = (id as i32) * 20_000;
+            let duration_ms = 11_000 + ((id * 7_919) % 3_000) as i32;
+            Race {
+                id,
+                racer: Some(make_racer(id as u16, "Racer")),
+                state: RaceState::Finished,
+                start_time: Some(CoordinatedInstant::from_millis(start_ms)),
+                finish_time: Some(CoordinatedInstant::from_millis(start_ms + duration_ms)),
+                duration: Some(Duration::from_millis(duration_ms as u64)),
+                poor_sync: id == 4,
+                recovered: id == 5,
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    SystemState {
+        time: CoordinatedInstant::from_millis(200_000),
+        gates: Gates::default(),
+        timing: finished(races),
+        next_racer: Some(make_racer(7, "Giulia")),
+        foreign_systems: vec![SystemId(2)],
+        ..Default::default()
+    }
+}
+
+fn test_seven_gates() -> SystemState {
+    let time = CoordinatedInstant::from_millis(5000);
+    let gate = Gate {
+        active: false,
+        last_activation_time: None,
+        last_beacon_time: Some(time),
+        sync: Some(SyncQuality {
+            offset_us: 1_234_567,
+            error: Duration::from_micros(250),
+            loss_percent: 2,
+        }),
+        ..Default::default()
+    };
+
+    SystemState {
+        time,
+        gates: Gates::new(vec![gate; 7]),
+        ..Default::default()
+    }
+}
+
+fn test_lap_session() -> SystemState {
+    let mut timing = Timing::default();
+    timing.course = Course {
+        gates: vec![CourseGate {
+            addr: NodeAddress::from(1),
+            role: GateRole::Lap,
+        }],
+    };
+    timing.laps = Laps::new(CoordinatedInstant::from_millis(0));
+
+    for t in [1_000, 31_200, 60_800, 91_000, 120_500] {
+        timing
+            .laps
+            .pass(CoordinatedInstant::from_millis(t), false, false);
+    }
+
+    SystemState {
+        time: CoordinatedInstant::from_millis(132_000),
+        gates: Gates::new([Gate {
+            active: false,
+            last_activation_time: Some(CoordinatedInstant::from_millis(120_500)),
+            last_beacon_time: Some(CoordinatedInstant::from_millis(132_000)),
+            sync: Some(SyncQuality {
+                offset_us: 1_234_567,
+                error: Duration::from_micros(250),
+                loss_percent: 2,
+            }),
+            ..Default::default()
+        }]),
+        timing,
+        ..Default::default()
     }
 }
 
@@ -92,16 +336,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         test_start_gate_active,
         test_race_finished,
         test_gate_dead,
+        test_race_with_splits,
+        test_two_races_running,
+        test_session_history,
+        test_seven_gates,
+        test_lap_session,
     ];
 
     let args: Vec<_> = std::env::args().collect();
     let test_id = args.get(1).map(|x| x.parse().unwrap()).unwrap_or(0);
     let test = table.get(test_id).expect("Invalid test id");
     let system_state = test();
+    let precision = args
+        .get(2)
+        .and_then(|x| x.parse().ok())
+        .and_then(Precision::from_digits)
+        .unwrap_or_default();
 
     let config = DesktopConfig::new().with_custom_head(custom_head());
 
-    let props = DashboardProps { system_state };
+    let props = DashboardProps {
+        system_state,
+        precision,
+    };
     dioxus_desktop::launch_with_props(Dashboard, props, config);
     Ok(())
 }
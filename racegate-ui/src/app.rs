@@ -1,13 +1,28 @@
-use std::{rc::Rc, time::Duration};
+use std::{cell::RefCell, rc::Rc, time::Duration};
 
 use dioxus::prelude::*;
 use dioxus_websocket_hooks::use_ws_context_provider_json;
 use fermi::{use_init_atom_root, use_read, use_set, Atom};
-use racegate::app::{gates::Gate, SystemState};
+use racegate::app::{
+    gates::Gate, AddressConflict, GateRole, History, Lap, Laps, OperatorCommand, Race, RaceState,
+    Racer, Split, SystemState,
+};
+use racegate::svc::race_node::{GateCommand, GateDiagnostics, NodeAddress, SystemId};
+use racegate::svc::OperatorToken;
 use racegate::CoordinatedInstant;
 
+use crate::format::{format_delta, format_duration, Precision};
+
 pub static SYSTEM_STATE: Atom<Option<SystemState>> = |_| None;
 
+pub static DISPLAY_PRECISION: Atom<Precision> = |_| Precision::default();
+
+thread_local! {
+    /// Sent with operator commands, derived from the network key typed by the
+    /// operator
+    static OPERATOR_TOKEN: RefCell<Option<OperatorToken>> = RefCell::new(None);
+}
+
 #[allow(non_snake_case)]
 pub fn App(cx: Scope) -> Element {
     use_init_atom_root(cx);
@@ -22,48 +37,272 @@ pub fn App(cx: Scope) -> Element {
     cx.render(rsx!(
         h1 { "racegate" },
         Main { },
+        PrecisionSelector { },
+        NetworkKeyInput { },
     ))
 }
 
 #[allow(non_snake_case)]
 fn Main(cx: Scope) -> Element {
+    let precision = *use_read(cx, DISPLAY_PRECISION);
+
     if let Some(system_state) = use_read(cx, SYSTEM_STATE) {
         cx.render(rsx!(Dashboard {
-            system_state: system_state.clone()
+            system_state: system_state.clone(),
+            precision: precision
         }))
     } else {
         cx.render(rsx!(div { "loading..." }))
     }
 }
 
+/// Number of decimals shown for times
+#[allow(non_snake_case)]
+fn PrecisionSelector(cx: Scope) -> Element {
+    let digits = use_read(cx, DISPLAY_PRECISION).digits();
+    let set_precision = use_set(cx, DISPLAY_PRECISION);
+
+    cx.render(rsx!(
+        select {
+            class: "precision",
+            value: "{digits}",
+            onchange: move |evt| {
+                let precision = evt.value.parse().ok().and_then(Precision::from_digits);
+                if let Some(precision) = precision {
+                    set_precision(precision);
+                }
+            },
+            option { value: "1", "0.1" }
+            option { value: "2", "0.01" }
+            option { value: "3", "0.001" }
+        }
+    ))
+}
+
+/// The coordinator only accepts operator commands from who knows the network
+/// key
+#[allow(non_snake_case)]
+fn NetworkKeyInput(cx: Scope) -> Element {
+    cx.render(rsx!(input {
+        class: "network-key",
+        r#type: "password",
+        placeholder: "network key",
+        onchange: move |evt| {
+            let token = Some(OperatorToken::from_passphrase(&evt.value));
+            OPERATOR_TOKEN.with(|x| *x.borrow_mut() = token);
+        },
+    }))
+}
+
 #[allow(non_snake_case)]
 #[inline_props]
-pub fn Dashboard(cx: Scope<'a>, system_state: SystemState) -> Element {
-    let duration = system_state.race.duration();
+pub fn Dashboard(
+    cx: Scope<'a>,
+    system_state: SystemState,
+    #[props(default)] precision: Precision,
+) -> Element {
+    let precision = *precision;
+    let race = system_state.timing.current();
+    let duration = race.elapsed(system_state.time);
+    let race_state = race.state();
+
+    let course = &system_state.timing.course;
+
+    let splits = race.splits().iter().cloned().map(|split| {
+        let addr = split.addr;
+        let name = course
+            .role(addr)
+            .map(gate_name)
+            .unwrap_or_else(|| "Split".to_owned());
+
+        rsx!(SplitComponent {
+            key: "{addr:?}",
+            name: name,
+            split: split,
+            precision: precision
+        })
+    });
 
-    let start_gate = system_state.gates.start_gate().clone();
-    let finish_gate = system_state.gates.finish_gate().clone();
+    let course_gates = course.gates.iter().map(|course_gate| {
+        let addr = course_gate.addr;
+        let gate = system_state.gates.get(addr).cloned().unwrap_or_default();
+        (addr, gate_name(course_gate.role), gate)
+    });
+
+    // Gates sending beacons, but not part of the course
+    let other_gates = system_state
+        .gates
+        .iter()
+        .filter(|(addr, gate)| course.role(*addr).is_none() && gate.is_alive(system_state.time))
+        .map(|(addr, gate)| {
+            let name = format!("Gate {}", addr.unwrap_as_gate_index() + 1);
+            (addr, name, gate.clone())
+        });
+
+    let gates = course_gates.chain(other_gates).map(|(addr, name, gate)| {
+        rsx!(GateComponent {
+            key: "{addr:?}",
+            addr: addr,
+            name: name,
+            gate: gate,
+            time: system_state.time,
+            precision: precision
+        })
+    });
+
+    if course.lap().is_some() {
+        return cx.render(rsx!(
+            ForeignSystemsComponent {
+                systems: system_state.foreign_systems.clone()
+            },
+            AddressConflictsComponent {
+                conflicts: system_state.roster.conflicts().to_vec()
+            },
+            LapsComponent {
+                laps: system_state.timing.laps.clone(),
+                time: system_state.time,
+                precision: precision
+            },
+            div {
+                class: "gates",
+                gates
+            },
+        ));
+    }
 
     cx.render(rsx!(
-        DurationComponent { duration: duration },
-        GateComponent {
-            name: "Start".to_owned(),
-            gate: start_gate,
-            time: system_state.time
+        ForeignSystemsComponent {
+            systems: system_state.foreign_systems.clone()
         },
-        GateComponent {
-            name: "Finish".to_owned(),
-            gate: finish_gate,
-            time: system_state.time
+        AddressConflictsComponent {
+            conflicts: system_state.roster.conflicts().to_vec()
+        },
+        RaceStateComponent {
+            race_state: race_state
+        },
+        RacerComponent {
+            class: "racer-current",
+            racer: system_state.current_racer.clone()
+        },
+        DurationComponent {
+            duration: duration,
+            precision: precision
+        },
+        div {
+            class: "splits",
+            splits
+        },
+        RunningRacesComponent {
+            races: system_state.timing.running.iter().cloned().collect(),
+            time: system_state.time,
+            precision: precision
+        },
+        div {
+            class: "gates",
+            gates
+        },
+        RacerComponent {
+            class: "racer-next",
+            racer: system_state.next_racer.clone()
+        },
+        LeaderboardComponent {
+            history: system_state.timing.history.clone(),
+            precision: precision
+        },
+        HistoryComponent {
+            history: system_state.timing.history.clone(),
+            precision: precision
         },
     ))
 }
 
+/// Other systems are on the same network, which is fine unless they are
+/// expected to be this one
 #[allow(non_snake_case)]
 #[inline_props]
-fn DurationComponent(cx: Scope, #[props(!optional)] duration: Option<Duration>) -> Element {
+fn ForeignSystemsComponent(cx: Scope, systems: Vec<SystemId>) -> Element {
+    if systems.is_empty() {
+        return None;
+    }
+
+    let ids = systems
+        .iter()
+        .map(|x| x.0.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    cx.render(rsx!(
+        div {
+            class: "foreign-systems",
+            "Other systems on this network: {ids}"
+        }
+    ))
+}
+
+/// Nodes sharing an address are mixed up, races are not armed until one of
+/// them is given another address
+#[allow(non_snake_case)]
+#[inline_props]
+fn AddressConflictsComponent(cx: Scope, conflicts: Vec<AddressConflict>) -> Element {
+    if conflicts.is_empty() {
+        return None;
+    }
+
+    let items = conflicts.iter().map(|conflict| {
+        let name = if conflict.addr.is_coordinator() {
+            "Coordinator".to_owned()
+        } else {
+            format!("Gate {}", conflict.addr.unwrap_as_gate_index() + 1)
+        };
+
+        let ids = conflict
+            .hardware_ids
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        rsx!(div {
+            key: "{conflict.addr:?}",
+            "{name} address used by {ids}"
+        })
+    });
+
+    cx.render(rsx!(div {
+        class: "address-conflicts",
+        items
+    }))
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn RaceStateComponent(cx: Scope, race_state: RaceState) -> Element {
+    let (class, text) = match race_state {
+        RaceState::Idle => ("race-state race-idle", "idle"),
+        RaceState::Armed => ("race-state race-armed", "ready"),
+        RaceState::Running => ("race-state race-running", "running"),
+        RaceState::Finished => ("race-state race-finished", "finished"),
+        RaceState::Aborted => ("race-state race-aborted", "aborted"),
+        RaceState::Dnf => ("race-state race-dnf", "DNF"),
+    };
+
+    cx.render(rsx!(
+        div {
+            class: class,
+            span { text }
+        }
+    ))
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn DurationComponent(
+    cx: Scope,
+    #[props(!optional)] duration: Option<Duration>,
+    precision: Precision,
+) -> Element {
     let duration_text = duration
-        .map(format_duration)
+        .map(|x| format_duration(x, *precision))
         .unwrap_or_else(|| "-".to_owned());
 
     cx.render(rsx!(
@@ -74,25 +313,336 @@ fn DurationComponent(cx: Scope, #[props(!optional)] duration: Option<Duration>)
     ))
 }
 
-fn format_duration(duration: Duration) -> String {
-    format!("{:.2}", duration.as_secs_f64())
+#[allow(non_snake_case)]
+#[inline_props]
+fn RacerComponent(
+    cx: Scope,
+    class: &'static str,
+    #[props(!optional)] racer: Option<Racer>,
+) -> Element {
+    let racer = racer.as_ref()?;
+    let category = &racer.category;
+
+    cx.render(rsx!(
+        div {
+            class: "racer {class}",
+            span { class: "racer-name", "{racer}" }
+            span { class: "racer-category", "{category}" }
+        }
+    ))
+}
+
+fn racer_name(racer: &Option<Racer>) -> String {
+    racer.as_ref().map(|x| x.to_string()).unwrap_or_default()
 }
 
-fn time_since_gate_activation(gate: &Gate, time: &CoordinatedInstant) -> String {
+/// Races on course, with operator controls to fix the finish order
+#[allow(non_snake_case)]
+#[inline_props]
+fn RunningRacesComponent(
+    cx: Scope,
+    races: Vec<Race>,
+    time: CoordinatedInstant,
+    precision: Precision,
+) -> Element {
+    // A single race is already shown by the dashboard
+    if races.len() < 2 {
+        return None;
+    }
+
+    let items = races.iter().map(|race| {
+        let id = race.id;
+        let racer = racer_name(&race.racer);
+        let elapsed = race
+            .elapsed(*time)
+            .map(|x| format_duration(x, *precision))
+            .unwrap_or_else(|| "-".to_owned());
+
+        rsx!(
+            div {
+                key: "{id}",
+                class: "running-race",
+                span {
+                    class: "running-race-id",
+                    "#{id}"
+                }
+                span {
+                    class: "running-race-racer",
+                    "{racer}"
+                }
+                span {
+                    class: "running-race-elapsed",
+                    "{elapsed}"
+                }
+                button {
+                    onclick: move |_| send_operator_command(&OperatorCommand::Promote { race: id }),
+                    "first"
+                }
+                button {
+                    onclick: move |_| send_operator_command(&OperatorCommand::Abort { race: id }),
+                    "abort"
+                }
+            }
+        )
+    });
+
+    cx.render(rsx!(div {
+        class: "running-races",
+        items
+    }))
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn LeaderboardComponent(cx: Scope, history: History, precision: Precision) -> Element {
+    if history.leaderboard.is_empty() {
+        return None;
+    }
+
+    let rows = history.leaderboard.iter().enumerate().map(|(i, entry)| {
+        let position = i + 1;
+        let race = entry.race;
+        let racer = racer_name(&entry.racer);
+        let duration = format_duration(entry.duration, *precision);
+
+        rsx!(
+            tr {
+                key: "{position}",
+                td { "{position}" }
+                td { "#{race}" }
+                td { "{racer}" }
+                td { "{duration}" }
+            }
+        )
+    });
+
+    cx.render(rsx!(
+        table {
+            class: "leaderboard",
+            caption { "Best times" }
+            rows
+        }
+    ))
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn HistoryComponent(cx: Scope, history: History, precision: Precision) -> Element {
+    if history.races.is_empty() {
+        return None;
+    }
+
+    // Most recent first
+    let rows = history.races.iter().rev().map(|race| {
+        let id = race.id;
+        let racer = racer_name(&race.racer);
+        let result = match race.state() {
+            RaceState::Finished => race
+                .duration()
+                .map(|x| format_duration(x, *precision))
+                .unwrap_or_else(|| "-".to_owned()),
+            RaceState::Dnf => "DNF".to_owned(),
+            _ => "aborted".to_owned(),
+        };
+        let splits = race
+            .splits()
+            .iter()
+            .map(|x| format_duration(x.elapsed, *precision))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let poor_sync = poor_sync_mark(race.poor_sync);
+        let recovered = recovered_mark(race.recovered);
+
+        rsx!(
+            tr {
+                key: "{id}",
+                td { "#{id}" }
+                td { "{racer}" }
+                td { "{result}" }
+                td { class: "poor-sync", "{poor_sync}" }
+                td { class: "recovered", "{recovered}" }
+                td { class: "history-splits", "{splits}" }
+            }
+        )
+    });
+
+    cx.render(rsx!(
+        table {
+            class: "history",
+            caption { "History" }
+            rows
+        }
+    ))
+}
+
+/// Lap timing session: current lap, last and best laps
+#[allow(non_snake_case)]
+#[inline_props]
+fn LapsComponent(cx: Scope, laps: Laps, time: CoordinatedInstant, precision: Precision) -> Element {
+    let lap_count = if laps.last_pass.is_some() {
+        format!("lap {}", laps.count + 1)
+    } else {
+        "waiting".to_owned()
+    };
+    let elapsed = laps.elapsed(*time);
+
+    let last = laps.last().cloned().map(|lap| {
+        rsx!(LapComponent {
+            class: "lap-last",
+            lap: lap,
+            precision: *precision
+        })
+    });
+
+    let best = laps.best.clone().map(|lap| {
+        rsx!(LapComponent {
+            class: "lap-best",
+            lap: lap,
+            precision: *precision
+        })
+    });
+
+    // Most recent first
+    let rows = laps.laps.iter().rev().cloned().map(|lap| {
+        let number = lap.number;
+        rsx!(LapComponent {
+            key: "{number}",
+            class: "lap",
+            lap: lap,
+            precision: *precision
+        })
+    });
+
+    cx.render(rsx!(
+        div {
+            class: "lap-count",
+            span { lap_count }
+        },
+        DurationComponent {
+            duration: elapsed,
+            precision: *precision
+        },
+        table {
+            class: "laps-summary",
+            last,
+            best
+        },
+        table {
+            class: "laps",
+            caption { "Laps" }
+            rows
+        },
+    ))
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn LapComponent(cx: Scope, class: &'static str, lap: Lap, precision: Precision) -> Element {
+    let number = lap.number;
+    let duration = format_duration(lap.duration, *precision);
+    let delta = lap
+        .delta_us
+        .map(|x| format_delta(x, *precision))
+        .unwrap_or_default();
+    let delta_class = match lap.delta_us {
+        Some(x) if x < 0 => "lap-delta lap-faster",
+        Some(_) => "lap-delta lap-slower",
+        None => "lap-delta",
+    };
+    let poor_sync = poor_sync_mark(lap.poor_sync);
+    let recovered = recovered_mark(lap.recovered);
+
+    cx.render(rsx!(
+        tr {
+            class: "{class}",
+            td { class: "lap-number", "{number}" }
+            td { class: "lap-duration", "{duration}" }
+            td { class: delta_class, "{delta}" }
+            td { class: "poor-sync", "{poor_sync}" }
+            td { class: "recovered", "{recovered}" }
+        }
+    ))
+}
+
+/// Results timed by gates with poor clock synchronization may be inaccurate
+fn poor_sync_mark(poor_sync: bool) -> &'static str {
+    if poor_sync {
+        "⚠"
+    } else {
+        ""
+    }
+}
+
+/// Results completed with activations delivered late, after a gate lost the
+/// coordinator
+fn recovered_mark(recovered: bool) -> &'static str {
+    if recovered {
+        "↻"
+    } else {
+        ""
+    }
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn SplitComponent(cx: Scope, name: String, split: Split, precision: Precision) -> Element {
+    let elapsed = format_duration(split.elapsed, *precision);
+    let segment = format_duration(split.segment, *precision);
+
+    cx.render(rsx!(
+        div {
+            class: "split",
+            span {
+                class: "split-name",
+                "{name}"
+            }
+            span {
+                class: "split-elapsed",
+                "{elapsed}"
+            }
+            span {
+                class: "split-segment",
+                "+{segment}"
+            }
+        }
+    ))
+}
+
+fn gate_name(role: GateRole) -> String {
+    match role {
+        GateRole::Start => "Start".to_owned(),
+        GateRole::Split(n) => format!("Split {n}"),
+        GateRole::Finish => "Finish".to_owned(),
+        GateRole::StartFinish => "Start/Finish".to_owned(),
+        GateRole::Lap => "Lap".to_owned(),
+    }
+}
+
+fn time_since_gate_activation(
+    gate: &Gate,
+    time: &CoordinatedInstant,
+    precision: Precision,
+) -> String {
     let Some(t) = gate.last_activation_time else {
         return "-".to_owned();
     };
 
-    let diff = Duration::from_millis((time.as_millis() - t.as_millis()) as u64);
-
-    format_duration(diff)
+    format_duration(time.duration_since(t), precision)
 }
 
 #[allow(non_snake_case)]
 #[inline_props]
-fn GateComponent(cx: Scope, name: String, gate: Gate, time: CoordinatedInstant) -> Element {
+fn GateComponent(
+    cx: Scope,
+    addr: NodeAddress,
+    name: String,
+    gate: Gate,
+    time: CoordinatedInstant,
+    precision: Precision,
+) -> Element {
     let alive = gate.is_alive(*time);
     let active = gate.is_active();
+    let addr = *addr;
 
     let alive_class = if alive { "gate-alive" } else { "gate-dead" };
 
@@ -102,7 +652,32 @@ fn GateComponent(cx: Scope, name: String, gate: Gate, time: CoordinatedInstant)
         "gate-inactive"
     };
 
-    let time = time_since_gate_activation(&gate, &time);
+    let time = time_since_gate_activation(&gate, &time, *precision);
+
+    let sync_error = gate
+        .sync
+        .map(|x| format!("±{:.1}ms", x.error.as_secs_f64() * 1000.0))
+        .unwrap_or_default();
+    let sync_offset = gate
+        .sync
+        .map(|x| format!("{:+.1}ms", x.offset_us as f64 / 1000.0))
+        .unwrap_or_default();
+    let sync_loss = gate
+        .sync
+        .map(|x| format!("{}% lost", x.loss_percent))
+        .unwrap_or_default();
+
+    let sync_class = if gate.has_poor_sync() {
+        "gate-sync gate-poor-sync"
+    } else {
+        "gate-sync"
+    };
+
+    let diagnostics = gate
+        .diagnostics
+        .as_ref()
+        .map(format_diagnostics)
+        .unwrap_or_default();
 
     cx.render(rsx!(
         div {
@@ -123,10 +698,64 @@ fn GateComponent(cx: Scope, name: String, gate: Gate, time: CoordinatedInstant)
                 class: active_class,
                 "{active}",
             }
+            span {
+                class: sync_class,
+                span {
+                    class: "gate-sync-error",
+                    "{sync_error}",
+                }
+                span {
+                    class: "gate-sync-offset",
+                    "{sync_offset}",
+                }
+                span {
+                    class: "gate-sync-loss",
+                    "{sync_loss}",
+                }
+            }
+            span {
+                class: "gate-diagnostics",
+                "{diagnostics}",
+            }
+            button {
+                onclick: move |_| send_operator_command(&OperatorCommand::Gate {
+                    addr,
+                    command: GateCommand::Identify,
+                }),
+                "identify"
+            }
         }
     ))
 }
 
+/// Firmware version, uptime, signal strength, supply voltage and overruns
+fn format_diagnostics(diagnostics: &GateDiagnostics) -> String {
+    let uptime_mins = diagnostics.uptime.as_secs() / 60;
+
+    let mut items = vec![
+        format!("v{}", diagnostics.firmware_version),
+        format!("up {}h{:02}m", uptime_mins / 60, uptime_mins % 60),
+    ];
+
+    if let Some(rssi) = diagnostics.rssi {
+        items.push(format!("{rssi}dBm"));
+    }
+
+    if let Some(mv) = diagnostics.supply_voltage_mv {
+        items.push(format!("{:.2}V", mv as f64 / 1000.0));
+    }
+
+    if diagnostics.loop_overruns > 0 {
+        items.push(format!("{} overruns", diagnostics.loop_overruns));
+    }
+
+    if diagnostics.dropped_gate_events > 0 {
+        items.push(format!("{} edges lost", diagnostics.dropped_gate_events));
+    }
+
+    items.join(" ")
+}
+
 fn hostname() -> Option<String> {
     #[cfg(target_family = "wasm")]
     {
@@ -139,6 +768,35 @@ fn hostname() -> Option<String> {
     }
 }
 
+fn send_operator_command(command: &OperatorCommand) {
+    #[cfg(target_family = "wasm")]
+    {
+        let Ok(body) = serde_json::to_string(command) else {
+            return;
+        };
+        let Ok(request) = web_sys::XmlHttpRequest::new() else {
+            return;
+        };
+        let authorization = OPERATOR_TOKEN.with(|x| x.borrow().as_ref().map(|x| x.authorization()));
+        // Rejected by the coordinator anyway
+        let Some(authorization) = authorization else {
+            return;
+        };
+        if request.open("POST", "/command").is_ok()
+            && request
+                .set_request_header("Authorization", &authorization)
+                .is_ok()
+        {
+            request.send_with_opt_str(Some(&body)).ok();
+        }
+    }
+    // Commands need the coordinator web server
+    #[cfg(not(target_family = "wasm"))]
+    {
+        let _ = command;
+    }
+}
+
 fn ws_url_from_hostname() -> String {
     const DEFAULT_HOSTNAME: &'static str = "192.168.71.1";
     let h = hostname().unwrap_or_else(|| DEFAULT_HOSTNAME.to_owned());
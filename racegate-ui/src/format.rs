@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// Number of decimals shown for times
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Precision {
+    Tenths,
+    #[default]
+    Hundredths,
+    Thousandths,
+}
+
+impl Precision {
+    pub fn digits(&self) -> u32 {
+        match self {
+            Precision::Tenths => 1,
+            Precision::Hundredths => 2,
+            Precision::Thousandths => 3,
+        }
+    }
+
+    pub fn from_digits(digits: u32) -> Option<Self> {
+        match digits {
+            1 => Some(Precision::Tenths),
+            2 => Some(Precision::Hundredths),
+            3 => Some(Precision::Thousandths),
+            _ => None,
+        }
+    }
+}
+
+/// Seconds, rounded to the nearest unit of the given precision
+pub fn format_duration(duration: Duration, precision: Precision) -> String {
+    let digits = precision.digits();
+    let unit = 10u128.pow(6 - digits);
+    let scale = 10u128.pow(digits);
+    let units = (duration.as_micros() + unit / 2) / unit;
+
+    format!(
+        "{}.{:0width$}",
+        units / scale,
+        units % scale,
+        width = digits as usize
+    )
+}
+
+/// Signed difference in microseconds, like `+0.12` or `-1.05`
+pub fn format_delta(delta_us: i64, precision: Precision) -> String {
+    let sign = if delta_us < 0 { '-' } else { '+' };
+    let duration = Duration::from_micros(delta_us.unsigned_abs());
+    format!("{}{}", sign, format_duration(duration, precision))
+}
@@ -0,0 +1,16 @@
+//! Print the `Authorization` header of operator commands, for the network key
+//! passphrase given as argument
+
+use racegate::svc::OperatorToken;
+
+fn main() {
+    let Some(passphrase) = std::env::args().nth(1) else {
+        eprintln!("Usage: operator_token <network key passphrase>");
+        std::process::exit(1);
+    };
+
+    println!(
+        "{}",
+        OperatorToken::from_passphrase(&passphrase).authorization()
+    );
+}
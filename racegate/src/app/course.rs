@@ -0,0 +1,236 @@
+use crate::svc::race_node::NodeAddress;
+
+/// What a gate is used for in the course
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum GateRole {
+    Start,
+    /// Intermediate gate, numbered from 1 in course order
+    Split(u8),
+    Finish,
+    /// Same gate for start and finish, for loop courses. Passes are not told
+    /// apart by racer, so only one racer can be on course at a time: the
+    /// next pass finishes the race running, even if it is the start of the
+    /// racer behind.
+    StartFinish,
+    /// Every pass closes a lap, for lap timing sessions
+    Lap,
+}
+
+impl GateRole {
+    pub fn is_start(&self) -> bool {
+        matches!(self, GateRole::Start | GateRole::StartFinish)
+    }
+
+    pub fn is_finish(&self) -> bool {
+        matches!(self, GateRole::Finish | GateRole::StartFinish)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CourseGate {
+    pub addr: NodeAddress,
+    pub role: GateRole,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CourseError {
+    NoStart,
+    NoFinish,
+    ManyStarts,
+    ManyFinishes,
+    CoordinatorAddress,
+    DuplicateAddress(NodeAddress),
+    DuplicateSplit(u8),
+    ManyLapGates,
+    /// A lap gate can't be mixed with start and finish gates
+    LapWithStartOrFinish,
+}
+
+/// Maps node addresses to gate roles, so the same hardware can be used for
+/// different course layouts.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Course {
+    pub gates: Vec<CourseGate>,
+}
+
+impl Default for Course {
+    /// Start at address 1, two splits and finish at address 4
+    fn default() -> Self {
+        Self {
+            gates: vec![
+                CourseGate {
+                    addr: NodeAddress::from(1),
+                    role: GateRole::Start,
+                },
+                CourseGate {
+                    addr: NodeAddress::from(2),
+                    role: GateRole::Split(1),
+                },
+                CourseGate {
+                    addr: NodeAddress::from(3),
+                    role: GateRole::Split(2),
+                },
+                CourseGate {
+                    addr: NodeAddress::from(4),
+                    role: GateRole::Finish,
+                },
+            ],
+        }
+    }
+}
+
+impl Course {
+    pub fn role(&self, addr: NodeAddress) -> Option<GateRole> {
+        self.gates.iter().find(|x| x.addr == addr).map(|x| x.role)
+    }
+
+    /// Address of the gate opening the races
+    pub fn start(&self) -> Option<NodeAddress> {
+        self.gates
+            .iter()
+            .find(|x| x.role.is_start())
+            .map(|x| x.addr)
+    }
+
+    /// Address of the gate closing the races
+    pub fn finish(&self) -> Option<NodeAddress> {
+        self.gates
+            .iter()
+            .find(|x| x.role.is_finish())
+            .map(|x| x.addr)
+    }
+
+    /// Address of the lap gate, only for lap timing courses
+    pub fn lap(&self) -> Option<NodeAddress> {
+        self.gates
+            .iter()
+            .find(|x| x.role == GateRole::Lap)
+            .map(|x| x.addr)
+    }
+
+    pub fn validate(&self) -> Result<(), CourseError> {
+        let starts = self.gates.iter().filter(|x| x.role.is_start()).count();
+        let finishes = self.gates.iter().filter(|x| x.role.is_finish()).count();
+        let laps = self
+            .gates
+            .iter()
+            .filter(|x| x.role == GateRole::Lap)
+            .count();
+
+        if laps > 1 {
+            return Err(CourseError::ManyLapGates);
+        }
+
+        if laps == 1 && (starts > 0 || finishes > 0) {
+            return Err(CourseError::LapWithStartOrFinish);
+        }
+
+        if laps == 0 {
+            match starts {
+                0 => return Err(CourseError::NoStart),
+                1 => {}
+                _ => return Err(CourseError::ManyStarts),
+            }
+
+            match finishes {
+                0 => return Err(CourseError::NoFinish),
+                1 => {}
+                _ => return Err(CourseError::ManyFinishes),
+            }
+        }
+
+        for (i, gate) in self.gates.iter().enumerate() {
+            if gate.addr.is_coordinator() {
+                return Err(CourseError::CoordinatorAddress);
+            }
+
+            let others = &self.gates[(i + 1)..];
+
+            if others.iter().any(|x| x.addr == gate.addr) {
+                return Err(CourseError::DuplicateAddress(gate.addr));
+            }
+
+            if let GateRole::Split(n) = gate.role {
+                if others.iter().any(|x| x.role == GateRole::Split(n)) {
+                    return Err(CourseError::DuplicateSplit(n));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_gate(addr: u8, role: GateRole) -> CourseGate {
+        CourseGate {
+            addr: NodeAddress::from(addr),
+            role,
+        }
+    }
+
+    #[test]
+    fn test_default_course_is_valid() {
+        let course = Course::default();
+        assert_eq!(course.validate(), Ok(()));
+        assert_eq!(course.start(), Some(NodeAddress::from(1)));
+        assert_eq!(course.finish(), Some(NodeAddress::from(4)));
+    }
+
+    #[test]
+    fn test_loop_course() {
+        let course = Course {
+            gates: vec![
+                make_gate(3, GateRole::StartFinish),
+                make_gate(5, GateRole::Split(1)),
+            ],
+        };
+        assert_eq!(course.validate(), Ok(()));
+        assert_eq!(course.start(), course.finish());
+        assert_eq!(course.role(NodeAddress::from(5)), Some(GateRole::Split(1)));
+        assert_eq!(course.role(NodeAddress::from(1)), None);
+    }
+
+    #[test]
+    fn test_lap_course() {
+        let course = Course {
+            gates: vec![make_gate(2, GateRole::Lap)],
+        };
+        assert_eq!(course.validate(), Ok(()));
+        assert_eq!(course.lap(), Some(NodeAddress::from(2)));
+        assert_eq!(course.start(), None);
+
+        let course = Course {
+            gates: vec![make_gate(2, GateRole::Lap), make_gate(3, GateRole::Finish)],
+        };
+        assert_eq!(course.validate(), Err(CourseError::LapWithStartOrFinish));
+    }
+
+    #[test]
+    fn test_invalid_courses() {
+        let course = Course {
+            gates: vec![make_gate(1, GateRole::Start)],
+        };
+        assert_eq!(course.validate(), Err(CourseError::NoFinish));
+
+        let course = Course {
+            gates: vec![
+                make_gate(1, GateRole::StartFinish),
+                make_gate(2, GateRole::Finish),
+            ],
+        };
+        assert_eq!(course.validate(), Err(CourseError::ManyFinishes));
+
+        let course = Course {
+            gates: vec![
+                make_gate(1, GateRole::Start),
+                make_gate(1, GateRole::Finish),
+            ],
+        };
+        let addr = NodeAddress::from(1);
+        assert_eq!(course.validate(), Err(CourseError::DuplicateAddress(addr)));
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use crate::hal::Platform;
+use crate::svc::race_node::{FirmwareVersion, GateDiagnostics, NodeAddress};
+
+/// Gates send diagnostics at this period, much longer than the beacon one
+const DIAGNOSTICS_PERIOD: Duration = Duration::from_secs(5);
+
+/// An update started later than this after the previous one is an overrun.
+/// The application loop runs every 20 ms on the target.
+const MAX_UPDATE_INTERVAL: Duration = Duration::from_millis(40);
+
+/// Keeps track of the node health and sends it to the coordinator, if the
+/// node is a gate
+pub(crate) struct DiagnosticsReporter {
+    started: Instant,
+    last_update: Option<Instant>,
+    loop_overruns: u32,
+    last_sent: Option<Instant>,
+}
+
+impl DiagnosticsReporter {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            last_update: None,
+            loop_overruns: 0,
+            last_sent: None,
+        }
+    }
+
+    /// Called on each application update
+    pub(crate) fn update(&mut self, platform: &dyn Platform, addr: NodeAddress, now: Instant) {
+        if let Some(last_update) = self.last_update {
+            if now.saturating_duration_since(last_update) > MAX_UPDATE_INTERVAL {
+                self.loop_overruns += 1;
+            }
+        }
+
+        self.last_update = Some(now);
+
+        if !addr.is_gate() {
+            return;
+        }
+
+        let due = self
+            .last_sent
+            .map(|x| now.saturating_duration_since(x) >= DIAGNOSTICS_PERIOD)
+            .unwrap_or(true);
+
+        if !due {
+            return;
+        }
+
+        self.last_sent = Some(now);
+
+        let diagnostics = GateDiagnostics {
+            addr,
+            firmware_version: FirmwareVersion::current(),
+            uptime: now.saturating_duration_since(self.started),
+            rssi: platform.wifi().rssi(),
+            supply_voltage_mv: platform.supply_voltage_mv(),
+            loop_overruns: self.loop_overruns,
+            dropped_gate_events: platform.gate().dropped_events(),
+            stats: platform.race_node().stats(),
+        };
+
+        log::debug!("{:?}", diagnostics);
+
+        platform.race_node().send_diagnostics(diagnostics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hal::clock::Clock;
+    use crate::hal::mock::MockPlatform;
+    use crate::svc::race_node::NodeStats;
+
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn test_gate_sends_diagnostics_periodically() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut reporter = DiagnosticsReporter::new(platform.clock.now());
+
+        platform.wifi.rssi.set(Some(-70));
+        platform.supply_voltage_mv.set(Some(5_020));
+        platform.gate.dropped_events.set(2);
+        platform.race_node.stats.set(NodeStats {
+            tx_count: 10,
+            rx_count: 20,
+            rx_rejected_count: 1,
+        });
+
+        let mut elapsed = Duration::ZERO;
+        while elapsed < DIAGNOSTICS_PERIOD * 2 {
+            reporter.update(&platform, NodeAddress::from(1), platform.clock.now());
+            platform.clock.advance(PERIOD);
+            elapsed += PERIOD;
+        }
+
+        // An update much later than expected
+        platform.clock.advance(Duration::from_millis(100));
+        reporter.update(&platform, NodeAddress::from(1), platform.clock.now());
+
+        let sent = platform.race_node.sent_diagnostics.take();
+        assert_eq!(sent.len(), 3);
+
+        assert_eq!(sent[0].uptime, Duration::ZERO);
+        assert_eq!(sent[0].loop_overruns, 0);
+        assert_eq!(sent[1].uptime, DIAGNOSTICS_PERIOD);
+
+        let last = sent[2];
+        assert_eq!(last.addr, NodeAddress::from(1));
+        assert_eq!(last.firmware_version, FirmwareVersion::current());
+        assert_eq!(last.rssi, Some(-70));
+        assert_eq!(last.supply_voltage_mv, Some(5_020));
+        assert_eq!(last.loop_overruns, 1);
+        assert_eq!(last.dropped_gate_events, 2);
+        assert_eq!(last.stats, platform.race_node.stats.get());
+    }
+
+    #[test]
+    fn test_coordinator_sends_no_diagnostics() {
+        let platform = MockPlatform::new(NodeAddress::coordinator());
+        let mut reporter = DiagnosticsReporter::new(platform.clock.now());
+
+        reporter.update(&platform, NodeAddress::coordinator(), platform.clock.now());
+
+        assert!(platform.race_node.sent_diagnostics.borrow().is_empty());
+    }
+}
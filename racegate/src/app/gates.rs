@@ -1,19 +1,36 @@
-use crate::svc::race_node::NodeAddress;
-use crate::svc::CoordinatedInstant;
+use std::time::Duration;
+
+use crate::app::course::GateRole;
+use crate::svc::race_node::{GateDiagnostics, NodeAddress, SyncQuality};
+use crate::svc::{ActivationStats, CoordinatedInstant};
+
+/// Times taken by a gate with a larger clock error are not reliable
+const MAX_SYNC_ERROR: Duration = Duration::from_millis(1);
+
+/// Above this loss rate, the gate clock runs mostly without synchronization
+const MAX_SYNC_LOSS_PERCENT: u8 = 50;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone, Eq, PartialEq)]
 pub struct Gate {
     pub active: bool,
     pub last_activation_time: Option<CoordinatedInstant>,
     pub last_beacon_time: Option<CoordinatedInstant>,
+    /// Clock synchronization reported by the gate
+    pub sync: Option<SyncQuality>,
+    /// Delivery of the activations, only on the coordinator
+    pub activations: ActivationStats,
+    /// Role the gate was told by the coordinator
+    pub role: Option<GateRole>,
+    /// Last diagnostics sent by the gate
+    pub diagnostics: Option<GateDiagnostics>,
 }
 
 impl Gate {
     pub fn is_alive(&self, now: CoordinatedInstant) -> bool {
         self.last_beacon_time
             .map(|x| {
-                let diff = now.as_millis() - x.as_millis();
-                diff < 1_000
+                let diff = now.as_micros() - x.as_micros();
+                diff < 1_000_000
             })
             .unwrap_or(false)
     }
@@ -21,30 +38,110 @@ impl Gate {
     pub fn is_active(&self) -> bool {
         self.active
     }
+
+    /// Activation times of this gate may be inaccurate
+    pub fn has_poor_sync(&self) -> bool {
+        self.sync
+            .map(|x| x.error > MAX_SYNC_ERROR || x.loss_percent > MAX_SYNC_LOSS_PERCENT)
+            .unwrap_or(true)
+    }
 }
 
+/// Gates known by the coordinator, indexed by node address. The set grows
+/// when a beacon is received from a gate with a new address.
 #[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone, Eq, PartialEq)]
 pub struct Gates {
-    items: [Gate; 4],
+    items: Vec<Gate>,
 }
 
 impl Gates {
-    pub fn new(items: [Gate; 4]) -> Self {
-        Self { items }
+    pub fn new(items: impl Into<Vec<Gate>>) -> Self {
+        Self {
+            items: items.into(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
     }
 
-    pub fn start_gate(&self) -> &Gate {
-        const INDEX: usize = NodeAddress::start().unwrap_as_gate_index();
-        &self.items[INDEX]
+    pub fn get(&self, addr: NodeAddress) -> Option<&Gate> {
+        let index = addr.as_gate_index()?;
+        self.items.get(index)
     }
 
-    pub fn finish_gate(&self) -> &Gate {
-        const INDEX: usize = NodeAddress::finish().unwrap_as_gate_index();
-        &self.items[INDEX]
+    pub fn iter(&self) -> impl Iterator<Item = (NodeAddress, &Gate)> {
+        self.items
+            .iter()
+            .enumerate()
+            .map(|(i, gate)| (NodeAddress::from_gate_index(i), gate))
     }
 
+    /// Get the gate with the given address, adding it if it is not known yet.
+    /// Returns `None` only for the coordinator address.
     pub fn get_mut_from_addr(&mut self, addr: NodeAddress) -> Option<&mut Gate> {
         let index = addr.as_gate_index()?;
-        Some(&mut self.items[index])
+
+        if index >= self.items.len() {
+            self.items.resize_with(index + 1, Gate::default);
+        }
+
+        self.items.get_mut(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gates_grow_on_new_address() {
+        let mut gates = Gates::default();
+        assert!(gates.is_empty());
+
+        let addr = NodeAddress::from(7);
+        gates.get_mut_from_addr(addr).unwrap().active = true;
+
+        assert_eq!(gates.len(), 7);
+        assert!(gates.get(addr).unwrap().is_active());
+        assert!(!gates.get(NodeAddress::from(5)).unwrap().is_active());
+        assert!(gates.get(NodeAddress::from(8)).is_none());
+        assert!(gates
+            .get_mut_from_addr(NodeAddress::coordinator())
+            .is_none());
+    }
+
+    #[test]
+    fn test_gate_with_poor_sync() {
+        let sync = SyncQuality {
+            offset_us: 12_345,
+            error: Duration::from_micros(300),
+            loss_percent: 10,
+        };
+
+        let mut gate = Gate {
+            sync: Some(sync),
+            ..Default::default()
+        };
+        assert!(!gate.has_poor_sync());
+
+        gate.sync = Some(SyncQuality {
+            error: Duration::from_millis(3),
+            ..sync
+        });
+        assert!(gate.has_poor_sync());
+
+        gate.sync = Some(SyncQuality {
+            loss_percent: 80,
+            ..sync
+        });
+        assert!(gate.has_poor_sync());
+
+        gate.sync = None;
+        assert!(gate.has_poor_sync());
     }
 }
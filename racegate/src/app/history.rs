@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::app::race::{Race, RaceState};
+use crate::app::racers::Racer;
+
+/// Maximum number of races kept in history, older ones are discarded
+const HISTORY_SIZE: usize = 16;
+
+/// Number of best times kept in the leaderboard
+const LEADERBOARD_SIZE: usize = 10;
+
+/// Races that are over, with the best times of the session
+#[derive(Debug, Default, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct History {
+    /// Most recent races, oldest first
+    pub races: VecDeque<Race>,
+    /// Best times, fastest first
+    pub leaderboard: Vec<LeaderboardEntry>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LeaderboardEntry {
+    /// Id of the race
+    pub race: u32,
+    pub racer: Option<Racer>,
+    pub duration: Duration,
+}
+
+impl History {
+    pub fn push(&mut self, race: Race) {
+        if race.state() == RaceState::Finished {
+            if let Some(duration) = race.duration() {
+                self.add_to_leaderboard(LeaderboardEntry {
+                    race: race.id,
+                    racer: race.racer.clone(),
+                    duration,
+                });
+            }
+        }
+
+        self.races.push_back(race);
+
+        while self.races.len() > HISTORY_SIZE {
+            self.races.pop_front();
+        }
+    }
+
+    pub fn last(&self) -> Option<&Race> {
+        self.races.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.races.clear();
+        self.leaderboard.clear();
+    }
+
+    fn add_to_leaderboard(&mut self, entry: LeaderboardEntry) {
+        // Same times are ranked by arrival order
+        let index = self
+            .leaderboard
+            .partition_point(|x| x.duration <= entry.duration);
+
+        if index < LEADERBOARD_SIZE {
+            self.leaderboard.insert(index, entry);
+            self.leaderboard.truncate(LEADERBOARD_SIZE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use insta::assert_debug_snapshot;
+
+    use crate::svc::CoordinatedInstant;
+
+    use super::*;
+
+    fn make_finished_race(id: u32, duration_ms: i32) -> Race {
+        let mut race = Race::new(id);
+        let t0 = CoordinatedInstant::from_millis(1_000);
+        let t1 = CoordinatedInstant::from_millis(1_000 + duration_ms);
+        race.arm(CoordinatedInstant::from_millis(0));
+        race.start(t0, t0);
+        race.finish(t1, t1);
+        race
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut history = History::default();
+
+        for id in 0..(HISTORY_SIZE as u32 + 3) {
+            history.push(make_finished_race(id, 10_000));
+        }
+
+        assert_eq!(history.races.len(), HISTORY_SIZE);
+        assert_eq!(history.races.front().map(|x| x.id), Some(3));
+        assert_eq!(history.last().map(|x| x.id), Some(HISTORY_SIZE as u32 + 2));
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        let mut history = History::default();
+        history.push(make_finished_race(1, 12_000));
+        history.push(make_finished_race(2, 10_000));
+        history.push(make_finished_race(3, 12_000));
+        history.push(make_finished_race(4, 11_000));
+
+        let mut aborted = Race::new(5);
+        aborted.arm(CoordinatedInstant::from_millis(0));
+        aborted.abort(CoordinatedInstant::from_millis(1));
+        history.push(aborted);
+
+        assert_debug_snapshot!(history.leaderboard);
+    }
+
+    #[test]
+    fn test_leaderboard_keeps_best_times_only() {
+        let mut history = History::default();
+
+        for id in 0..(LEADERBOARD_SIZE as u32 * 2) {
+            history.push(make_finished_race(id, 30_000 - (id as i32) * 1000));
+        }
+
+        assert_eq!(history.leaderboard.len(), LEADERBOARD_SIZE);
+        assert_eq!(history.leaderboard[0].race, LEADERBOARD_SIZE as u32 * 2 - 1);
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::app::race::RaceEvent;
+use crate::svc::CoordinatedInstant;
+
+/// Maximum number of laps kept, older ones are discarded
+const LAPS_SIZE: usize = 50;
+
+/// Lap timing on a single gate: every pass closes the current lap and opens
+/// the next one.
+#[derive(Debug, Default, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Laps {
+    /// Passes before this time are stale
+    pub reset_time: Option<CoordinatedInstant>,
+    /// Time of the last pass, when the current lap started
+    pub last_pass: Option<CoordinatedInstant>,
+    /// The last pass was timed with poor clock synchronization
+    pub last_pass_poor_sync: bool,
+    /// The last pass was delivered after the gate lost the coordinator
+    pub last_pass_recovered: bool,
+    /// Number of completed laps
+    pub count: u32,
+    pub best: Option<Lap>,
+    /// Most recent laps, oldest first
+    pub laps: VecDeque<Lap>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Lap {
+    /// Progressive number of the lap, from 1
+    pub number: u32,
+    /// Time of the pass closing the lap
+    pub time: CoordinatedInstant,
+    pub duration: Duration,
+    /// Difference from the best lap before this one, in microseconds.
+    /// Negative when this is a new best lap.
+    pub delta_us: Option<i64>,
+    /// The lap was timed with poor clock synchronization
+    pub poor_sync: bool,
+    /// The lap was completed with a pass delivered late, after the gate lost
+    /// the coordinator
+    pub recovered: bool,
+}
+
+impl Lap {
+    /// Time of the pass opening the lap
+    fn start(&self) -> CoordinatedInstant {
+        self.time - self.duration
+    }
+}
+
+impl Laps {
+    pub fn new(now: CoordinatedInstant) -> Self {
+        Self {
+            reset_time: Some(now),
+            ..Default::default()
+        }
+    }
+
+    pub fn pass(
+        &mut self,
+        time: CoordinatedInstant,
+        poor_sync: bool,
+        recovered: bool,
+    ) -> Option<RaceEvent> {
+        let after_reset = self.reset_time.map(|x| time > x).unwrap_or(true);
+        let after_last_pass = self.last_pass.map(|x| time > x).unwrap_or(true);
+
+        if !after_reset {
+            return None;
+        }
+
+        if !after_last_pass {
+            return self.insert_pass(time, poor_sync);
+        }
+
+        let last_pass_poor_sync = std::mem::replace(&mut self.last_pass_poor_sync, poor_sync);
+        let last_pass_recovered = std::mem::replace(&mut self.last_pass_recovered, recovered);
+
+        let Some(last_pass) = self.last_pass.replace(time) else {
+            // First pass starts the first lap
+            return Some(RaceEvent::Started);
+        };
+
+        let duration = time.duration_since(last_pass);
+        let delta_us = self
+            .best
+            .as_ref()
+            .map(|x| duration.as_micros() as i64 - x.duration.as_micros() as i64);
+
+        self.count += 1;
+
+        let lap = Lap {
+            number: self.count,
+            time,
+            duration,
+            delta_us,
+            poor_sync: poor_sync || last_pass_poor_sync,
+            recovered: recovered || last_pass_recovered,
+        };
+
+        if delta_us.map(|x| x < 0).unwrap_or(true) {
+            self.best = Some(lap.clone());
+        }
+
+        self.laps.push_back(lap);
+
+        while self.laps.len() > LAPS_SIZE {
+            self.laps.pop_front();
+        }
+
+        Some(RaceEvent::Lap(self.count))
+    }
+
+    /// Pass delivered after later ones, e.g. when the gate lost the
+    /// coordinator for a while: it splits the lap it falls into, or opens
+    /// the first lap when it comes before the first pass.
+    fn insert_pass(&mut self, time: CoordinatedInstant, poor_sync: bool) -> Option<RaceEvent> {
+        let last_pass = self.last_pass?;
+
+        let (index, lap) = match self.laps.iter().position(|x| time <= x.time) {
+            Some(index) => {
+                let old = &self.laps[index];
+
+                if time == old.time || time == old.start() {
+                    // Already counted
+                    return None;
+                }
+
+                if time > old.start() {
+                    // Split the lap, the first part is inserted before it
+                    let poor_sync = poor_sync || old.poor_sync;
+                    let lap = Lap {
+                        number: old.number,
+                        time,
+                        duration: time.duration_since(old.start()),
+                        delta_us: None,
+                        poor_sync,
+                        recovered: true,
+                    };
+                    let old = &mut self.laps[index];
+                    old.duration = old.time.duration_since(time);
+                    old.poor_sync = poor_sync;
+                    old.recovered = true;
+                    (index, lap)
+                } else if old.number == 1 {
+                    // Before the first pass, which now closes the first lap
+                    let lap = Lap {
+                        number: 1,
+                        time: old.start(),
+                        duration: old.start().duration_since(time),
+                        delta_us: None,
+                        poor_sync: poor_sync || old.poor_sync,
+                        recovered: true,
+                    };
+                    (0, lap)
+                } else {
+                    // Before the oldest lap kept
+                    return None;
+                }
+            }
+            None if time == last_pass => return None,
+            None => {
+                // Before the only pass, which now closes the first lap
+                let lap = Lap {
+                    number: 1,
+                    time: last_pass,
+                    duration: last_pass.duration_since(time),
+                    delta_us: None,
+                    poor_sync: poor_sync || self.last_pass_poor_sync,
+                    recovered: true,
+                };
+                (0, lap)
+            }
+        };
+
+        let number = lap.number;
+        self.laps.insert(index, lap);
+        self.count += 1;
+
+        for lap in self.laps.iter_mut().skip(index + 1) {
+            lap.number += 1;
+        }
+
+        self.update_deltas();
+
+        while self.laps.len() > LAPS_SIZE {
+            self.laps.pop_front();
+        }
+
+        Some(RaceEvent::Lap(number))
+    }
+
+    /// Recompute the deltas and the best lap after inserting a lap
+    fn update_deltas(&mut self) {
+        let first = self.laps.front().map(|x| x.number).unwrap_or_default();
+        // The best lap may have been discarded already
+        let mut best = self.best.take().filter(|x| x.number < first);
+
+        for lap in self.laps.iter_mut() {
+            lap.delta_us = best
+                .as_ref()
+                .map(|x| lap.duration.as_micros() as i64 - x.duration.as_micros() as i64);
+
+            if lap.delta_us.map(|x| x < 0).unwrap_or(true) {
+                best = Some(lap.clone());
+            }
+        }
+
+        self.best = best;
+    }
+
+    pub fn last(&self) -> Option<&Lap> {
+        self.laps.back()
+    }
+
+    /// Time since the start of the current lap
+    pub fn elapsed(&self, now: CoordinatedInstant) -> Option<Duration> {
+        self.last_pass.map(|x| now.duration_since(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use insta::assert_debug_snapshot;
+
+    use super::*;
+
+    fn ms(time_ms: i32) -> CoordinatedInstant {
+        CoordinatedInstant::from_millis(time_ms)
+    }
+
+    #[test]
+    fn test_laps() {
+        let mut laps = Laps::new(ms(0));
+        assert_eq!(laps.pass(ms(1_000), false, false), Some(RaceEvent::Started));
+        assert_eq!(laps.pass(ms(31_000), false, false), Some(RaceEvent::Lap(1)));
+        assert_eq!(laps.pass(ms(60_000), false, false), Some(RaceEvent::Lap(2)));
+        assert_eq!(laps.pass(ms(90_500), false, false), Some(RaceEvent::Lap(3)));
+
+        assert_eq!(laps.count, 3);
+        assert_eq!(laps.best.as_ref().map(|x| x.number), Some(2));
+        assert_eq!(laps.last().and_then(|x| x.delta_us), Some(1_500_000));
+        assert_eq!(
+            laps.elapsed(ms(100_000)),
+            Some(Duration::from_millis(9_500))
+        );
+        assert_debug_snapshot!(laps);
+    }
+
+    #[test]
+    fn test_laps_ignore_stale_passes() {
+        let mut laps = Laps::new(ms(10_000));
+        assert_eq!(laps.pass(ms(5_000), false, false), None);
+        assert_eq!(
+            laps.pass(ms(11_000), false, false),
+            Some(RaceEvent::Started)
+        );
+        assert_eq!(laps.pass(ms(11_000), false, false), None);
+        assert_eq!(laps.count, 0);
+    }
+
+    #[test]
+    fn test_laps_insert_late_passes() {
+        let mut laps = Laps::new(ms(0));
+        assert_eq!(laps.pass(ms(1_000), false, false), Some(RaceEvent::Started));
+        assert_eq!(laps.pass(ms(61_000), false, false), Some(RaceEvent::Lap(1)));
+        assert_eq!(laps.pass(ms(91_500), false, false), Some(RaceEvent::Lap(2)));
+
+        // Missed pass in the middle of the first lap
+        assert_eq!(laps.pass(ms(31_000), false, true), Some(RaceEvent::Lap(1)));
+        assert_eq!(laps.pass(ms(31_000), false, true), None);
+        assert_eq!(laps.count, 3);
+
+        let laps_summary: Vec<_> = laps
+            .laps
+            .iter()
+            .map(|x| (x.number, x.duration.as_millis(), x.delta_us, x.recovered))
+            .collect();
+        assert_eq!(
+            laps_summary,
+            vec![
+                (1, 30_000, None, true),
+                (2, 30_000, Some(0), true),
+                (3, 30_500, Some(500_000), false),
+            ]
+        );
+        assert_eq!(laps.best.as_ref().map(|x| x.number), Some(1));
+        assert_eq!(laps.last_pass, Some(ms(91_500)));
+    }
+
+    #[test]
+    fn test_laps_insert_late_first_pass() {
+        let mut laps = Laps::new(ms(0));
+        assert_eq!(
+            laps.pass(ms(31_000), false, false),
+            Some(RaceEvent::Started)
+        );
+        assert_eq!(laps.pass(ms(1_000), true, true), Some(RaceEvent::Lap(1)));
+        assert_eq!(laps.pass(ms(60_000), false, false), Some(RaceEvent::Lap(2)));
+
+        let laps_summary: Vec<_> = laps
+            .laps
+            .iter()
+            .map(|x| (x.number, x.duration.as_millis(), x.poor_sync, x.recovered))
+            .collect();
+        assert_eq!(
+            laps_summary,
+            vec![(1, 30_000, true, true), (2, 29_000, false, false)]
+        );
+        assert_eq!(laps.best.as_ref().map(|x| x.number), Some(2));
+    }
+
+    #[test]
+    fn test_laps_are_bounded() {
+        let mut laps = Laps::new(ms(0));
+
+        for i in 1..=(LAPS_SIZE as i32 + 5) {
+            laps.pass(ms(i * 30_000), false, false);
+        }
+
+        assert_eq!(laps.count, LAPS_SIZE as u32 + 4);
+        assert_eq!(laps.laps.len(), LAPS_SIZE);
+        assert_eq!(laps.best.as_ref().map(|x| x.number), Some(1));
+
+        // Passes before the oldest lap kept can't be placed
+        assert_eq!(laps.pass(ms(45_000), false, true), None);
+        assert_eq!(laps.count, LAPS_SIZE as u32 + 4);
+    }
+
+    #[test]
+    fn test_laps_with_poor_sync() {
+        let mut laps = Laps::new(ms(0));
+        laps.pass(ms(1_000), false, false);
+        laps.pass(ms(31_000), true, false);
+        laps.pass(ms(61_000), false, false);
+        laps.pass(ms(91_000), false, false);
+
+        let poor_sync: Vec<_> = laps.laps.iter().map(|x| x.poor_sync).collect();
+        assert_eq!(poor_sync, vec![true, true, false]);
+    }
+}
@@ -1,9 +1,27 @@
 use std::time::{Duration, Instant};
 
+pub use crate::app::course::Course;
+pub use crate::app::course::CourseError;
+pub use crate::app::course::CourseGate;
+pub use crate::app::course::GateRole;
 pub use crate::app::gates::Gate;
 pub use crate::app::gates::Gates;
+pub use crate::app::history::History;
+pub use crate::app::history::LeaderboardEntry;
+pub use crate::app::laps::Lap;
+pub use crate::app::laps::Laps;
+pub use crate::app::operator::OperatorCommand;
 pub use crate::app::race::Race;
+pub use crate::app::race::RaceEvent;
+pub use crate::app::race::RaceState;
+pub use crate::app::race::Split;
+pub use crate::app::racers::Racer;
+pub use crate::app::roster::AddressConflict;
+pub use crate::app::roster::Roster;
+pub use crate::app::roster::RosterEntry;
+pub use crate::app::timing::Timing;
 
+use crate::app::diagnostics::DiagnosticsReporter;
 use crate::hal::button::ButtonState;
 use crate::hal::gate::GateState;
 use crate::hal::rgb_led::RgbLed;
@@ -11,17 +29,34 @@ use crate::hal::rgb_led::RgbLedColor;
 use crate::hal::Platform;
 use crate::svc::race_node::*;
 use crate::svc::{
-    calculate_clock_offset, CoordinatedClock, CoordinatedInstant, LocalClock, LocalInstant,
+    calculate_clock_offset, ClockFilter, ClockSample, ClockSync, CoordinatedClock,
+    CoordinatedInstant, DriftEstimator, LocalClock, LocalInstant,
 };
 
+mod course;
+mod diagnostics;
 pub mod gates;
+mod history;
+mod laps;
+mod operator;
 mod race;
+mod racers;
+mod roster;
+mod timing;
 
 #[derive(Debug, Default, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct SystemState {
     pub time: CoordinatedInstant,
     pub gates: Gates,
-    pub race: Race,
+    pub timing: Timing,
+    /// Racer of the current race
+    pub current_racer: Option<Racer>,
+    /// Racer expected at the start gate
+    pub next_racer: Option<Racer>,
+    /// Other systems on the same network
+    pub foreign_systems: Vec<SystemId>,
+    /// Nodes of this system, with their address conflicts
+    pub roster: Roster,
 }
 
 struct Services<'a> {
@@ -30,12 +65,22 @@ struct Services<'a> {
     local_clock: LocalClock,
 }
 
+impl Services<'_> {
+    fn now(&self) -> Instant {
+        self.platform.clock().now()
+    }
+
+    fn local_time(&self) -> LocalInstant {
+        self.local_clock.at(self.now()).expect("Cannot get time")
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 enum AppState {
     Init(InitState),
-    CoordinatorReady(CoordinatorReadyState),
+    CoordinatorReady(Box<CoordinatorReadyState>),
     GateStartup(GateStartupState),
-    GateReady(GateReadyState),
+    GateReady(Box<GateReadyState>),
 }
 
 impl Default for AppState {
@@ -47,15 +92,19 @@ impl Default for AppState {
 pub struct App<'a> {
     services: Services<'a>,
     state: AppState,
+    diagnostics: DiagnosticsReporter,
 }
 
 impl<'a> App<'a> {
-    pub fn new(platform: &'a mut (dyn Platform)) -> Self {
+    /// The platform is borrowed shared, so tests and the simulation can drive
+    /// its clock and inputs while the application runs. Drivers needing
+    /// mutation rely on interior mutability.
+    pub fn new(platform: &'a dyn Platform) -> Self {
         let led_controller = LedController {
             led: platform.rgb_led(),
         };
 
-        let race_clock = LocalClock::default();
+        let race_clock = LocalClock::new(platform.clock().now());
 
         let services = Services {
             led_controller,
@@ -65,7 +114,13 @@ impl<'a> App<'a> {
 
         let state = AppState::default();
 
-        Self { services, state }
+        let diagnostics = DiagnosticsReporter::new(platform.clock().now());
+
+        Self {
+            services,
+            state,
+            diagnostics,
+        }
     }
 
     pub fn update(&mut self) {
@@ -81,7 +136,11 @@ impl<'a> App<'a> {
             self.state = new_state;
         }
 
-        self.services.led_controller.update(&self.state);
+        let now = self.services.now();
+        self.services.led_controller.update(&self.state, now);
+
+        let addr = address(&self.services);
+        self.diagnostics.update(self.services.platform, addr, now);
     }
 }
 
@@ -90,13 +149,13 @@ struct LedController<'a> {
 }
 
 impl<'a> LedController<'a> {
-    pub fn update(&mut self, app_state: &AppState) {
-        let color = color_from_app_state(app_state);
+    pub fn update(&mut self, app_state: &AppState, now: Instant) {
+        let color = color_from_app_state(app_state, now);
         self.led.set_color(RgbLedColor::from(color));
     }
 }
 
-fn color_from_app_state(app_state: &AppState) -> u32 {
+fn color_from_app_state(app_state: &AppState, now: Instant) -> u32 {
     const RED: u32 = 0xFF0000;
     const YELLOW: u32 = 0xFFFF00;
     const GREEN: u32 = 0x00FF00;
@@ -105,16 +164,22 @@ fn color_from_app_state(app_state: &AppState) -> u32 {
 
     match app_state {
         AppState::Init(_) => RED,
-        AppState::GateStartup(_) => YELLOW,
+        AppState::GateStartup(state) => state.settings.led_color(now).unwrap_or(YELLOW),
         AppState::CoordinatorReady(state) => {
-            if state.any_gate_active {
+            if state.system_state.roster.has_conflicts() {
+                RED
+            } else if state.any_gate_active {
                 BLUE
+            } else if !state.system_state.timing.running.is_empty() {
+                GREEN
             } else {
                 WHITE
             }
         }
         AppState::GateReady(state) => {
-            if state.gate_state == GateState::Active {
+            if let Some(color) = state.settings.led_color(now) {
+                color
+            } else if state.gate_state == GateState::Active {
                 BLUE
             } else {
                 GREEN
@@ -134,7 +199,7 @@ impl InitState {
     pub fn update(&mut self, services: &Services) -> AppState {
         let gate_state = services.platform.gate().state();
         let button_state = services.platform.button().state();
-        let local_time = services.local_clock.now().expect("Cannot get time");
+        let local_time = services.local_time();
         let address = address(services);
 
         let startup_as_gate = address.is_gate()
@@ -148,15 +213,15 @@ impl InitState {
 
         if startup_as_gate {
             log::info!("This is a gate");
-            AppState::GateStartup(GateStartupState::default())
+            AppState::GateStartup(GateStartupState::new(services.now()))
         } else if startup_as_coordinator {
             log::info!("This is a coordinator");
             // On coordinator, local time is the coordinated time, without any offset
-            AppState::CoordinatorReady(CoordinatorReadyState {
-                time: CoordinatedInstant::from_millis(local_time.as_millis()),
+            AppState::CoordinatorReady(Box::new(CoordinatorReadyState {
+                time: CoordinatedInstant::from_micros(local_time.as_micros()),
                 system_state: SystemState::default(),
                 any_gate_active: false,
-            })
+            }))
         } else {
             AppState::Init(*self)
         }
@@ -180,12 +245,15 @@ impl CoordinatorReadyState {
             return AppState::Init(InitState::default());
         }
 
-        let local_time = services.local_clock.now().expect("Cannot get time");
+        let local_time = services.local_time();
 
         // On coordinator, local time is the coordinated time, without any offset
-        let time = CoordinatedInstant::from_millis(local_time.as_millis());
+        let time = CoordinatedInstant::from_micros(local_time.as_micros());
 
-        let beacon = CoordinatorBeacon { time };
+        let beacon = CoordinatorBeacon {
+            time,
+            hardware_id: services.platform.hardware_id(),
+        };
 
         if let Err(e) = services.platform.race_node().publish(beacon.into()) {
             log::error!("{e}");
@@ -194,70 +262,264 @@ impl CoordinatorReadyState {
         services.platform.race_node().set_coordinator_time(time);
 
         let gates = services.platform.race_node().gates();
+        let activations = services.platform.race_node().take_activations();
+        let roster = services.platform.race_node().roster();
+
+        let mut timing = self.system_state.timing.clone();
+        timing.address_conflict = roster.has_conflicts();
 
-        let mut race = self.system_state.race.clone();
+        let http_server = services.platform.http_server();
+        while let Some(command) = http_server.take_operator_command() {
+            log::info!("operator: {:?}", command);
 
-        race.set_gates(&gates);
+            if let OperatorCommand::Gate { addr, command } = command {
+                services.platform.race_node().send_command(addr, command);
+                continue;
+            }
 
-        let any_gate_active = gates.start_gate().active || gates.finish_gate().active;
+            for event in timing.apply(command, time) {
+                log::info!("race: {:?}", event);
+            }
+        }
 
-        let system_state = SystemState { time, gates, race };
+        for event in timing.set_gates(&gates, &activations, time) {
+            log::info!("race: {:?}", event);
+        }
+
+        let any_gate_active = gates.iter().any(|(_, gate)| gate.is_active());
+
+        let current_racer = timing.current_racer().cloned();
+        let next_racer = timing.next_racer().cloned();
+
+        let system_state = SystemState {
+            time,
+            gates,
+            timing,
+            current_racer,
+            next_racer,
+            foreign_systems: services.platform.race_node().foreign_systems(),
+            roster,
+        };
 
         services
             .platform
             .http_server()
             .set_system_state(&self.system_state);
 
-        AppState::CoordinatorReady(CoordinatorReadyState {
+        AppState::CoordinatorReady(Box::new(CoordinatorReadyState {
             time,
             system_state,
             any_gate_active,
-        })
+        }))
     }
 }
 
+/// How long a gate blinks its LED when asked to identify itself
+const IDENTIFY_DURATION: Duration = Duration::from_secs(10);
+
+/// Time left to a gate to acknowledge a reboot command before rebooting
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+/// Gate settings changed by the coordinator commands, kept while the gate
+/// synchronizes again
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-struct GateStartupState {
-    time_started: Instant,
+struct GateSettings {
+    /// Activations are reported only when armed
+    armed: bool,
+    /// Role in the course, reported back to the coordinator
+    role: Option<GateRole>,
+    /// Color shown instead of the gate state
+    led_color: Option<u32>,
+    /// The LED blinks until this time
+    identify_until: Option<Instant>,
+    reboot_at: Option<Instant>,
 }
 
-impl Default for GateStartupState {
+impl Default for GateSettings {
     fn default() -> Self {
         Self {
-            time_started: Instant::now(),
+            armed: true,
+            role: None,
+            led_color: None,
+            identify_until: None,
+            reboot_at: None,
         }
     }
 }
 
+impl GateSettings {
+    /// Apply the commands received from the coordinator
+    fn update(&mut self, services: &Services) {
+        let now = services.now();
+
+        for command in services.platform.race_node().take_commands() {
+            log::info!("coordinator: {:?}", command);
+
+            match command {
+                GateCommand::Identify => self.identify_until = Some(now + IDENTIFY_DURATION),
+                GateCommand::Reboot => self.reboot_at = Some(now + REBOOT_DELAY),
+                GateCommand::SetRole { role } => self.role = Some(role),
+                GateCommand::Arm => self.armed = true,
+                GateCommand::Disarm => self.armed = false,
+                GateCommand::SetLed { color } => self.led_color = color,
+            }
+        }
+
+        if self.reboot_at.map(|x| now >= x).unwrap_or(false) {
+            self.reboot_at = None;
+            services.platform.reboot();
+        }
+    }
+
+    /// Color of the LED, when it doesn't show the gate state
+    fn led_color(&self, now: Instant) -> Option<u32> {
+        const BLINK_PERIOD_MS: u128 = 500;
+
+        match self
+            .identify_until
+            .and_then(|x| x.checked_duration_since(now))
+        {
+            Some(left) if left.as_millis() % BLINK_PERIOD_MS < BLINK_PERIOD_MS / 2 => {
+                Some(0xFFFFFF)
+            }
+            Some(_) => Some(0x000000),
+            None => self.led_color,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct GateStartupState {
+    time_started: Instant,
+    /// Last time the link was checked, after a link loss
+    time_retried: Instant,
+    /// Clock of a gate that lost the coordinator, still good enough to
+    /// timestamp activations until the gate is synchronized again
+    last_clock: Option<CoordinatedClock>,
+    gate_state: GateState,
+    settings: GateSettings,
+}
+
 impl GateStartupState {
+    fn new(time_started: Instant) -> Self {
+        Self {
+            time_started,
+            time_retried: time_started,
+            last_clock: None,
+            gate_state: GateState::Inactive,
+            settings: GateSettings::default(),
+        }
+    }
+
+    fn after_link_loss(time_started: Instant, ready: &GateReadyState) -> Self {
+        Self {
+            time_started,
+            time_retried: time_started,
+            last_clock: Some(ready.coordinated_clock),
+            gate_state: ready.gate_state,
+            settings: ready.settings,
+        }
+    }
+
     pub fn update(&mut self, services: &Services) -> AppState {
         let gate_state = services.platform.gate().state();
 
-        if let Some(time_since_started) = Instant::now().checked_duration_since(self.time_started) {
-            // Apparently, there's no way to recover the connection. Just panic and hope.
+        self.settings.update(services);
+
+        // Activations are kept until the coordinator is reachable again.
+        // Changes happened before being ready the first time are not
+        // activations.
+        match &self.last_clock {
+            Some(last_clock) if self.settings.armed => {
+                let was_active = self.gate_state == GateState::Active;
+                send_activations(services, last_clock, was_active, true);
+            }
+            _ => while services.platform.gate().take_event().is_some() {},
+        }
+
+        self.gate_state = gate_state;
+
+        if let Some(time_since_retried) = services.now().checked_duration_since(self.time_retried) {
             const TIMEOUT: Duration = Duration::from_secs(10);
-            if time_since_started > TIMEOUT {
-                panic!();
+            if time_since_retried > TIMEOUT {
+                // Apparently, there's no way to recover the connection at
+                // boot. Just panic and hope.
+                if self.last_clock.is_none() {
+                    panic!();
+                }
+
+                // After a link loss, a reboot would lose the activations not
+                // delivered yet and the ones to come, so the gate keeps
+                // waiting for the coordinator.
+                let wifi = services.platform.wifi();
+                if !wifi.is_up() {
+                    log::warn!("Wi-Fi down, reconnecting");
+                    wifi.reconnect();
+                }
+                self.time_retried = services.now();
             }
         }
 
-        if let Some(coordinated_clock) = make_coordinated_clock(services) {
-            AppState::GateReady(GateReadyState {
+        // Synchronization done before startup may refer to a previous
+        // coordinator session
+        let clock_sync = services
+            .platform
+            .race_node()
+            .clock_sync()
+            .filter(|x| x.instant > self.time_started);
+
+        let mut clock_filter = ClockFilter::default();
+        let mut drift_estimator = DriftEstimator::default();
+
+        let coordinated_clock = clock_sync.and_then(|x| {
+            make_coordinated_clock(services, &x, &mut clock_filter, &mut drift_estimator)
+        });
+
+        // The beacon makes the node request clock synchronizations, and the
+        // coordinator see the gate while it is not ready yet
+        let beacon = GateBeacon {
+            addr: address(services),
+            hardware_id: services.platform.hardware_id(),
+            state: gate_state,
+            sync: None,
+            role: self.settings.role,
+        };
+
+        if let Err(e) = services.platform.race_node().publish(beacon.into()) {
+            log::error!("{e}");
+        }
+
+        if let (Some(clock_sync), Some(coordinated_clock)) = (clock_sync, coordinated_clock) {
+            AppState::GateReady(Box::new(GateReadyState {
                 gate_state,
                 coordinated_clock,
-                last_activation_time: None,
-            })
+                clock_sync,
+                clock_filter,
+                drift_estimator,
+                settings: self.settings,
+            }))
         } else {
             AppState::GateStartup(*self)
         }
     }
 }
 
+/// Largest error of the coordinated time a gate can run with
+const MAX_CLOCK_UNCERTAINTY: Duration = Duration::from_millis(5);
+
+/// A gate not hearing the coordinator for this time has lost the link, so its
+/// activations are delivered late
+const LINK_LOSS_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 struct GateReadyState {
     gate_state: GateState,
     coordinated_clock: CoordinatedClock,
-    last_activation_time: Option<CoordinatedInstant>,
+    /// Synchronization the clock offset is calculated from
+    clock_sync: ClockSync,
+    clock_filter: ClockFilter,
+    drift_estimator: DriftEstimator,
+    settings: GateSettings,
 }
 
 impl GateReadyState {
@@ -265,20 +527,49 @@ impl GateReadyState {
         let gate_state = services.platform.gate().state();
         let button_state = services.platform.button().state();
 
-        let coordinated_clock = make_coordinated_clock(services).unwrap_or(self.coordinated_clock);
-        let coordinated_time = coordinated_clock.now();
+        self.settings.update(services);
+        let settings = self.settings;
 
-        if services
+        let clock_sync = services
             .platform
             .race_node()
-            .time_since_coordinator_beacon()
-            > Duration::from_secs(10)
-        {
-            // No beacon from coordinator, maybe due to a disconnection
-            return AppState::GateStartup(GateStartupState::default());
+            .clock_sync()
+            .unwrap_or(self.clock_sync);
+
+        let mut clock_filter = self.clock_filter;
+        let mut drift_estimator = self.drift_estimator;
+
+        // The offset changes only when a new synchronization is done
+        let coordinated_clock = if clock_sync != self.clock_sync {
+            make_coordinated_clock(
+                services,
+                &clock_sync,
+                &mut clock_filter,
+                &mut drift_estimator,
+            )
+            .map(|x| slew_coordinated_clock(services, &self.coordinated_clock, x))
+            .unwrap_or(self.coordinated_clock)
+        } else {
+            self.coordinated_clock
+        };
+
+        let local_time = services.local_time();
+        let coordinated_time = coordinated_clock.at_local(local_time);
+
+        // Without synchronization, e.g. when the coordinator is not reachable,
+        // the clock keeps going on the estimated drift until its error is too
+        // large to time a race. A correction being slewed is not a reason to
+        // synchronize again, since it is known.
+        let sync_uncertainty = coordinated_clock.sync_uncertainty_at(local_time);
+        if sync_uncertainty > MAX_CLOCK_UNCERTAINTY {
+            log::warn!(
+                "Clock uncertainty {}us, synchronizing again",
+                sync_uncertainty.as_micros()
+            );
+            return AppState::GateStartup(GateStartupState::after_link_loss(services.now(), self));
         }
 
-        log::trace!("coordinated_time: {}", coordinated_time.as_millis());
+        log::trace!("coordinated_time: {}", coordinated_time.as_micros());
 
         let addr = address(services);
 
@@ -286,32 +577,79 @@ impl GateReadyState {
         // like a gate active event.
         let gate_state = gate_state_or_button(gate_state, button_state);
 
-        let last_activation_time = if gate_state == GateState::Active {
-            Some(coordinated_time)
+        let race_node = services.platform.race_node();
+        let was_active = self.gate_state == GateState::Active;
+        let recovered = race_node.time_since_coordinator_beacon() > LINK_LOSS_TIMEOUT;
+
+        if settings.armed {
+            send_activations(services, &coordinated_clock, was_active, recovered);
+
+            if button_state == ButtonState::Pressed && !was_active {
+                race_node.send_activation(addr, coordinated_time, false, recovered);
+            }
         } else {
-            self.last_activation_time
-        };
+            while services.platform.gate().take_event().is_some() {}
+        }
 
         let beacon = GateBeacon {
             addr,
+            hardware_id: services.platform.hardware_id(),
             state: gate_state,
-            last_activation_time,
+            sync: Some(SyncQuality {
+                offset_us: coordinated_clock.offset().as_micros(),
+                error: coordinated_clock.uncertainty_at(local_time),
+                loss_percent: services.platform.race_node().sync_loss_percent(),
+            }),
+            role: settings.role,
         };
 
         if let Err(e) = services.platform.race_node().publish(beacon.into()) {
             log::error!("{e}");
         }
 
-        AppState::GateReady(GateReadyState {
+        AppState::GateReady(Box::new(GateReadyState {
             gate_state,
             coordinated_clock,
-            last_activation_time,
-        })
+            clock_sync,
+            clock_filter,
+            drift_estimator,
+            settings,
+        }))
+    }
+}
+
+/// Report the beam interruptions of this gate. Both the start and the end of
+/// an interruption are reported, the end as a continued activation.
+fn send_activations(
+    services: &Services,
+    coordinated_clock: &CoordinatedClock,
+    was_active: bool,
+    recovered: bool,
+) {
+    let race_node = services.platform.race_node();
+    let addr = address(services);
+    let mut active = was_active;
+
+    while let Some(event) = services.platform.gate().take_event() {
+        let is_new_activation = event.state == GateState::Active;
+
+        if is_new_activation || active {
+            if let Some(time) = coordinated_clock.at(event.time) {
+                race_node.send_activation(addr, time, active, recovered);
+            }
+        }
+
+        active = is_new_activation;
     }
 }
 
 fn address(services: &Services) -> NodeAddress {
-    address_from_env_var().unwrap_or_else(|| services.platform.dip_switch().address())
+    node_address(services.platform)
+}
+
+/// Address of this node, from the environment at build time or the dip switch
+pub fn node_address(platform: &dyn Platform) -> NodeAddress {
+    address_from_env_var().unwrap_or_else(|| platform.dip_switch().address())
 }
 
 fn address_from_env_var() -> Option<NodeAddress> {
@@ -321,15 +659,41 @@ fn address_from_env_var() -> Option<NodeAddress> {
         .map(NodeAddress::from)
 }
 
-fn make_coordinated_clock(services: &Services) -> Option<CoordinatedClock> {
-    let time = services.local_clock.now()?;
+fn make_coordinated_clock(
+    services: &Services,
+    clock_sync: &ClockSync,
+    clock_filter: &mut ClockFilter,
+    drift_estimator: &mut DriftEstimator,
+) -> Option<CoordinatedClock> {
+    let sample = ClockSample {
+        local: services.local_clock.at(clock_sync.instant)?,
+        time: clock_sync.time,
+        error: clock_sync.error,
+    };
 
-    services
-        .platform
-        .race_node()
-        .coordinator_time()
-        .map(|coord_time| calculate_clock_offset(coord_time, time))
-        .map(|clock_offset| CoordinatedClock::new(services.local_clock, clock_offset))
+    clock_filter.add(sample);
+    let best = clock_filter.best(&sample)?;
+
+    drift_estimator.update(best);
+
+    let clock_offset = calculate_clock_offset(best.time, best.local);
+    Some(
+        CoordinatedClock::new(services.local_clock, clock_offset).with_sync(
+            best.local,
+            best.error,
+            drift_estimator.drift(),
+        ),
+    )
+}
+
+/// Move to the new clock estimation without jumps, so timestamps never go
+/// backwards during a run
+fn slew_coordinated_clock(
+    services: &Services,
+    previous: &CoordinatedClock,
+    target: CoordinatedClock,
+) -> CoordinatedClock {
+    previous.slew_to(target, services.local_time())
 }
 
 fn gate_state_or_button(gate: GateState, button: ButtonState) -> GateState {
@@ -339,3 +703,333 @@ fn gate_state_or_button(gate: GateState, button: ButtonState) -> GateState {
         GateState::Inactive
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::hal::clock::Clock;
+    use crate::hal::gate::GateEvent;
+    use crate::hal::mock::MockPlatform;
+    use crate::svc::race_node::GateActivation;
+
+    use super::*;
+
+    /// Period of the main loop
+    const PERIOD: Duration = Duration::from_millis(20);
+
+    fn update_for(app: &mut App, platform: &MockPlatform, duration: Duration) {
+        let end = platform.clock.elapsed() + duration;
+        while platform.clock.elapsed() < end {
+            platform.clock.advance(PERIOD);
+            app.update();
+        }
+    }
+
+    fn sync_now(platform: &MockPlatform, time: CoordinatedInstant) {
+        platform.race_node.clock_sync.set(Some(ClockSync {
+            time,
+            instant: platform.clock.now(),
+            error: Duration::from_micros(200),
+        }));
+    }
+
+    fn last_gate_beacon(platform: &MockPlatform) -> Option<GateBeacon> {
+        platform
+            .race_node
+            .published
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|x| match x {
+                RaceNodeMessage::GateBeacon(x) => Some(*x),
+                _ => None,
+            })
+    }
+
+    fn make_ready_gate(platform: &MockPlatform) -> App<'_> {
+        let mut app = App::new(platform);
+        update_for(&mut app, platform, PERIOD);
+        platform.clock.advance(PERIOD);
+        sync_now(platform, CoordinatedInstant::from_millis(60_000));
+        app.update();
+        assert!(matches!(app.state, AppState::GateReady(_)));
+        app
+    }
+
+    #[test]
+    fn test_coordinator_publishes_its_local_time() {
+        let platform = MockPlatform::new(NodeAddress::coordinator());
+        let mut app = App::new(&platform);
+
+        update_for(&mut app, &platform, Duration::from_secs(1));
+
+        assert!(matches!(app.state, AppState::CoordinatorReady(_)));
+        assert_eq!(
+            platform.race_node.coordinator_time.get(),
+            Some(CoordinatedInstant::from_millis(1_000))
+        );
+    }
+
+    #[test]
+    fn test_gate_is_ready_after_clock_sync() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = App::new(&platform);
+
+        update_for(&mut app, &platform, Duration::from_secs(1));
+        assert!(matches!(app.state, AppState::GateStartup(_)));
+        assert_eq!(platform.rgb_led.color.get(), 0xFFFF00);
+
+        sync_now(&platform, CoordinatedInstant::from_millis(60_000));
+        app.update();
+        assert!(matches!(app.state, AppState::GateReady(_)));
+        assert_eq!(platform.rgb_led.color.get(), 0x00FF00);
+
+        app.update();
+        let sync = last_gate_beacon(&platform).and_then(|x| x.sync).unwrap();
+        assert_eq!(sync.offset_us, 59_000_000);
+        assert_eq!(sync.error, Duration::from_micros(200));
+    }
+
+    #[test]
+    fn test_gate_timestamps_activation_when_beam_is_interrupted() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        platform.clock.advance(PERIOD);
+        platform.gate.push_event(GateEvent {
+            state: GateState::Active,
+            time: platform.clock.now() - Duration::from_millis(7),
+        });
+        app.update();
+
+        let beacon = last_gate_beacon(&platform).unwrap();
+        assert_eq!(beacon.state, GateState::Active);
+
+        let activations = platform.race_node.sent_activations.borrow();
+        assert_eq!(
+            *activations,
+            vec![GateActivation {
+                addr: NodeAddress::from(1),
+                seq: 0,
+                time: CoordinatedInstant::from_millis(60_013),
+                continued: false,
+                recovered: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_gate_reports_both_ends_of_a_short_interruption() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        platform.clock.advance(PERIOD);
+        for (state, ago_ms) in [(GateState::Active, 15), (GateState::Inactive, 5)] {
+            platform.gate.push_event(GateEvent {
+                state,
+                time: platform.clock.now() - Duration::from_millis(ago_ms),
+            });
+        }
+        app.update();
+
+        let activations = platform.race_node.sent_activations.borrow();
+        let continued: Vec<_> = activations.iter().map(|x| x.continued).collect();
+        assert_eq!(continued, vec![false, true]);
+        assert_eq!(
+            last_gate_beacon(&platform).unwrap().state,
+            GateState::Inactive
+        );
+    }
+
+    #[test]
+    fn test_gate_keeps_time_until_uncertainty_is_too_large() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        // 200us + 50ppm for 90s is 4.7ms
+        update_for(&mut app, &platform, Duration::from_secs(90));
+        assert!(matches!(app.state, AppState::GateReady(_)));
+
+        update_for(&mut app, &platform, Duration::from_secs(10));
+        assert!(matches!(app.state, AppState::GateStartup(_)));
+    }
+
+    #[test]
+    fn test_gate_slews_corrections_larger_than_the_uncertainty_limit() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+        let synced = platform.clock.now();
+
+        // Coordinated time as kept by the gate, without corrections
+        let gate_time = |platform: &MockPlatform| {
+            let elapsed = platform.clock.now() - synced;
+            CoordinatedInstant::from_micros(60_000_000 + elapsed.as_micros() as i64)
+        };
+
+        update_for(&mut app, &platform, Duration::from_secs(1));
+
+        // The coordinator time is 7ms ahead of the gate clock
+        let time = CoordinatedInstant::from_micros(gate_time(&platform).as_micros() + 7_000);
+        sync_now(&platform, time);
+        app.update();
+
+        update_for(&mut app, &platform, Duration::from_secs(1));
+        assert!(matches!(app.state, AppState::GateReady(_)));
+
+        platform.gate.push_event(GateEvent {
+            state: GateState::Active,
+            time: platform.clock.now(),
+        });
+        app.update();
+
+        // Slewed at 500ppm, without jumps
+        let activation = *platform.race_node.sent_activations.borrow().last().unwrap();
+        let ahead = activation.time.as_micros() - gate_time(&platform).as_micros();
+        assert!((500..1_000).contains(&ahead), "{ahead}");
+        assert!(!activation.recovered);
+    }
+
+    #[test]
+    fn test_gate_keeps_activations_after_losing_coordinator() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        platform
+            .race_node
+            .time_since_coordinator_beacon
+            .set(Duration::from_secs(5));
+        update_for(&mut app, &platform, Duration::from_secs(100));
+        assert!(matches!(app.state, AppState::GateStartup(_)));
+
+        // Timestamped with the last clock, even if it is not synchronized
+        platform.gate.push_event(GateEvent {
+            state: GateState::Active,
+            time: platform.clock.now(),
+        });
+        app.update();
+
+        let activation = *platform.race_node.sent_activations.borrow().last().unwrap();
+        assert!(activation.recovered);
+        assert!(!activation.continued);
+
+        // Not delivered yet, so the gate must not restart
+        update_for(&mut app, &platform, Duration::from_secs(11));
+        assert!(matches!(app.state, AppState::GateStartup(_)));
+    }
+
+    #[test]
+    fn test_gate_waits_for_coordinator_after_link_loss() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        platform
+            .race_node
+            .time_since_coordinator_beacon
+            .set(Duration::from_secs(5));
+        platform.wifi.up.set(false);
+
+        // No activation to deliver, but the gate doesn't restart
+        update_for(&mut app, &platform, Duration::from_secs(200));
+        assert!(matches!(app.state, AppState::GateStartup(_)));
+        assert!(platform.wifi.reconnect_count.get() > 0);
+
+        platform.wifi.up.set(true);
+        sync_now(&platform, CoordinatedInstant::from_millis(300_000));
+        app.update();
+        assert!(matches!(app.state, AppState::GateReady(_)));
+    }
+
+    #[test]
+    fn test_gate_applies_coordinator_commands() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        let role = GateRole::Split(1);
+        platform.race_node.commands.borrow_mut().extend([
+            GateCommand::SetRole { role },
+            GateCommand::Disarm,
+            GateCommand::SetLed {
+                color: Some(0xFF00FF),
+            },
+        ]);
+        app.update();
+        assert_eq!(last_gate_beacon(&platform).unwrap().role, Some(role));
+        assert_eq!(platform.rgb_led.color.get(), 0xFF00FF);
+
+        // A disarmed gate doesn't report activations
+        platform.gate.push_event(GateEvent {
+            state: GateState::Active,
+            time: platform.clock.now(),
+        });
+        app.update();
+        assert!(platform.race_node.sent_activations.borrow().is_empty());
+
+        platform.race_node.commands.borrow_mut().extend([
+            GateCommand::Arm,
+            GateCommand::SetLed { color: None },
+            GateCommand::Reboot,
+        ]);
+        app.update();
+        assert_eq!(platform.rgb_led.color.get(), 0x0000FF);
+        assert_eq!(platform.reboots.get(), 0);
+
+        // Reboot waits for the acknowledgement to be sent
+        update_for(&mut app, &platform, REBOOT_DELAY);
+        assert_eq!(platform.reboots.get(), 1);
+    }
+
+    #[test]
+    fn test_gate_blinks_to_identify_itself() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = make_ready_gate(&platform);
+
+        platform
+            .race_node
+            .commands
+            .borrow_mut()
+            .push(GateCommand::Identify);
+
+        let mut colors = Vec::new();
+        for _ in 0..20 {
+            platform.clock.advance(PERIOD);
+            app.update();
+            colors.push(platform.rgb_led.color.get());
+        }
+        assert!(colors.contains(&0xFFFFFF));
+        assert!(colors.contains(&0x000000));
+
+        update_for(&mut app, &platform, IDENTIFY_DURATION);
+        assert_eq!(platform.rgb_led.color.get(), 0x00FF00);
+    }
+
+    #[test]
+    fn test_coordinator_forwards_gate_commands() {
+        let platform = MockPlatform::new(NodeAddress::coordinator());
+        let mut app = App::new(&platform);
+        update_for(&mut app, &platform, PERIOD);
+
+        let addr = NodeAddress::from(3);
+        platform
+            .http_server
+            .commands
+            .borrow_mut()
+            .push_back(OperatorCommand::Gate {
+                addr,
+                command: GateCommand::Identify,
+            });
+        app.update();
+
+        assert_eq!(
+            *platform.race_node.sent_commands.borrow(),
+            vec![(addr, GateCommand::Identify)]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_gate_startup_without_coordinator_panics() {
+        let platform = MockPlatform::new(NodeAddress::from(1));
+        let mut app = App::new(&platform);
+
+        update_for(&mut app, &platform, Duration::from_secs(11));
+    }
+}
@@ -0,0 +1,26 @@
+use crate::app::course::Course;
+use crate::app::racers::Racer;
+use crate::svc::race_node::{GateCommand, NodeAddress};
+
+/// Commands sent by the operator to the coordinator
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum OperatorCommand {
+    /// Close this race with the next finish gate activation, even if other
+    /// races started before it (e.g. a racer has been overtaken).
+    Promote { race: u32 },
+    /// Abort a race on course
+    Abort { race: u32 },
+    /// Start a new session, discarding history and leaderboard
+    ClearHistory,
+    /// Replace the racers registry
+    SetRacers { racers: Vec<Racer> },
+    /// Replace the start list, with bib numbers in start order
+    SetStartList { bibs: Vec<u16> },
+    /// Replace the course layout, only when no racer is on course
+    SetCourse { course: Course },
+    /// Forward a command to a gate
+    Gate {
+        addr: NodeAddress,
+        command: GateCommand,
+    },
+}
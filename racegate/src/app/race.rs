@@ -1,86 +1,290 @@
 use std::time::Duration;
 
-use crate::app::gates::Gates;
+use crate::app::racers::Racer;
+use crate::svc::race_node::NodeAddress;
 use crate::svc::CoordinatedInstant;
 
+/// A race not finished within this time is considered Did Not Finish
+const DNF_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Lifecycle of a race.
+///
+/// ```text
+/// Idle -> Armed              start gate is alive and its beam is clear
+/// Armed -> Idle              nodes share an address
+/// Armed -> Running           start gate activated
+/// Idle -> Running            start gate activation buffered during a link loss
+/// Running -> Finished        finish gate activated
+/// Running -> Dnf             no finish within DNF_TIMEOUT
+/// Armed, Running -> Aborted  aborted by the operator, or nodes share an address
+/// ```
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RaceState {
+    #[default]
+    Idle,
+    Armed,
+    Running,
+    Finished,
+    Aborted,
+    Dnf,
+}
+
+impl RaceState {
+    /// The race is over, with or without a valid result
+    pub fn is_over(&self) -> bool {
+        matches!(
+            self,
+            RaceState::Finished | RaceState::Aborted | RaceState::Dnf
+        )
+    }
+}
+
+/// Emitted on every transition of [RaceState]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RaceEvent {
+    Armed,
+    Started,
+    Split(NodeAddress),
+    /// Lap completed on the lap gate, with its number
+    Lap(u32),
+    Finished,
+    Aborted,
+    Dnf,
+}
+
+/// A single run, from the start gate to the finish gate
 #[derive(Debug, Default, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Race {
+    /// Progressive number of the race in the session
+    pub id: u32,
+    /// Racer from the start list, if any
+    pub racer: Option<Racer>,
+    pub state: RaceState,
+    /// Time of the last state transition
+    pub state_time: Option<CoordinatedInstant>,
     pub start_time: Option<CoordinatedInstant>,
     pub finish_time: Option<CoordinatedInstant>,
     pub duration: Option<Duration>,
+    pub splits: Vec<Split>,
+    /// Some times were taken by gates with poor clock synchronization
+    pub poor_sync: bool,
+    /// Some times were delivered by gates after losing the coordinator
+    pub recovered: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Split {
+    /// Address of the intermediate gate
+    pub addr: NodeAddress,
+    pub time: CoordinatedInstant,
+    /// Time elapsed since start
+    pub elapsed: Duration,
+    /// Time elapsed since the previous split (or since start for the first one)
+    pub segment: Duration,
 }
 
 impl Race {
-    pub fn set_gates(&mut self, gates: &Gates) {
-        let start_time = gates.start_gate().last_activation_time;
-        let finish_time = gates.finish_gate().last_activation_time;
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            ..Default::default()
+        }
+    }
+
+    pub fn arm(&mut self, now: CoordinatedInstant) -> Option<RaceEvent> {
+        if self.state != RaceState::Idle {
+            return None;
+        }
+
+        self.transition(RaceState::Armed, now)
+    }
+
+    pub fn disarm(&mut self, now: CoordinatedInstant) {
+        if self.state == RaceState::Armed {
+            self.transition(RaceState::Idle, now);
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        start_time: CoordinatedInstant,
+        now: CoordinatedInstant,
+    ) -> Option<RaceEvent> {
+        // Activations older than arming are stale
+        let after_arming = self.state_time.map(|x| start_time > x).unwrap_or(true);
 
-        // Always override start time if it is defined
-        if let Some(start_time) = start_time {
+        if self.state != RaceState::Armed || !after_arming {
+            return None;
+        }
+
+        self.start_time = Some(start_time);
+        self.transition(RaceState::Running, now)
+    }
+
+    /// Start with an activation buffered by the start gate while it lost the
+    /// coordinator. Arming happens when the gate is heard again, so the start
+    /// time can be older than it.
+    pub fn start_recovered(
+        &mut self,
+        start_time: CoordinatedInstant,
+        now: CoordinatedInstant,
+    ) -> Option<RaceEvent> {
+        if !matches!(self.state, RaceState::Idle | RaceState::Armed) {
+            return None;
+        }
+
+        self.start_time = Some(start_time);
+        self.transition(RaceState::Running, now)
+    }
+
+    /// Start gate keeps updating its activation time while the beam is
+    /// interrupted, so the start time is when the racer leaves it.
+    pub fn refine_start(&mut self, start_time: CoordinatedInstant) {
+        if self.state == RaceState::Running && self.is_after_start(start_time) {
             self.start_time = Some(start_time);
+            self.update_splits();
+        }
+    }
+
+    pub fn split(&mut self, addr: NodeAddress, time: CoordinatedInstant) -> Option<RaceEvent> {
+        // Only the first activation of each intermediate gate is a split,
+        // like it happens for the finish gate.
+        if !self.accepts_split(addr, time) {
+            return None;
         }
 
-        // Override finish time only if it is not already defined
-        if self.finish_time.is_none() {
-            if let Some(finish_time) = finish_time {
-                self.finish_time = Some(finish_time);
-            }
+        self.splits.push(Split {
+            addr,
+            time,
+            elapsed: Duration::ZERO,
+            segment: Duration::ZERO,
+        });
+
+        self.update_splits();
+
+        Some(RaceEvent::Split(addr))
+    }
+
+    pub fn finish(
+        &mut self,
+        finish_time: CoordinatedInstant,
+        now: CoordinatedInstant,
+    ) -> Option<RaceEvent> {
+        if !self.accepts_finish(finish_time) {
+            return None;
         }
 
-        if let Some(start_time) = start_time {
-            if let Some(finish_time) = self.finish_time {
-                if start_time > finish_time {
-                    // When start gate is activated after finish gate, it means
-                    // we have a new race, so we must reset.
-                    self.finish_time = None;
-                    self.duration = None;
-                } else {
-                    // When finish gate is activated after start gate, it means
-                    // the race has just ended and we can calculate the duration.
-                    let duration_millis = finish_time.as_millis() - start_time.as_millis();
-                    self.duration = Some(Duration::from_millis(duration_millis as u64));
-                }
-            }
+        let start_time = self.start_time?;
+
+        self.finish_time = Some(finish_time);
+        self.duration = Some(finish_time.duration_since(start_time));
+        self.transition(RaceState::Finished, now)
+    }
+
+    pub fn check_timeout(&mut self, now: CoordinatedInstant) -> Option<RaceEvent> {
+        let timed_out = self
+            .start_time
+            .map(|x| now.duration_since(x) > DNF_TIMEOUT)
+            .unwrap_or(false);
+
+        if self.state == RaceState::Running && timed_out {
+            self.transition(RaceState::Dnf, now)
+        } else {
+            None
         }
     }
 
+    /// Abort the race, if it is armed or running
+    pub fn abort(&mut self, now: CoordinatedInstant) -> Option<RaceEvent> {
+        if matches!(self.state, RaceState::Armed | RaceState::Running) {
+            self.transition(RaceState::Aborted, now)
+        } else {
+            None
+        }
+    }
+
+    pub fn accepts_split(&self, addr: NodeAddress, time: CoordinatedInstant) -> bool {
+        let already_taken = self.splits.iter().any(|x| x.addr == addr);
+        self.state == RaceState::Running && self.is_after_start(time) && !already_taken
+    }
+
+    pub fn accepts_finish(&self, time: CoordinatedInstant) -> bool {
+        self.state == RaceState::Running && self.is_after_start(time)
+    }
+
+    fn transition(&mut self, state: RaceState, now: CoordinatedInstant) -> Option<RaceEvent> {
+        self.state = state;
+        self.state_time = Some(now);
+
+        match state {
+            RaceState::Idle => None,
+            RaceState::Armed => Some(RaceEvent::Armed),
+            RaceState::Running => Some(RaceEvent::Started),
+            RaceState::Finished => Some(RaceEvent::Finished),
+            RaceState::Aborted => Some(RaceEvent::Aborted),
+            RaceState::Dnf => Some(RaceEvent::Dnf),
+        }
+    }
+
+    fn is_after_start(&self, t: CoordinatedInstant) -> bool {
+        self.start_time.map(|x| t > x).unwrap_or(false)
+    }
+
+    fn update_splits(&mut self) {
+        let Some(start_time) = self.start_time else {
+            self.splits.clear();
+            return;
+        };
+
+        // Start time can be refined, discarding splits taken before it
+        self.splits.retain(|x| x.time > start_time);
+        self.splits.sort_by_key(|x| x.time);
+
+        let mut previous_time = start_time;
+        for split in self.splits.iter_mut() {
+            split.elapsed = split.time.duration_since(start_time);
+            split.segment = split.time.duration_since(previous_time);
+            previous_time = split.time;
+        }
+    }
+
+    pub fn state(&self) -> RaceState {
+        self.state
+    }
+
     pub fn duration(&self) -> Option<Duration> {
         self.duration
     }
+
+    /// Duration of a finished race or, while running, time since start
+    pub fn elapsed(&self, now: CoordinatedInstant) -> Option<Duration> {
+        match self.state {
+            RaceState::Running => self.start_time.map(|x| now.duration_since(x)),
+            RaceState::Finished => self.duration,
+            _ => None,
+        }
+    }
+
+    pub fn splits(&self) -> &[Split] {
+        &self.splits
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use insta::assert_debug_snapshot;
 
-    use crate::app::gates::Gate;
-
     use super::*;
 
-    fn make_active_gate(time_ms: i32) -> Gate {
-        let t = Some(CoordinatedInstant::from_millis(time_ms));
-        Gate {
-            active: true,
-            last_activation_time: t,
-            last_beacon_time: t,
-        }
+    fn ms(time_ms: i32) -> CoordinatedInstant {
+        CoordinatedInstant::from_millis(time_ms)
     }
 
-    fn make_inactive_gate(time_ms: i32) -> Gate {
-        let t = Some(CoordinatedInstant::from_millis(time_ms));
-        Gate {
-            active: false,
-            last_activation_time: t,
-            last_beacon_time: t,
-        }
-    }
-
-    fn make_never_activated_gate() -> Gate {
-        Gate {
-            active: false,
-            last_activation_time: None,
-            last_beacon_time: None,
-        }
+    fn make_running_race() -> Race {
+        let mut race = Race::new(1);
+        assert_eq!(race.arm(ms(5_000)), Some(RaceEvent::Armed));
+        assert_eq!(race.start(ms(10_000), ms(10_000)), Some(RaceEvent::Started));
+        race
     }
 
     #[test]
@@ -90,85 +294,70 @@ mod tests {
     }
 
     #[test]
-    fn test_race_with_start_gate_active() {
-        let mut race = Race::default();
-        race.set_gates(&Gates::new([
-            make_active_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-        ]));
-        assert_debug_snapshot!(race);
+    fn test_race_is_not_started_by_stale_activation() {
+        let mut race = Race::new(1);
+        race.arm(ms(5_000));
+        assert_eq!(race.start(ms(4_000), ms(5_020)), None);
+        assert_eq!(race.state(), RaceState::Armed);
     }
 
     #[test]
-    fn test_race_with_start_and_finish_gates_active() {
-        let mut race = Race::default();
-        race.set_gates(&Gates::new([
-            make_active_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-        ]));
-        race.set_gates(&Gates::new([
-            make_active_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_active_gate(20_000),
-        ]));
-        assert_debug_snapshot!(race);
+    fn test_race_is_started_by_recovered_activation() {
+        let mut race = Race::new(1);
+        race.arm(ms(5_000));
+        assert_eq!(
+            race.start_recovered(ms(4_000), ms(5_020)),
+            Some(RaceEvent::Started)
+        );
+        assert_eq!(race.start_time, Some(ms(4_000)));
+        assert_eq!(race.start_recovered(ms(4_500), ms(5_040)), None);
     }
 
     #[test]
-    fn test_race_with_start_after_finish() {
-        let mut race = Race::default();
-        race.set_gates(&Gates::new([
-            make_active_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-        ]));
-        race.set_gates(&Gates::new([
-            make_active_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_active_gate(20_000),
-        ]));
-        race.set_gates(&Gates::new([
-            make_active_gate(30_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_active_gate(20_000),
-        ]));
+    fn test_race_with_splits() {
+        let mut race = make_running_race();
+        let addr_2 = NodeAddress::from(2);
+        let addr_3 = NodeAddress::from(3);
+
+        assert_eq!(
+            race.split(addr_3, ms(17_000)),
+            Some(RaceEvent::Split(addr_3))
+        );
+        assert_eq!(
+            race.split(addr_2, ms(13_000)),
+            Some(RaceEvent::Split(addr_2))
+        );
+        assert_eq!(race.split(addr_2, ms(13_500)), None);
+        assert_eq!(
+            race.finish(ms(20_000), ms(20_000)),
+            Some(RaceEvent::Finished)
+        );
+
+        // Only the first activation of a split gate is taken
+        assert_eq!(race.splits[0].elapsed, Duration::from_secs(3));
+        assert_eq!(race.splits[1].segment, Duration::from_secs(4));
+
         assert_debug_snapshot!(race);
     }
 
     #[test]
-    fn test_race_with_two_finish_activations() {
-        let mut race = Race::default();
-        race.set_gates(&Gates::new([
-            make_active_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-        ]));
-        race.set_gates(&Gates::new([
-            make_inactive_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_active_gate(20_000),
-        ]));
-        race.set_gates(&Gates::new([
-            make_inactive_gate(10_000),
-            make_never_activated_gate(),
-            make_never_activated_gate(),
-            make_active_gate(30_000),
-        ]));
-
-        // Even if the finish gate has been activated again, the duration is
-        // calculated start gate activation and first finish gate activation.
-        assert_eq!(race.duration, Some(Duration::from_secs(10)));
+    fn test_race_not_finished_in_time() {
+        let mut race = make_running_race();
+        assert_eq!(race.check_timeout(ms(10_000) + DNF_TIMEOUT), None);
+        let now = ms(10_000) + DNF_TIMEOUT + Duration::from_millis(1);
+        assert_eq!(race.check_timeout(now), Some(RaceEvent::Dnf));
+        assert_eq!(race.duration(), None);
+    }
 
-        assert_debug_snapshot!(race);
+    #[test]
+    fn test_race_aborted() {
+        let mut race = make_running_race();
+        assert_eq!(race.abort(ms(11_000)), Some(RaceEvent::Aborted));
+        assert_eq!(race.abort(ms(12_000)), None);
+
+        // A late finish activation does not change an aborted race
+        assert_eq!(race.finish(ms(13_000), ms(13_000)), None);
+        assert_eq!(race.state(), RaceState::Aborted);
+        assert_eq!(race.finish_time, None);
     }
 }
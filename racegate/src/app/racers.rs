@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Default, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Racer {
+    /// Bib number, unique in the registry
+    pub bib: u16,
+    pub name: String,
+    pub category: String,
+}
+
+impl Display for Racer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.bib, self.name)
+    }
+}
+
+/// Racers registry and start list, held by the coordinator
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Racers {
+    registry: Vec<Racer>,
+    /// Bibs of racers waiting to start, in start order
+    start_list: VecDeque<u16>,
+}
+
+impl Racers {
+    /// Replace the registry. A racer registered twice keeps the last entry.
+    pub fn set_registry(&mut self, racers: Vec<Racer>) {
+        self.registry.clear();
+
+        for racer in racers {
+            self.registry.retain(|x| x.bib != racer.bib);
+            self.registry.push(racer);
+        }
+    }
+
+    /// Replace the start list. Bibs not in the registry are discarded.
+    pub fn set_start_list(&mut self, bibs: Vec<u16>) {
+        self.start_list = bibs
+            .into_iter()
+            .filter(|&x| self.get(x).is_some())
+            .collect();
+    }
+
+    pub fn get(&self, bib: u16) -> Option<&Racer> {
+        self.registry.iter().find(|x| x.bib == bib)
+    }
+
+    /// Next racer expected at the start gate
+    pub fn next(&self) -> Option<&Racer> {
+        self.start_list.front().and_then(|&x| self.get(x))
+    }
+
+    /// Remove the next racer from the start list
+    pub fn take_next(&mut self) -> Option<Racer> {
+        let bib = self.start_list.pop_front()?;
+        self.get(bib).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_racer(bib: u16, name: &str) -> Racer {
+        Racer {
+            bib,
+            name: name.to_owned(),
+            category: "U16".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_start_list_follows_given_order() {
+        let mut racers = Racers::default();
+        racers.set_registry(vec![make_racer(1, "Anna"), make_racer(2, "Bruno")]);
+        racers.set_start_list(vec![2, 7, 1]);
+
+        assert_eq!(racers.next().map(|x| x.bib), Some(2));
+        assert_eq!(racers.take_next().map(|x| x.bib), Some(2));
+        // Bib 7 is not registered
+        assert_eq!(racers.take_next().map(|x| x.bib), Some(1));
+        assert_eq!(racers.take_next(), None);
+    }
+
+    #[test]
+    fn test_registry_keeps_last_entry_of_same_bib() {
+        let mut racers = Racers::default();
+        racers.set_registry(vec![make_racer(1, "Anna"), make_racer(1, "Bruno")]);
+
+        assert_eq!(racers.get(1).map(|x| x.name.as_str()), Some("Bruno"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use crate::svc::race_node::{HardwareId, NodeAddress};
+use crate::svc::CoordinatedInstant;
+
+/// Nodes not heard for this time leave the roster, e.g. a gate switched off
+const ROSTER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A node heard by the coordinator
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RosterEntry {
+    pub hardware_id: HardwareId,
+    /// Address the node is using
+    pub addr: NodeAddress,
+    pub last_seen: CoordinatedInstant,
+}
+
+/// Nodes with different hardware using the same address. Their messages
+/// can't be told apart, so the coordinator mixes them up.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AddressConflict {
+    pub addr: NodeAddress,
+    pub hardware_ids: Vec<HardwareId>,
+}
+
+/// Every node recently heard by the coordinator, itself included, keyed by
+/// hardware id. A conflict lasts until the nodes use different addresses, or
+/// one of them is not heard anymore.
+#[derive(Debug, Default, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Roster {
+    /// Sorted by address, then by hardware id
+    entries: Vec<RosterEntry>,
+    conflicts: Vec<AddressConflict>,
+}
+
+impl Roster {
+    pub fn entries(&self) -> &[RosterEntry] {
+        &self.entries
+    }
+
+    pub fn conflicts(&self) -> &[AddressConflict] {
+        &self.conflicts
+    }
+
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts.is_empty()
+    }
+
+    /// A message of the node `hardware_id` with address `addr` was received
+    /// at `time`
+    pub(crate) fn update(
+        &mut self,
+        hardware_id: HardwareId,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+    ) {
+        self.entries.retain(|x| x.hardware_id != hardware_id);
+        self.entries.push(RosterEntry {
+            hardware_id,
+            addr,
+            last_seen: time,
+        });
+
+        self.entries.retain(|x| {
+            let elapsed_us = time.as_micros() - x.last_seen.as_micros();
+            elapsed_us < ROSTER_TIMEOUT.as_micros() as i64
+        });
+
+        self.entries
+            .sort_by_key(|x| (x.addr.as_gate_index(), x.hardware_id));
+
+        self.update_conflicts();
+    }
+
+    fn update_conflicts(&mut self) {
+        let mut conflicts: Vec<AddressConflict> = Vec::new();
+
+        for pair in self.entries.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+
+            if a.addr != b.addr {
+                continue;
+            }
+
+            match conflicts.last_mut() {
+                Some(conflict) if conflict.addr == a.addr => {
+                    conflict.hardware_ids.push(b.hardware_id)
+                }
+                _ => conflicts.push(AddressConflict {
+                    addr: a.addr,
+                    hardware_ids: vec![a.hardware_id, b.hardware_id],
+                }),
+            }
+        }
+
+        if conflicts != self.conflicts {
+            for conflict in &conflicts {
+                let ids: Vec<_> = conflict
+                    .hardware_ids
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect();
+                log::error!("Address {:?} used by {}", conflict.addr, ids.join(", "));
+            }
+        }
+
+        self.conflicts = conflicts;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(x: u8) -> HardwareId {
+        HardwareId([0, 0, 0, 0, 0, x])
+    }
+
+    fn ms(x: i32) -> CoordinatedInstant {
+        CoordinatedInstant::from_millis(x)
+    }
+
+    #[test]
+    fn test_nodes_are_keyed_by_hardware_id() {
+        let mut roster = Roster::default();
+        roster.update(id(1), NodeAddress::coordinator(), ms(0));
+        roster.update(id(2), NodeAddress::from(2), ms(10));
+        roster.update(id(3), NodeAddress::from(1), ms(20));
+
+        // The node changed address
+        roster.update(id(2), NodeAddress::from(3), ms(30));
+
+        let addrs: Vec<_> = roster
+            .entries()
+            .iter()
+            .map(|x| (x.hardware_id, x.addr))
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                (id(1), NodeAddress::coordinator()),
+                (id(3), NodeAddress::from(1)),
+                (id(2), NodeAddress::from(3)),
+            ]
+        );
+        assert!(!roster.has_conflicts());
+    }
+
+    #[test]
+    fn test_conflict_is_kept_until_resolved() {
+        let mut roster = Roster::default();
+        roster.update(id(1), NodeAddress::from(2), ms(0));
+        roster.update(id(2), NodeAddress::from(2), ms(10));
+        roster.update(id(3), NodeAddress::from(2), ms(20));
+
+        assert_eq!(
+            roster.conflicts(),
+            [AddressConflict {
+                addr: NodeAddress::from(2),
+                hardware_ids: vec![id(1), id(2), id(3)],
+            }]
+        );
+
+        // Node 3 is given another address
+        roster.update(id(3), NodeAddress::from(5), ms(1_000));
+        assert_eq!(roster.conflicts()[0].hardware_ids, vec![id(1), id(2)]);
+
+        // Node 1 is switched off
+        roster.update(id(2), NodeAddress::from(2), ms(2_000));
+        assert!(roster.has_conflicts());
+        roster.update(id(2), NodeAddress::from(2), ms(3_000));
+        assert!(!roster.has_conflicts());
+        assert_eq!(roster.entries().len(), 2);
+    }
+}
@@ -0,0 +1,1118 @@
+use std::collections::VecDeque;
+
+use crate::app::course::{Course, GateRole};
+use crate::app::gates::{Gate, Gates};
+use crate::app::history::History;
+use crate::app::laps::Laps;
+use crate::app::operator::OperatorCommand;
+use crate::app::race::{Race, RaceEvent};
+use crate::app::racers::{Racer, Racers};
+use crate::svc::race_node::{GateActivation, NodeAddress};
+use crate::svc::CoordinatedInstant;
+
+/// Activations of split and finish gates matching no race, kept in case the
+/// start of their race is delivered late
+const UNMATCHED_SIZE: usize = 16;
+
+/// Races handled by the coordinator. Many racers can be on course at the same
+/// time: each start gate activation opens a race and each finish gate
+/// activation closes the oldest open race.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Timing {
+    pub course: Course,
+    /// Race waiting for the start gate
+    pub next: Race,
+    /// Races on course, in finish order
+    pub running: VecDeque<Race>,
+    /// Races that are over
+    pub history: History,
+    /// Lap times, when the course has a lap gate
+    pub laps: Laps,
+    /// Nodes share an address, so races are aborted and not armed until it
+    /// is resolved
+    pub address_conflict: bool,
+    /// Registry and start list are not part of the state sent to clients
+    #[serde(skip)]
+    racers: Racers,
+    #[serde(skip)]
+    unmatched: VecDeque<Activation>,
+    /// Race opened by the current beam interruption of the start gate
+    #[serde(skip)]
+    opened_by_start: Option<u32>,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            course: Course::default(),
+            next: Race::new(1),
+            running: VecDeque::new(),
+            history: History::default(),
+            laps: Laps::default(),
+            address_conflict: false,
+            racers: Racers::default(),
+            unmatched: VecDeque::new(),
+            opened_by_start: None,
+        }
+    }
+}
+
+impl Timing {
+    /// Advance all races with the latest gates state and the activations
+    /// received since the last call, returning the events caused by the
+    /// transitions.
+    pub fn set_gates(
+        &mut self,
+        gates: &Gates,
+        activations: &[GateActivation],
+        now: CoordinatedInstant,
+    ) -> Vec<RaceEvent> {
+        let mut events = Vec::new();
+
+        let start_gate = self.course.start().and_then(|x| gates.get(x));
+
+        let start_ready = start_gate.map(|x| is_ready(x, now)).unwrap_or(false);
+
+        if self.address_conflict {
+            // Activations of nodes sharing an address can't be told apart, so
+            // no race is timed until it is resolved
+            self.next.disarm(now);
+            for race in self.running.iter_mut() {
+                events.extend(race.abort(now));
+            }
+        } else if start_ready {
+            events.extend(self.next.arm(now));
+        }
+
+        let mut activations: Vec<_> = activations
+            .iter()
+            .map(|x| Activation::new(x, gates))
+            .collect();
+
+        activations.sort_by_key(|x| x.time);
+
+        for activation in activations {
+            let event = self.on_activation(activation, now);
+            let started = event == Some(RaceEvent::Started);
+            events.extend(event);
+
+            if started {
+                events.extend(self.replay_unmatched(now));
+            }
+        }
+
+        for race in self.running.iter_mut() {
+            events.extend(race.check_timeout(now));
+        }
+
+        self.close_races();
+
+        events
+    }
+
+    pub fn apply(&mut self, command: OperatorCommand, now: CoordinatedInstant) -> Vec<RaceEvent> {
+        let mut events = Vec::new();
+
+        match command {
+            OperatorCommand::Promote { race } => {
+                if let Some(index) = self.running.iter().position(|x| x.id == race) {
+                    if let Some(race) = self.running.remove(index) {
+                        self.running.push_front(race);
+                    }
+                }
+            }
+            OperatorCommand::Abort { race } => {
+                if let Some(race) = self.running.iter_mut().find(|x| x.id == race) {
+                    events.extend(race.abort(now));
+                }
+            }
+            OperatorCommand::ClearHistory => {
+                self.history.clear();
+                self.laps = Laps::new(now);
+            }
+            OperatorCommand::SetRacers { racers } => {
+                self.racers.set_registry(racers);
+            }
+            OperatorCommand::SetStartList { bibs } => {
+                self.racers.set_start_list(bibs);
+            }
+            OperatorCommand::SetCourse { course } => {
+                self.set_course(course, now);
+            }
+            OperatorCommand::Gate { .. } => {}
+        }
+
+        self.close_races();
+
+        events
+    }
+
+    /// The race worth showing: the first on course or the last one over
+    pub fn current(&self) -> &Race {
+        self.running
+            .front()
+            .or(self.history.last())
+            .unwrap_or(&self.next)
+    }
+
+    /// Racer of the current race
+    pub fn current_racer(&self) -> Option<&Racer> {
+        self.current().racer.as_ref()
+    }
+
+    /// Racer bound to the next race opened by the start gate
+    pub fn next_racer(&self) -> Option<&Racer> {
+        self.racers.next()
+    }
+
+    fn set_course(&mut self, course: Course, now: CoordinatedInstant) {
+        if let Err(e) = course.validate() {
+            log::error!("Invalid course: {:?}", e);
+        } else if !self.running.is_empty() {
+            log::error!("Cannot change course while racers are on course");
+        } else {
+            self.course = course;
+            self.laps = Laps::new(now);
+        }
+    }
+
+    fn on_activation(
+        &mut self,
+        activation: Activation,
+        now: CoordinatedInstant,
+    ) -> Option<RaceEvent> {
+        let role = self.course.role(activation.addr)?;
+
+        if activation.continued {
+            // Only the first activation of finish and split gates is taken,
+            // while the start gate refines the start time of the race opened
+            // by the same interruption. On a loop course, a racer finishing
+            // doesn't move the start of the racer behind.
+            let id = self.opened_by_start.filter(|_| role.is_start())?;
+            let race = self.running.iter_mut().find(|x| x.id == id)?;
+            race.refine_start(activation.time);
+            activation.flag(race);
+            return None;
+        }
+
+        if role.is_start() {
+            self.opened_by_start = None;
+        }
+
+        let event = match role {
+            GateRole::Start => self.start(&activation, now),
+            GateRole::Split(_) => self.split(&activation),
+            GateRole::Finish => self.finish(&activation, now),
+            GateRole::StartFinish => {
+                // On a loop course, racers on course are closed before
+                // opening a new race. Passes can't be matched to racers, so
+                // only one racer can be on course at a time.
+                return self
+                    .finish(&activation, now)
+                    .or_else(|| self.start(&activation, now));
+            }
+            GateRole::Lap => {
+                return self
+                    .laps
+                    .pass(activation.time, activation.poor_sync, activation.recovered)
+            }
+        };
+
+        if event.is_none() && role != GateRole::Start {
+            if self.unmatched.len() == UNMATCHED_SIZE {
+                self.unmatched.pop_front();
+            }
+            self.unmatched.push_back(activation);
+        }
+
+        event
+    }
+
+    /// Split and finish activations delivered before the start of their race,
+    /// e.g. when the start gate lost the coordinator for a while
+    fn replay_unmatched(&mut self, now: CoordinatedInstant) -> Vec<RaceEvent> {
+        std::mem::take(&mut self.unmatched)
+            .into_iter()
+            .filter_map(|x| self.on_activation(x, now))
+            .collect()
+    }
+
+    fn start(&mut self, activation: &Activation, now: CoordinatedInstant) -> Option<RaceEvent> {
+        if self.address_conflict {
+            return None;
+        }
+
+        // Many starts can be buffered during a link loss of the start gate,
+        // all older than the arming after it. They are only required to
+        // follow the start of the previous race.
+        let after_previous = self
+            .last_start_time()
+            .map(|x| activation.time > x)
+            .unwrap_or(true);
+
+        let event = if activation.recovered && after_previous {
+            self.next.start_recovered(activation.time, now)?
+        } else {
+            self.next.start(activation.time, now)?
+        };
+
+        let next = Race::new(self.next.id + 1);
+        let mut race = std::mem::replace(&mut self.next, next);
+        race.racer = self.racers.take_next();
+        activation.flag(&mut race);
+        self.opened_by_start = Some(race.id);
+        self.running.push_back(race);
+        Some(event)
+    }
+
+    /// Start time of the last opened race
+    fn last_start_time(&self) -> Option<CoordinatedInstant> {
+        self.running
+            .iter()
+            .chain(self.history.races.iter())
+            .filter_map(|x| x.start_time)
+            .max()
+    }
+
+    fn split(&mut self, activation: &Activation) -> Option<RaceEvent> {
+        let Activation { addr, time, .. } = *activation;
+        let race = self
+            .running
+            .iter_mut()
+            .find(|x| x.accepts_split(addr, time))?;
+        let event = race.split(addr, time)?;
+        activation.flag(race);
+        Some(event)
+    }
+
+    fn finish(&mut self, activation: &Activation, now: CoordinatedInstant) -> Option<RaceEvent> {
+        let time = activation.time;
+        let race = self.running.iter_mut().find(|x| x.accepts_finish(time))?;
+        let event = race.finish(time, now)?;
+        activation.flag(race);
+        Some(event)
+    }
+
+    fn close_races(&mut self) {
+        while let Some(index) = self.running.iter().position(|x| x.state().is_over()) {
+            if let Some(race) = self.running.remove(index) {
+                self.history.push(race);
+            }
+        }
+    }
+}
+
+/// Start gate is ready to detect a racer
+fn is_ready(gate: &Gate, now: CoordinatedInstant) -> bool {
+    gate.is_alive(now) && !gate.is_active()
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Activation {
+    addr: NodeAddress,
+    time: CoordinatedInstant,
+    /// The gate was already active, so this is the same beam interruption
+    continued: bool,
+    /// The gate clock was poorly synchronized
+    poor_sync: bool,
+    /// Delivered after the gate lost the coordinator
+    recovered: bool,
+}
+
+impl Activation {
+    fn new(activation: &GateActivation, gates: &Gates) -> Self {
+        Self {
+            addr: activation.addr,
+            time: activation.time,
+            continued: activation.continued,
+            poor_sync: gates
+                .get(activation.addr)
+                .map(|x| x.has_poor_sync())
+                .unwrap_or(true),
+            recovered: activation.recovered,
+        }
+    }
+
+    /// Flag the race with the quality of this activation
+    fn flag(&self, race: &mut Race) {
+        race.poor_sync |= self.poor_sync;
+        race.recovered |= self.recovered;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use insta::assert_debug_snapshot;
+
+    use crate::app::course::CourseGate;
+    use crate::app::race::RaceState;
+    use crate::svc::race_node::SyncQuality;
+
+    use super::*;
+
+    fn ms(time_ms: i32) -> CoordinatedInstant {
+        CoordinatedInstant::from_millis(time_ms)
+    }
+
+    fn good_sync() -> Option<SyncQuality> {
+        Some(SyncQuality {
+            offset_us: 0,
+            error: Duration::from_micros(200),
+            loss_percent: 0,
+        })
+    }
+
+    fn make_active_gate(time_ms: i32) -> Gate {
+        let t = Some(ms(time_ms));
+        Gate {
+            active: true,
+            last_activation_time: t,
+            last_beacon_time: t,
+            sync: good_sync(),
+            ..Default::default()
+        }
+    }
+
+    fn make_inactive_gate(time_ms: i32) -> Gate {
+        let t = Some(ms(time_ms));
+        Gate {
+            active: false,
+            last_activation_time: t,
+            last_beacon_time: t,
+            sync: good_sync(),
+            ..Default::default()
+        }
+    }
+
+    fn make_ready_gate(time_ms: i32) -> Gate {
+        Gate {
+            active: false,
+            last_activation_time: None,
+            last_beacon_time: Some(ms(time_ms)),
+            sync: good_sync(),
+            ..Default::default()
+        }
+    }
+
+    fn activation(addr: u8, time_ms: i32, continued: bool) -> GateActivation {
+        GateActivation {
+            addr: NodeAddress::from(addr),
+            seq: 0,
+            time: ms(time_ms),
+            continued,
+            recovered: false,
+        }
+    }
+
+    fn make_never_activated_gate() -> Gate {
+        Gate::default()
+    }
+
+    fn make_armed_timing() -> Timing {
+        let mut timing = Timing::default();
+        let events = timing.set_gates(
+            &Gates::new([
+                make_ready_gate(5_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[],
+            ms(5_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Armed]);
+        timing
+    }
+
+    #[test]
+    fn test_timing_is_not_armed_while_start_gate_is_active() {
+        let mut timing = Timing::default();
+        let events = timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        assert!(events.is_empty());
+        assert_eq!(timing.next.state(), RaceState::Idle);
+        assert!(timing.running.is_empty());
+    }
+
+    #[test]
+    fn test_timing_is_not_armed_while_addresses_conflict() {
+        let gates = Gates::new([
+            make_ready_gate(5_000),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+        ]);
+
+        let mut timing = Timing {
+            address_conflict: true,
+            ..Default::default()
+        };
+        let events = timing.set_gates(&gates, &[], ms(5_000));
+        assert!(events.is_empty());
+        assert_eq!(timing.next.state(), RaceState::Idle);
+
+        // Resolved
+        timing.address_conflict = false;
+        let events = timing.set_gates(&gates, &[], ms(5_100));
+        assert_eq!(events, vec![RaceEvent::Armed]);
+    }
+
+    #[test]
+    fn test_timing_is_disarmed_when_addresses_conflict() {
+        let mut timing = make_armed_timing();
+        let mut gates = [
+            make_active_gate(10_000),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+        ];
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        gates[0] = make_inactive_gate(10_000);
+        let events = timing.set_gates(&Gates::new(gates.clone()), &[], ms(10_500));
+        assert_eq!(events, vec![RaceEvent::Armed]);
+
+        // A node is switched on with the address of another one
+        timing.address_conflict = true;
+        let events = timing.set_gates(&Gates::new(gates.clone()), &[], ms(11_000));
+        assert_eq!(events, vec![RaceEvent::Aborted]);
+        assert_eq!(timing.next.state(), RaceState::Idle);
+        assert!(timing.running.is_empty());
+        assert_eq!(
+            timing.history.last().map(|x| x.state()),
+            Some(RaceState::Aborted)
+        );
+
+        // Recovered starts don't open races either
+        let start = GateActivation {
+            recovered: true,
+            ..activation(1, 12_000, false)
+        };
+        let events = timing.set_gates(&Gates::new(gates.clone()), &[start], ms(12_000));
+        assert!(events.is_empty());
+        assert!(timing.running.is_empty());
+    }
+
+    #[test]
+    fn test_timing_with_start_gate_active() {
+        let mut timing = make_armed_timing();
+        let events = timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Started]);
+        assert_debug_snapshot!(timing.current());
+    }
+
+    #[test]
+    fn test_timing_start_is_refined_while_start_gate_is_active() {
+        let mut timing = make_armed_timing();
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        let events = timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_100),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_100, true)],
+            ms(10_100),
+        );
+        assert!(events.is_empty());
+        assert_eq!(timing.running.len(), 1);
+        assert_eq!(timing.current().start_time, Some(ms(10_100)));
+    }
+
+    #[test]
+    fn test_timing_with_start_and_finish_gates_active() {
+        let mut timing = make_armed_timing();
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        let events = timing.set_gates(
+            &Gates::new([
+                make_inactive_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_active_gate(20_000),
+            ]),
+            &[activation(4, 20_000, false)],
+            ms(20_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Finished]);
+        assert_debug_snapshot!(timing.current());
+    }
+
+    #[test]
+    fn test_timing_flags_finish_gate_with_poor_sync() {
+        let mut timing = make_armed_timing();
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        assert!(!timing.current().poor_sync);
+
+        let finish_gate = Gate {
+            sync: Some(SyncQuality {
+                offset_us: 0,
+                error: Duration::from_millis(4),
+                loss_percent: 90,
+            }),
+            ..make_active_gate(20_000)
+        };
+
+        let events = timing.set_gates(
+            &Gates::new([
+                make_inactive_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                finish_gate,
+            ]),
+            &[activation(4, 20_000, false)],
+            ms(20_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Finished]);
+        assert!(timing.current().poor_sync);
+    }
+
+    #[test]
+    fn test_timing_with_two_finish_activations() {
+        let mut timing = make_armed_timing();
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        timing.set_gates(
+            &Gates::new([
+                make_inactive_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_active_gate(20_000),
+            ]),
+            &[activation(4, 20_000, false)],
+            ms(20_000),
+        );
+        let events = timing.set_gates(
+            &Gates::new([
+                make_inactive_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_active_gate(30_000),
+            ]),
+            &[activation(4, 30_000, false)],
+            ms(30_000),
+        );
+
+        // Even if the finish gate has been activated again, the duration is
+        // calculated start gate activation and first finish gate activation.
+        assert_eq!(timing.current().duration(), Some(Duration::from_secs(10)));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_timing_with_split_gates() {
+        let mut timing = make_armed_timing();
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        let events = timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_active_gate(13_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(2, 13_000, false)],
+            ms(13_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Split(NodeAddress::from(2))]);
+        timing.set_gates(
+            &Gates::new([
+                make_inactive_gate(10_000),
+                make_active_gate(13_500),
+                make_active_gate(17_000),
+                make_never_activated_gate(),
+            ]),
+            &[activation(2, 13_500, true), activation(3, 17_000, false)],
+            ms(17_000),
+        );
+        timing.set_gates(
+            &Gates::new([
+                make_inactive_gate(10_000),
+                make_inactive_gate(13_500),
+                make_inactive_gate(17_000),
+                make_active_gate(20_000),
+            ]),
+            &[activation(4, 20_000, false)],
+            ms(20_000),
+        );
+
+        let race = timing.current();
+        assert_eq!(race.state(), RaceState::Finished);
+        assert_eq!(race.splits()[0].elapsed, Duration::from_secs(3));
+        assert_eq!(race.splits()[1].segment, Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_timing_with_two_racers_on_course() {
+        let mut timing = make_armed_timing();
+        let mut gates = [
+            make_active_gate(10_000),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+        ];
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+
+        // Start gate is free again, so the next race is armed
+        gates[0] = make_inactive_gate(10_000);
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(10_500));
+
+        // Second racer starts before the first one finishes
+        gates[0] = make_active_gate(12_000);
+        let events = timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(1, 12_000, false)],
+            ms(12_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Started]);
+        assert_eq!(timing.running.len(), 2);
+
+        gates[0] = make_inactive_gate(12_000);
+        gates[3] = make_active_gate(20_000);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(4, 20_000, false)],
+            ms(20_000),
+        );
+
+        // First finish activation closes the first race
+        let race = timing.history.last().unwrap();
+        assert_eq!(race.id, 1);
+        assert_eq!(race.duration(), Some(Duration::from_secs(10)));
+
+        gates[3] = make_inactive_gate(20_000);
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(20_500));
+
+        gates[3] = make_active_gate(23_000);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(4, 23_000, false)],
+            ms(23_000),
+        );
+
+        assert!(timing.running.is_empty());
+        assert_debug_snapshot!(timing.history);
+    }
+
+    #[test]
+    fn test_timing_with_order_changed_by_operator() {
+        let mut timing = make_armed_timing();
+        let mut gates = [
+            make_active_gate(10_000),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+        ];
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+        gates[0] = make_inactive_gate(10_000);
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(10_500));
+        gates[0] = make_active_gate(12_000);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(1, 12_000, false)],
+            ms(12_000),
+        );
+
+        // Second racer overtakes the first one
+        timing.apply(OperatorCommand::Promote { race: 2 }, ms(15_000));
+
+        gates[0] = make_inactive_gate(12_000);
+        gates[3] = make_active_gate(20_000);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(4, 20_000, false)],
+            ms(20_000),
+        );
+
+        let race = timing.current();
+        assert_eq!(race.id, 1);
+        assert_eq!(race.state(), RaceState::Running);
+
+        let race = timing.history.last().unwrap();
+        assert_eq!(race.id, 2);
+        assert_eq!(race.duration(), Some(Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn test_timing_binds_races_to_start_list() {
+        let mut timing = make_armed_timing();
+        let racers = [(7, "Anna"), (3, "Bruno")]
+            .into_iter()
+            .map(|(bib, name)| Racer {
+                bib,
+                name: name.to_owned(),
+                category: "U14".to_owned(),
+            })
+            .collect();
+        timing.apply(OperatorCommand::SetRacers { racers }, ms(6_000));
+        timing.apply(
+            OperatorCommand::SetStartList { bibs: vec![3, 7] },
+            ms(6_000),
+        );
+
+        assert_eq!(timing.next_racer().map(|x| x.bib), Some(3));
+
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+
+        assert_eq!(timing.current_racer().map(|x| x.bib), Some(3));
+        assert_eq!(timing.next_racer().map(|x| x.bib), Some(7));
+    }
+
+    #[test]
+    fn test_timing_with_loop_course() {
+        let mut timing = Timing::default();
+        let course = Course {
+            gates: vec![CourseGate {
+                addr: NodeAddress::from(3),
+                role: GateRole::StartFinish,
+            }],
+        };
+        timing.apply(OperatorCommand::SetCourse { course }, ms(1_000));
+
+        let mut gates = [
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_ready_gate(5_000),
+            make_never_activated_gate(),
+        ];
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(5_000));
+
+        gates[2] = make_active_gate(10_000);
+        let events = timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 10_000, false)],
+            ms(10_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Started]);
+
+        gates[2] = make_inactive_gate(10_000);
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(10_500));
+
+        gates[2] = make_active_gate(40_000);
+        let events = timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 40_000, false)],
+            ms(40_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Finished]);
+        assert_eq!(timing.current().duration(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_timing_loop_course_takes_one_racer_at_a_time() {
+        let mut timing = Timing::default();
+        let course = Course {
+            gates: vec![CourseGate {
+                addr: NodeAddress::from(3),
+                role: GateRole::StartFinish,
+            }],
+        };
+        timing.apply(OperatorCommand::SetCourse { course }, ms(1_000));
+
+        let mut gates = [
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_ready_gate(5_000),
+            make_never_activated_gate(),
+        ];
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(5_000));
+
+        gates[2] = make_active_gate(10_000);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 10_000, false)],
+            ms(10_000),
+        );
+
+        gates[2] = make_inactive_gate(10_000);
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(10_500));
+
+        // The start of the racer behind is taken as the finish of the racer
+        // on course
+        gates[2] = make_active_gate(20_000);
+        let events = timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 20_000, false)],
+            ms(20_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Finished]);
+        assert_eq!(timing.current().duration(), Some(Duration::from_secs(10)));
+        assert!(timing.running.is_empty());
+    }
+
+    #[test]
+    fn test_timing_loop_course_finish_does_not_refine_other_start() {
+        let mut timing = Timing::default();
+        let course = Course {
+            gates: vec![CourseGate {
+                addr: NodeAddress::from(3),
+                role: GateRole::StartFinish,
+            }],
+        };
+        timing.apply(OperatorCommand::SetCourse { course }, ms(1_000));
+
+        let mut gates = [
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_ready_gate(5_000),
+            make_never_activated_gate(),
+        ];
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(5_000));
+
+        gates[2] = make_active_gate(10_000);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 10_000, false)],
+            ms(10_000),
+        );
+
+        gates[2] = make_inactive_gate(10_000);
+        timing.set_gates(&Gates::new(gates.clone()), &[], ms(10_500));
+
+        // The loop gate closes a race on each pass, so the racer behind is
+        // put on course directly
+        let racer_2 = Activation::new(&activation(3, 20_000, false), &Gates::new(gates.clone()));
+        assert_eq!(timing.start(&racer_2, ms(20_000)), Some(RaceEvent::Started));
+        assert_eq!(timing.running.len(), 2);
+
+        // The first racer finishes and stays in the beam for a while
+        gates[2] = make_active_gate(40_000);
+        let events = timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 40_000, false)],
+            ms(40_000),
+        );
+        assert_eq!(events, vec![RaceEvent::Finished]);
+
+        gates[2] = make_active_gate(40_300);
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(3, 40_300, true)],
+            ms(40_300),
+        );
+
+        assert_eq!(timing.history.last().map(|x| x.id), Some(1));
+        assert_eq!(timing.running.len(), 1);
+        assert_eq!(timing.running[0].id, 2);
+        assert_eq!(timing.running[0].start_time, Some(ms(20_000)));
+    }
+
+    #[test]
+    fn test_timing_with_lap_course() {
+        let mut timing = Timing::default();
+        let course = Course {
+            gates: vec![CourseGate {
+                addr: NodeAddress::from(1),
+                role: GateRole::Lap,
+            }],
+        };
+
+        // Activation before the lap course is set is ignored
+        let mut gates = [make_inactive_gate(500)];
+        timing.set_gates(
+            &Gates::new(gates.clone()),
+            &[activation(1, 500, false)],
+            ms(500),
+        );
+        timing.apply(OperatorCommand::SetCourse { course }, ms(1_000));
+        let events = timing.set_gates(&Gates::new(gates.clone()), &[], ms(1_000));
+        assert_eq!(events, vec![]);
+
+        for (i, t) in [10_000, 40_000, 69_000].into_iter().enumerate() {
+            gates[0] = make_active_gate(t);
+            let events = timing.set_gates(
+                &Gates::new(gates.clone()),
+                &[activation(1, t, false)],
+                ms(t),
+            );
+            let expected = if i == 0 {
+                RaceEvent::Started
+            } else {
+                RaceEvent::Lap(i as u32)
+            };
+            assert_eq!(events, vec![expected]);
+
+            gates[0] = make_inactive_gate(t);
+            timing.set_gates(&Gates::new(gates.clone()), &[], ms(t + 500));
+        }
+
+        assert_eq!(timing.laps.count, 2);
+        assert_eq!(
+            timing.laps.last().and_then(|x| x.delta_us),
+            Some(-1_000_000)
+        );
+        assert!(timing.running.is_empty());
+    }
+
+    #[test]
+    fn test_timing_with_start_delivered_after_finish() {
+        let mut timing = make_armed_timing();
+        let gates = Gates::new([
+            make_ready_gate(30_000),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_inactive_gate(20_000),
+        ]);
+
+        // The start gate lost the coordinator before the racer started
+        let events = timing.set_gates(&gates, &[activation(4, 20_000, false)], ms(20_000));
+        assert!(events.is_empty());
+
+        let start = GateActivation {
+            recovered: true,
+            ..activation(1, 10_000, false)
+        };
+        let events = timing.set_gates(&gates, &[start], ms(30_000));
+        assert_eq!(events, vec![RaceEvent::Started, RaceEvent::Finished]);
+
+        let race = timing.history.last().unwrap();
+        assert_eq!(race.duration(), Some(Duration::from_secs(10)));
+        assert!(race.recovered);
+    }
+
+    #[test]
+    fn test_timing_with_starts_buffered_in_one_outage() {
+        let mut timing = make_armed_timing();
+        let recovered = |time_ms, continued| GateActivation {
+            recovered: true,
+            ..activation(1, time_ms, continued)
+        };
+
+        // Both racers started while the start gate lost the coordinator. The
+        // buffered activations are delivered over many updates, while the
+        // next race is armed again.
+        for (i, t) in [(10_000, 10_300), (15_000, 15_200)].into_iter().enumerate() {
+            let now = ms(30_000 + i as i32 * 20);
+            let gates = Gates::new([
+                make_ready_gate(30_000 + i as i32 * 20),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]);
+            let events =
+                timing.set_gates(&gates, &[recovered(t.0, false), recovered(t.1, true)], now);
+            assert!(events.contains(&RaceEvent::Started));
+        }
+
+        let starts: Vec<_> = timing
+            .running
+            .iter()
+            .map(|x| (x.id, x.start_time))
+            .collect();
+        assert_eq!(starts, vec![(1, Some(ms(10_300))), (2, Some(ms(15_200)))]);
+        assert!(timing.running.iter().all(|x| x.recovered));
+
+        // A start older than the previous race is still rejected
+        let gates = Gates::new([
+            make_ready_gate(30_100),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+            make_never_activated_gate(),
+        ]);
+        let events = timing.set_gates(&gates, &[recovered(12_000, false)], ms(30_100));
+        assert_eq!(events, vec![RaceEvent::Armed]);
+        assert_eq!(timing.running.len(), 2);
+    }
+
+    #[test]
+    fn test_timing_with_race_aborted_by_operator() {
+        let mut timing = make_armed_timing();
+        timing.set_gates(
+            &Gates::new([
+                make_active_gate(10_000),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+                make_never_activated_gate(),
+            ]),
+            &[activation(1, 10_000, false)],
+            ms(10_000),
+        );
+
+        let events = timing.apply(OperatorCommand::Abort { race: 1 }, ms(11_000));
+        assert_eq!(events, vec![RaceEvent::Aborted]);
+        assert!(timing.running.is_empty());
+        assert_eq!(timing.current().state(), RaceState::Aborted);
+    }
+}
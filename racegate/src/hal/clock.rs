@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Source of monotonic time. Everything reading the time goes through it, so
+/// tests and simulations can control the time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Time of the operating system
+#[derive(Default, Copy, Clone, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Time moving only when explicitly advanced
+#[derive(Debug)]
+pub struct VirtualClock {
+    start: Instant,
+    elapsed_ns: AtomicU64,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+impl VirtualClock {
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::AcqRel);
+    }
+
+    /// Time elapsed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns.load(Ordering::Acquire))
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock_moves_only_when_advanced() {
+        let clock = VirtualClock::default();
+        let t0 = clock.now();
+        assert_eq!(clock.now(), t0);
+
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(clock.now() - t0, Duration::from_millis(20));
+    }
+}
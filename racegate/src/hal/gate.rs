@@ -1,9 +1,22 @@
+use std::time::Instant;
+
 pub trait Gate {
     fn is_active(&self) -> bool {
         self.state() == GateState::Active
     }
 
     fn state(&self) -> GateState;
+
+    /// Take the oldest state change not taken yet. Changes are timestamped
+    /// when they happen, so they are not affected by the update period and
+    /// short beam interruptions are not lost.
+    fn take_event(&self) -> Option<GateEvent>;
+
+    /// State changes lost since the start, because they were not taken in
+    /// time
+    fn dropped_events(&self) -> u32 {
+        0
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
@@ -12,3 +25,11 @@ pub enum GateState {
     Inactive,
     Active,
 }
+
+/// Gate state change, on both edges
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct GateEvent {
+    /// State after the change
+    pub state: GateState,
+    pub time: Instant,
+}
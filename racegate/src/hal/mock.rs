@@ -0,0 +1,319 @@
+//! Platform with scripted peripherals and virtual time, to run the
+//! application deterministically in tests.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::app::{Gates, OperatorCommand, Roster, SystemState};
+use crate::hal::button::{Button, ButtonState};
+use crate::hal::clock::{Clock, VirtualClock};
+use crate::hal::dip_switch::DipSwitch;
+use crate::hal::gate::{Gate, GateEvent, GateState};
+use crate::hal::rgb_led::{RgbLed, RgbLedColor};
+use crate::hal::wifi::{Wifi, WifiConfig};
+use crate::hal::Platform;
+use crate::svc::race_node::{
+    GateActivation, GateCommand, GateDiagnostics, HardwareId, NodeAddress, NodeStats, RaceNode,
+    RaceNodeMessage, SystemId,
+};
+use crate::svc::{ClockSync, CoordinatedInstant, HttpServer};
+
+#[derive(Default)]
+pub struct MockPlatform<N = MockRaceNode> {
+    pub clock: Rc<VirtualClock>,
+    pub button: MockButton,
+    pub gate: MockGate,
+    pub http_server: MockHttpServer,
+    pub race_node: N,
+    pub rgb_led: MockRgbLed,
+    pub wifi: MockWifi,
+    pub dip_switch: MockDipSwitch,
+    pub hardware_id: HardwareId,
+    pub supply_voltage_mv: Cell<Option<u16>>,
+    pub reboots: Cell<u32>,
+}
+
+impl MockPlatform {
+    pub fn new(addr: NodeAddress) -> Self {
+        Self {
+            dip_switch: MockDipSwitch { addr },
+            ..Default::default()
+        }
+    }
+}
+
+impl<N: RaceNode> Platform for MockPlatform<N> {
+    fn button(&self) -> &(dyn Button + '_) {
+        &self.button
+    }
+
+    fn clock(&self) -> &(dyn Clock + '_) {
+        self.clock.as_ref()
+    }
+
+    fn gate(&self) -> &(dyn Gate + '_) {
+        &self.gate
+    }
+
+    fn http_server(&self) -> &(dyn HttpServer + '_) {
+        &self.http_server
+    }
+
+    fn race_node(&self) -> &(dyn RaceNode + '_) {
+        &self.race_node
+    }
+
+    fn rgb_led(&self) -> &(dyn RgbLed + '_) {
+        &self.rgb_led
+    }
+
+    fn wifi(&self) -> &(dyn Wifi + '_) {
+        &self.wifi
+    }
+
+    fn dip_switch(&self) -> &(dyn DipSwitch + '_) {
+        &self.dip_switch
+    }
+
+    fn hardware_id(&self) -> HardwareId {
+        self.hardware_id
+    }
+
+    fn supply_voltage_mv(&self) -> Option<u16> {
+        self.supply_voltage_mv.get()
+    }
+
+    fn reboot(&self) {
+        self.reboots.set(self.reboots.get() + 1);
+    }
+}
+
+#[derive(Default)]
+pub struct MockButton {
+    pub state: Cell<ButtonState>,
+}
+
+impl Button for MockButton {
+    fn state(&self) -> ButtonState {
+        self.state.get()
+    }
+}
+
+#[derive(Default)]
+pub struct MockGate {
+    state: Cell<GateState>,
+    events: RefCell<VecDeque<GateEvent>>,
+    pub dropped_events: Cell<u32>,
+}
+
+impl MockGate {
+    /// Change the state, like the beam did at `event.time`
+    pub fn push_event(&self, event: GateEvent) {
+        self.state.set(event.state);
+        self.events.borrow_mut().push_back(event);
+    }
+}
+
+impl Gate for MockGate {
+    fn state(&self) -> GateState {
+        self.state.get()
+    }
+
+    fn take_event(&self) -> Option<GateEvent> {
+        self.events.borrow_mut().pop_front()
+    }
+
+    fn dropped_events(&self) -> u32 {
+        self.dropped_events.get()
+    }
+}
+
+#[derive(Default)]
+pub struct MockHttpServer {
+    pub commands: RefCell<VecDeque<OperatorCommand>>,
+    pub system_state: RefCell<Option<SystemState>>,
+}
+
+impl HttpServer for MockHttpServer {
+    fn set_system_state(&self, status: &SystemState) {
+        self.system_state.replace(Some(status.clone()));
+    }
+
+    fn take_operator_command(&self) -> Option<OperatorCommand> {
+        self.commands.borrow_mut().pop_front()
+    }
+}
+
+/// Race node without a network: what the application publishes is recorded,
+/// what it reads is set by the test
+#[derive(Default)]
+pub struct MockRaceNode {
+    pub coordinator_time: Cell<Option<CoordinatedInstant>>,
+    pub clock_sync: Cell<Option<ClockSync>>,
+    pub sync_loss_percent: Cell<u8>,
+    pub time_since_coordinator_beacon: Cell<Duration>,
+    pub gates: RefCell<Gates>,
+    pub roster: RefCell<Roster>,
+    pub published: RefCell<Vec<RaceNodeMessage>>,
+    pub foreign_systems: RefCell<Vec<SystemId>>,
+    /// Activations sent by a gate, numbered in order
+    pub sent_activations: RefCell<Vec<GateActivation>>,
+    /// Activations to be taken by the coordinator
+    pub activations: RefCell<Vec<GateActivation>>,
+    /// Commands sent by the coordinator
+    pub sent_commands: RefCell<Vec<(NodeAddress, GateCommand)>>,
+    /// Commands to be taken by a gate
+    pub commands: RefCell<Vec<GateCommand>>,
+    pub stats: Cell<NodeStats>,
+    /// Diagnostics sent by a gate
+    pub sent_diagnostics: RefCell<Vec<GateDiagnostics>>,
+}
+
+impl RaceNode for MockRaceNode {
+    fn set_coordinator_time(&self, t: CoordinatedInstant) {
+        self.coordinator_time.set(Some(t));
+    }
+
+    fn coordinator_time(&self) -> Option<CoordinatedInstant> {
+        self.coordinator_time.get()
+    }
+
+    fn clock_sync(&self) -> Option<ClockSync> {
+        self.clock_sync.get()
+    }
+
+    fn sync_loss_percent(&self) -> u8 {
+        self.sync_loss_percent.get()
+    }
+
+    fn publish(&self, msg: RaceNodeMessage) -> anyhow::Result<()> {
+        self.published.borrow_mut().push(msg);
+        Ok(())
+    }
+
+    fn gates(&self) -> Gates {
+        self.gates.borrow().clone()
+    }
+
+    fn roster(&self) -> Roster {
+        self.roster.borrow().clone()
+    }
+
+    fn time_since_coordinator_beacon(&self) -> Duration {
+        self.time_since_coordinator_beacon.get()
+    }
+
+    fn foreign_systems(&self) -> Vec<SystemId> {
+        self.foreign_systems.borrow().clone()
+    }
+
+    fn send_activation(
+        &self,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+        continued: bool,
+        recovered: bool,
+    ) {
+        let mut sent = self.sent_activations.borrow_mut();
+        let seq = sent.len() as u16;
+        sent.push(GateActivation {
+            addr,
+            seq,
+            time,
+            continued,
+            recovered,
+        });
+    }
+
+    fn pending_activations(&self) -> usize {
+        // Never acknowledged
+        self.sent_activations.borrow().len()
+    }
+
+    fn send_command(&self, addr: NodeAddress, command: GateCommand) {
+        self.sent_commands.borrow_mut().push((addr, command));
+    }
+
+    fn take_commands(&self) -> Vec<GateCommand> {
+        self.commands.take()
+    }
+
+    fn take_activations(&self) -> Vec<GateActivation> {
+        self.activations.take()
+    }
+
+    fn stats(&self) -> NodeStats {
+        self.stats.get()
+    }
+
+    fn send_diagnostics(&self, diagnostics: GateDiagnostics) {
+        self.sent_diagnostics.borrow_mut().push(diagnostics);
+    }
+}
+
+#[derive(Default)]
+pub struct MockRgbLed {
+    pub color: Cell<u32>,
+}
+
+impl RgbLed for MockRgbLed {
+    fn set_color(&self, color: RgbLedColor) {
+        let RgbLedColor { r, g, b } = color;
+        self.color
+            .set(((r as u32) << 16) | ((g as u32) << 8) | b as u32);
+    }
+}
+
+pub struct MockWifi {
+    pub up: Cell<bool>,
+    pub rssi: Cell<Option<i8>>,
+    pub reconnect_count: Cell<u32>,
+}
+
+impl Default for MockWifi {
+    fn default() -> Self {
+        Self {
+            up: Cell::new(true),
+            rssi: Cell::default(),
+            reconnect_count: Cell::default(),
+        }
+    }
+}
+
+impl Wifi for MockWifi {
+    fn setup(&self, _config: &WifiConfig) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn is_up(&self) -> bool {
+        self.up.get()
+    }
+
+    fn reconnect(&self) {
+        self.reconnect_count.set(self.reconnect_count.get() + 1);
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        self.rssi.get()
+    }
+}
+
+pub struct MockDipSwitch {
+    pub addr: NodeAddress,
+}
+
+impl Default for MockDipSwitch {
+    fn default() -> Self {
+        Self {
+            addr: NodeAddress::coordinator(),
+        }
+    }
+}
+
+impl DipSwitch for MockDipSwitch {
+    fn address(&self) -> NodeAddress {
+        self.addr
+    }
+}
@@ -1,22 +1,37 @@
 use crate::hal::button::Button;
+use crate::hal::clock::Clock;
 use crate::hal::dip_switch::DipSwitch;
 use crate::hal::gate::Gate;
 use crate::hal::rgb_led::RgbLed;
 use crate::hal::wifi::Wifi;
-use crate::svc::{race_node::RaceNode, HttpServer};
+use crate::svc::race_node::{HardwareId, RaceNode};
+use crate::svc::HttpServer;
 
 pub mod button;
+pub mod clock;
 pub mod dip_switch;
 pub mod gate;
+#[cfg(any(test, feature = "sim"))]
+pub mod mock;
 pub mod rgb_led;
 pub mod wifi;
 
 pub trait Platform {
     fn button(&self) -> &(dyn Button + '_);
+    fn clock(&self) -> &(dyn Clock + '_);
     fn gate(&self) -> &(dyn Gate + '_);
     fn http_server(&self) -> &(dyn HttpServer + '_);
     fn race_node(&self) -> &(dyn RaceNode + '_);
     fn rgb_led(&self) -> &(dyn RgbLed + '_);
     fn wifi(&self) -> &(dyn Wifi + '_);
     fn dip_switch(&self) -> &(dyn DipSwitch + '_);
+
+    /// Unique identifier of the board
+    fn hardware_id(&self) -> HardwareId;
+
+    /// Supply voltage, in millivolts, if the board can measure it
+    fn supply_voltage_mv(&self) -> Option<u16>;
+
+    /// Restart the node. On a device, this doesn't return.
+    fn reboot(&self);
 }
@@ -4,13 +4,21 @@ pub trait Wifi {
     fn is_up(&self) -> bool;
 
     fn reconnect(&self);
+
+    /// Signal strength of the access point, in dBm, when connected to one
+    fn rssi(&self) -> Option<i8>;
 }
 
+const DEFAULT_NETWORK_KEY: &str = "racegate";
+
 #[derive(Eq, PartialEq)]
 pub struct WifiConfig<'a> {
     pub ap: bool,
     pub ssid: &'a str,
     pub password: &'a str,
+    /// Passphrase of the key authenticating race node messages, shared by all
+    /// the nodes of the system
+    pub network_key: &'a str,
 }
 
 pub enum WifiConfigError {
@@ -19,6 +27,11 @@ pub enum WifiConfigError {
 }
 
 impl WifiConfig<'_> {
+    /// The default key is public, so it authenticates nothing
+    pub fn has_default_network_key(&self) -> bool {
+        self.network_key == DEFAULT_NETWORK_KEY
+    }
+
     fn try_from_str(s: &'static str) -> Result<Self, WifiConfigError> {
         let mut iter = s.split_terminator(':');
         let ap: bool = iter
@@ -28,7 +41,14 @@ impl WifiConfig<'_> {
             .or(Err(WifiConfigError::ParseError))?;
         let ssid: &str = iter.next().ok_or(WifiConfigError::ParseError)?;
         let password: &str = iter.next().ok_or(WifiConfigError::ParseError)?;
-        Ok(WifiConfig { ap, ssid, password })
+        // Optional, for configurations written before it was introduced
+        let network_key: &str = iter.next().unwrap_or(DEFAULT_NETWORK_KEY);
+        Ok(WifiConfig {
+            ap,
+            ssid,
+            password,
+            network_key,
+        })
     }
 
     pub fn from_env_var() -> Result<Self, WifiConfigError> {
@@ -46,6 +66,7 @@ impl Default for WifiConfig<'_> {
             ap: true,
             ssid: "racegate",
             password: "racegate",
+            network_key: DEFAULT_NETWORK_KEY,
         }
     }
 }
@@ -1,5 +1,7 @@
 pub mod app;
 pub mod hal;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
 pub mod svc;
 
 pub use svc::CoordinatedInstant;
@@ -0,0 +1,367 @@
+//! Deterministic simulation of a whole system: nodes run the application on
+//! mock platforms, sharing virtual time and a simulated network.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::app::{App, SystemState};
+use crate::hal::clock::{Clock, VirtualClock};
+use crate::hal::gate::{GateEvent, GateState};
+use crate::hal::mock::{MockDipSwitch, MockPlatform};
+use crate::sim::network::SimNetwork;
+use crate::svc::race_node::{HardwareId, NodeAddress};
+
+pub use crate::sim::network::NetworkConfig;
+pub use crate::sim::race_node::SimRaceNode;
+
+mod network;
+mod race_node;
+
+/// Simulation step. Messages are timestamped at their arrival time anyway.
+const STEP: Duration = Duration::from_millis(1);
+
+/// Period of the application loop, like on the target
+const UPDATE_PERIOD: Duration = Duration::from_millis(20);
+
+/// Platforms of the simulated nodes, connected by the same network
+pub struct SimNodes {
+    network: Rc<RefCell<SimNetwork>>,
+    platforms: Vec<MockPlatform<SimRaceNode>>,
+}
+
+impl SimNodes {
+    pub fn new(addrs: &[NodeAddress], config: NetworkConfig) -> Self {
+        let network = Rc::new(RefCell::new(SimNetwork::new(config, addrs.len())));
+
+        let platforms = addrs
+            .iter()
+            .enumerate()
+            .map(|(index, &addr)| {
+                let clock = Rc::new(VirtualClock::default());
+                let race_node = SimRaceNode::new(index, clock.clone(), network.clone());
+
+                MockPlatform {
+                    clock,
+                    race_node,
+                    dip_switch: MockDipSwitch { addr },
+                    button: Default::default(),
+                    gate: Default::default(),
+                    http_server: Default::default(),
+                    rgb_led: Default::default(),
+                    wifi: Default::default(),
+                    hardware_id: HardwareId([0, 0, 0, 0, 0, index as u8]),
+                    supply_voltage_mv: Default::default(),
+                    reboots: Default::default(),
+                }
+            })
+            .collect();
+
+        Self { network, platforms }
+    }
+
+    pub fn platform(&self, addr: NodeAddress) -> Option<&MockPlatform<SimRaceNode>> {
+        self.platforms.iter().find(|x| x.dip_switch.addr == addr)
+    }
+}
+
+struct BeamChange {
+    at: Duration,
+    node: usize,
+    state: GateState,
+}
+
+/// Runs the application of every node, step by step
+pub struct Simulation<'a> {
+    nodes: &'a SimNodes,
+    apps: Vec<App<'a>>,
+    elapsed: Duration,
+    beam_changes: Vec<BeamChange>,
+}
+
+impl<'a> Simulation<'a> {
+    pub fn new(nodes: &'a SimNodes) -> Self {
+        let apps = nodes.platforms.iter().map(|x| App::new(x)).collect();
+
+        Self {
+            nodes,
+            apps,
+            elapsed: Duration::ZERO,
+            beam_changes: Vec::new(),
+        }
+    }
+
+    /// Time since the start of the simulation
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Interrupt the beam of the gate at `addr` at time `at`, for `length`
+    pub fn schedule_beam_break(&mut self, addr: NodeAddress, at: Duration, length: Duration) {
+        let Some(node) = self
+            .nodes
+            .platforms
+            .iter()
+            .position(|x| x.dip_switch.addr == addr)
+        else {
+            log::error!("No node with address {:?}", addr);
+            return;
+        };
+
+        self.beam_changes.push(BeamChange {
+            at,
+            node,
+            state: GateState::Active,
+        });
+        self.beam_changes.push(BeamChange {
+            at: at + length,
+            node,
+            state: GateState::Inactive,
+        });
+    }
+
+    pub fn run_until(&mut self, end: Duration) {
+        while self.elapsed < end {
+            self.step();
+        }
+    }
+
+    /// State published by the coordinator
+    pub fn system_state(&self) -> Option<SystemState> {
+        self.nodes
+            .platform(NodeAddress::coordinator())?
+            .http_server
+            .system_state
+            .borrow()
+            .clone()
+    }
+
+    fn step(&mut self) {
+        self.elapsed += STEP;
+
+        for platform in &self.nodes.platforms {
+            platform.clock.advance(STEP);
+        }
+
+        self.nodes.network.borrow_mut().set_now(self.elapsed);
+
+        self.apply_beam_changes();
+
+        let arrived = self.nodes.network.borrow_mut().take_arrived();
+        for (to, msg, arrival) in arrived {
+            let delay = self.elapsed - arrival;
+            self.nodes.platforms[to].race_node.receive(msg, delay);
+        }
+
+        let period = UPDATE_PERIOD.as_millis();
+        let elapsed = self.elapsed.as_millis();
+
+        for (index, app) in self.apps.iter_mut().enumerate() {
+            // Nodes don't run in phase
+            if (elapsed + index as u128 * 7) % period == 0 {
+                let platform = &self.nodes.platforms[index];
+                app.update();
+                platform.race_node.poll();
+            }
+        }
+    }
+
+    fn apply_beam_changes(&mut self) {
+        let elapsed = self.elapsed;
+        let (due, pending) = std::mem::take(&mut self.beam_changes)
+            .into_iter()
+            .partition(|x| x.at <= elapsed);
+        self.beam_changes = pending;
+
+        for change in due {
+            let platform = &self.nodes.platforms[change.node];
+            platform.gate.push_event(GateEvent {
+                state: change.state,
+                time: platform.clock.now() - (elapsed - change.at),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{GateRole, OperatorCommand, RaceState};
+    use crate::svc::race_node::GateCommand;
+
+    const START: NodeAddress = NodeAddress::from_gate_index(0);
+    const FINISH: NodeAddress = NodeAddress::from_gate_index(3);
+
+    fn make_nodes(config: NetworkConfig) -> SimNodes {
+        SimNodes::new(&[NodeAddress::coordinator(), START, FINISH], config)
+    }
+
+    fn run_race(simulation: &mut Simulation) {
+        simulation.schedule_beam_break(
+            START,
+            Duration::from_millis(3_000),
+            Duration::from_millis(100),
+        );
+        simulation.schedule_beam_break(
+            FINISH,
+            Duration::from_millis(13_250),
+            Duration::from_millis(100),
+        );
+        simulation.run_until(Duration::from_secs(15));
+    }
+
+    fn assert_duration_near(actual: Duration, expected: Duration, tolerance: Duration) {
+        let diff = if actual > expected {
+            actual - expected
+        } else {
+            expected - actual
+        };
+        assert!(diff <= tolerance, "{actual:?} != {expected:?}");
+    }
+
+    #[test]
+    fn test_race_on_ideal_network() {
+        let nodes = make_nodes(NetworkConfig {
+            jitter: Duration::ZERO,
+            ..Default::default()
+        });
+        let mut simulation = Simulation::new(&nodes);
+
+        run_race(&mut simulation);
+
+        let state = simulation.system_state().unwrap();
+        let race = state.timing.history.last().unwrap();
+        assert_eq!(race.state, RaceState::Finished);
+        assert!(!race.poor_sync);
+        assert_duration_near(
+            race.duration.unwrap(),
+            Duration::from_millis(10_150),
+            Duration::from_micros(200),
+        );
+
+        for addr in [START, FINISH] {
+            let gate = state.gates.get(addr).unwrap();
+            assert!(!gate.has_poor_sync(), "{:?}", gate.sync);
+        }
+
+        assert_eq!(state.roster.entries().len(), 3);
+        assert!(!state.roster.has_conflicts());
+    }
+
+    #[test]
+    fn test_race_on_lossy_network() {
+        let nodes = make_nodes(NetworkConfig {
+            latency: Duration::from_millis(3),
+            jitter: Duration::from_millis(2),
+            loss_percent: 20,
+            seed: 42,
+        });
+        let mut simulation = Simulation::new(&nodes);
+
+        run_race(&mut simulation);
+
+        let state = simulation.system_state().unwrap();
+        let race = state.timing.history.last().unwrap();
+        assert_eq!(race.state, RaceState::Finished);
+        assert_duration_near(
+            race.duration.unwrap(),
+            Duration::from_millis(10_150),
+            Duration::from_millis(2),
+        );
+
+        // Lost activations were sent again, and each one was taken once
+        for addr in [START, FINISH] {
+            let gate = state.gates.get(addr).unwrap();
+            assert_eq!(gate.activations.received, 2);
+            assert_eq!(gate.activations.missing, 0);
+        }
+    }
+
+    #[test]
+    fn test_command_is_delivered_on_lossy_network() {
+        let nodes = make_nodes(NetworkConfig {
+            loss_percent: 20,
+            ..Default::default()
+        });
+        let mut simulation = Simulation::new(&nodes);
+        simulation.run_until(Duration::from_secs(2));
+
+        let role = GateRole::Finish;
+        let command = OperatorCommand::Gate {
+            addr: START,
+            command: GateCommand::SetRole { role },
+        };
+        let coordinator = nodes.platform(NodeAddress::coordinator()).unwrap();
+        coordinator
+            .http_server
+            .commands
+            .borrow_mut()
+            .push_back(command);
+        simulation.run_until(Duration::from_secs(3));
+
+        // The gate reports the role it was given
+        let state = simulation.system_state().unwrap();
+        assert_eq!(state.gates.get(START).unwrap().role, Some(role));
+    }
+
+    #[test]
+    fn test_coordinator_collects_gate_diagnostics() {
+        let nodes = make_nodes(NetworkConfig::default());
+        let mut simulation = Simulation::new(&nodes);
+        simulation.run_until(Duration::from_secs(6));
+
+        let state = simulation.system_state().unwrap();
+
+        for addr in [START, FINISH] {
+            let diagnostics = state.gates.get(addr).unwrap().diagnostics.unwrap();
+            assert_eq!(diagnostics.addr, addr);
+            assert_eq!(diagnostics.uptime, Duration::from_secs(5));
+            assert_eq!(diagnostics.loop_overruns, 0);
+            assert_eq!(diagnostics.dropped_gate_events, 0);
+            assert!(diagnostics.stats.tx_count > 0);
+            assert!(diagnostics.stats.rx_count > 0);
+        }
+    }
+
+    #[test]
+    fn test_gates_with_the_same_address_block_arming() {
+        let nodes = SimNodes::new(
+            &[NodeAddress::coordinator(), START, FINISH, FINISH],
+            NetworkConfig::default(),
+        );
+        let mut simulation = Simulation::new(&nodes);
+        simulation.run_until(Duration::from_secs(3));
+
+        let state = simulation.system_state().unwrap();
+        assert_eq!(state.roster.entries().len(), 4);
+
+        let conflicts = state.roster.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].addr, FINISH);
+        assert_eq!(conflicts[0].hardware_ids.len(), 2);
+
+        assert_eq!(state.timing.next.state(), RaceState::Idle);
+    }
+
+    #[test]
+    fn test_simulation_is_deterministic() {
+        let config = NetworkConfig {
+            jitter: Duration::from_millis(5),
+            loss_percent: 20,
+            ..Default::default()
+        };
+
+        let durations: Vec<_> = (0..2)
+            .map(|_| {
+                let nodes = make_nodes(config);
+                let mut simulation = Simulation::new(&nodes);
+                run_race(&mut simulation);
+                let state = simulation.system_state().unwrap();
+                state.timing.history.last().and_then(|x| x.duration)
+            })
+            .collect();
+
+        assert!(durations[0].is_some());
+        assert_eq!(durations[0], durations[1]);
+    }
+}
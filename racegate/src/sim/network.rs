@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use crate::svc::race_node::RaceNodeMessage;
+
+#[derive(Debug, Copy, Clone)]
+pub struct NetworkConfig {
+    /// Minimum time to deliver a message
+    pub latency: Duration,
+    /// Maximum random delay added to the latency
+    pub jitter: Duration,
+    /// Probability of losing a message, for each receiver
+    pub loss_percent: u8,
+    /// Seed of the random generator, the same seed gives the same run
+    pub seed: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_micros(400),
+            jitter: Duration::from_micros(200),
+            loss_percent: 0,
+            seed: 1,
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: Duration,
+    to: usize,
+    msg: RaceNodeMessage,
+}
+
+/// Broadcast network between simulated nodes, with simulated time
+pub(crate) struct SimNetwork {
+    config: NetworkConfig,
+    rng: XorShift,
+    node_count: usize,
+    now: Duration,
+    in_flight: Vec<InFlight>,
+}
+
+impl SimNetwork {
+    pub(crate) fn new(config: NetworkConfig, node_count: usize) -> Self {
+        Self {
+            config,
+            rng: XorShift::new(config.seed),
+            node_count,
+            now: Duration::ZERO,
+            in_flight: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_now(&mut self, now: Duration) {
+        self.now = now;
+    }
+
+    /// Send a message from node `from` to all the other nodes
+    pub(crate) fn broadcast(&mut self, from: usize, msg: &RaceNodeMessage) {
+        // Messages go through the wire format, like on a real network
+        let Ok(msg) = RaceNodeMessage::try_from(msg.data()) else {
+            log::error!("Cannot parse {:?}", msg);
+            return;
+        };
+
+        for to in (0..self.node_count).filter(|x| *x != from) {
+            if self.rng.percent() < self.config.loss_percent as u64 {
+                continue;
+            }
+
+            let jitter_us = self.config.jitter.as_micros() as u64;
+            let jitter = Duration::from_micros(self.rng.next() % (jitter_us + 1));
+
+            self.in_flight.push(InFlight {
+                deliver_at: self.now + self.config.latency + jitter,
+                to,
+                msg: msg.clone(),
+            });
+        }
+    }
+
+    /// Messages arrived by now, with their receiver and arrival time, in order
+    /// of arrival
+    pub(crate) fn take_arrived(&mut self) -> Vec<(usize, RaceNodeMessage, Duration)> {
+        let now = self.now;
+        let (mut arrived, in_flight): (Vec<_>, Vec<_>) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|x| x.deliver_at <= now);
+
+        self.in_flight = in_flight;
+
+        arrived.sort_by_key(|x| x.deliver_at);
+        arrived
+            .into_iter()
+            .map(|x| (x.to, x.msg, x.deliver_at))
+            .collect()
+    }
+}
+
+/// Small deterministic random generator
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Zero is a fixed point
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn percent(&mut self) -> u64 {
+        self.next() % 100
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::race_node::{CoordinatorBeacon, HardwareId};
+    use crate::svc::CoordinatedInstant;
+
+    fn make_message() -> RaceNodeMessage {
+        CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(1_000),
+            hardware_id: HardwareId::default(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_messages_arrive_after_latency() {
+        let config = NetworkConfig {
+            jitter: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut network = SimNetwork::new(config, 3);
+
+        network.broadcast(0, &make_message());
+
+        network.set_now(Duration::from_micros(300));
+        assert!(network.take_arrived().is_empty());
+
+        network.set_now(Duration::from_micros(400));
+        let receivers: Vec<_> = network.take_arrived().iter().map(|x| x.0).collect();
+        assert_eq!(receivers, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_messages_are_lost() {
+        let config = NetworkConfig {
+            loss_percent: 30,
+            ..Default::default()
+        };
+        let mut network = SimNetwork::new(config, 2);
+
+        for _ in 0..1_000 {
+            network.broadcast(0, &make_message());
+        }
+
+        network.set_now(Duration::from_secs(1));
+        let arrived = network.take_arrived().len();
+        assert!((650..750).contains(&arrived), "{arrived}");
+    }
+}
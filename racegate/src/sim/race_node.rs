@@ -0,0 +1,161 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::app::{Gates, Roster};
+use crate::hal::clock::{Clock, VirtualClock};
+use crate::sim::network::SimNetwork;
+use crate::svc::node_protocol::{NodeProtocol, NodesState};
+use crate::svc::race_node::{
+    GateActivation, GateCommand, GateDiagnostics, NodeAddress, NodeStats, RaceNode,
+    RaceNodeMessage, SystemId,
+};
+use crate::svc::{ClockSync, CoordinatedInstant};
+
+/// Race node on the simulated network. It runs the same protocol of the real
+/// node, driven by the simulation instead of a thread.
+pub struct SimRaceNode {
+    index: usize,
+    clock: Rc<VirtualClock>,
+    network: Rc<RefCell<SimNetwork>>,
+    state: RefCell<NodesState>,
+    protocol: RefCell<NodeProtocol>,
+    tx: RefCell<Option<RaceNodeMessage>>,
+    stats: Cell<NodeStats>,
+}
+
+impl SimRaceNode {
+    pub(crate) fn new(
+        index: usize,
+        clock: Rc<VirtualClock>,
+        network: Rc<RefCell<SimNetwork>>,
+    ) -> Self {
+        Self {
+            index,
+            clock,
+            network,
+            state: RefCell::default(),
+            protocol: RefCell::default(),
+            tx: RefCell::default(),
+            stats: Cell::default(),
+        }
+    }
+
+    /// Periodic transmission, like the node thread does
+    pub(crate) fn poll(&self) {
+        let tx = self.tx.borrow();
+        let messages = self.protocol.borrow_mut().poll(
+            &mut self.state.borrow_mut(),
+            tx.as_ref(),
+            self.clock.now(),
+        );
+
+        for msg in &messages {
+            self.broadcast(msg);
+        }
+    }
+
+    /// Handle a message arrived `delay` ago
+    pub(crate) fn receive(&self, msg: RaceNodeMessage, delay: Duration) {
+        let mut stats = self.stats.get();
+        stats.rx_count += 1;
+        self.stats.set(stats);
+
+        let now = self.clock.now();
+        let response =
+            self.protocol
+                .borrow_mut()
+                .receive(&mut self.state.borrow_mut(), msg, now - delay, now);
+
+        if let Some(response) = response {
+            self.broadcast(&response);
+        }
+    }
+
+    fn broadcast(&self, msg: &RaceNodeMessage) {
+        let mut stats = self.stats.get();
+        stats.tx_count += 1;
+        self.stats.set(stats);
+
+        self.network.borrow_mut().broadcast(self.index, msg);
+    }
+}
+
+impl RaceNode for SimRaceNode {
+    fn set_coordinator_time(&self, t: CoordinatedInstant) {
+        self.state
+            .borrow_mut()
+            .set_coordinator_time(t, self.clock.now());
+    }
+
+    fn coordinator_time(&self) -> Option<CoordinatedInstant> {
+        self.state.borrow().coordinator_time(self.clock.now())
+    }
+
+    fn clock_sync(&self) -> Option<ClockSync> {
+        self.state.borrow().clock_sync()
+    }
+
+    fn sync_loss_percent(&self) -> u8 {
+        self.state.borrow().sync_loss_percent()
+    }
+
+    fn publish(&self, msg: RaceNodeMessage) -> anyhow::Result<()> {
+        self.tx.replace(Some(msg));
+        Ok(())
+    }
+
+    fn gates(&self) -> Gates {
+        self.state.borrow().gates()
+    }
+
+    fn roster(&self) -> Roster {
+        self.state.borrow().roster()
+    }
+
+    fn time_since_coordinator_beacon(&self) -> Duration {
+        self.state
+            .borrow()
+            .time_since_coordinator_beacon(self.clock.now())
+    }
+
+    fn foreign_systems(&self) -> Vec<SystemId> {
+        self.state.borrow().foreign_systems(self.clock.now())
+    }
+
+    fn send_activation(
+        &self,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+        continued: bool,
+        recovered: bool,
+    ) {
+        self.state
+            .borrow_mut()
+            .send_activation(addr, time, continued, recovered);
+    }
+
+    fn pending_activations(&self) -> usize {
+        self.state.borrow().pending_activations()
+    }
+
+    fn send_command(&self, addr: NodeAddress, command: GateCommand) {
+        self.state.borrow_mut().send_command(addr, command);
+    }
+
+    fn take_commands(&self) -> Vec<GateCommand> {
+        self.state.borrow_mut().take_commands()
+    }
+
+    fn take_activations(&self) -> Vec<GateActivation> {
+        self.state.borrow_mut().take_activations()
+    }
+
+    fn stats(&self) -> NodeStats {
+        self.stats.get()
+    }
+
+    fn send_diagnostics(&self, diagnostics: GateDiagnostics) {
+        self.state.borrow_mut().send_diagnostics(diagnostics);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::svc::race_node::{ActivationAck, GateActivation, NodeAddress};
+use crate::svc::CoordinatedInstant;
+
+/// Time to wait for the acknowledgement before sending an activation again
+const RETRANSMIT_PERIOD: Duration = Duration::from_millis(100);
+
+/// Activations waiting for acknowledgement, e.g. while the coordinator is not
+/// reachable. When full, the oldest one is dropped.
+const MAX_PENDING: usize = 256;
+
+/// Sequence numbers remembered by the coordinator to detect duplicates
+const RECEIVE_WINDOW: u16 = 32;
+
+/// Numbers the activations of a gate and sends them again until they are
+/// acknowledged. They are sent one at a time, so the coordinator gets them in
+/// order: the end of a beam interruption never comes before its start.
+pub(crate) struct ActivationSender {
+    next_seq: u16,
+    /// Activations not acknowledged yet, with the last time they were sent
+    pending: VecDeque<(GateActivation, Option<Instant>)>,
+}
+
+impl Default for ActivationSender {
+    fn default() -> Self {
+        // A random start, so the coordinator doesn't take the activations of
+        // a restarted gate for duplicates
+        let next_seq = RandomState::new().build_hasher().finish() as u16;
+
+        Self {
+            next_seq,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl ActivationSender {
+    pub(crate) fn push(
+        &mut self,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+        continued: bool,
+        recovered: bool,
+    ) {
+        let activation = GateActivation {
+            addr,
+            seq: self.next_seq,
+            time,
+            continued,
+            recovered,
+        };
+
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        if self.pending.len() == MAX_PENDING {
+            log::error!("Too many activations not acknowledged, dropping the oldest");
+            self.pending.pop_front();
+        }
+
+        self.pending.push_back((activation, None));
+    }
+
+    /// Activation to send now, if the oldest one is new or not acknowledged
+    /// in time
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<GateActivation> {
+        let (activation, sent) = self.pending.front_mut()?;
+
+        let due = sent
+            .map(|x| now.saturating_duration_since(x) >= RETRANSMIT_PERIOD)
+            .unwrap_or(true);
+
+        if !due {
+            return None;
+        }
+
+        *sent = Some(now);
+        Some(*activation)
+    }
+
+    pub(crate) fn on_ack(&mut self, ack: &ActivationAck) {
+        self.pending
+            .retain(|(x, _)| x.addr != ack.addr || x.seq != ack.seq);
+    }
+
+    pub(crate) fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Activations received by the coordinator from a gate
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ActivationStats {
+    pub received: u32,
+    /// Received more than once, because an acknowledgement was lost
+    pub duplicated: u32,
+    /// Skipped sequence numbers, not received yet
+    pub missing: u32,
+}
+
+/// Detects duplicated and missing activations of a gate
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct ActivationReceiver {
+    /// Highest sequence number received
+    last_seq: Option<u16>,
+    /// Bit `n` is set when `last_seq - n` was received
+    window: u32,
+}
+
+impl ActivationReceiver {
+    /// Returns true if the activation is new, so it has to be handled
+    pub(crate) fn receive(&mut self, seq: u16, stats: &mut ActivationStats) -> bool {
+        let Some(last_seq) = self.last_seq else {
+            self.restart(seq, stats);
+            return true;
+        };
+
+        let ahead = seq.wrapping_sub(last_seq) as i16;
+        let behind = ahead.unsigned_abs();
+
+        if ahead > 0 && behind < RECEIVE_WINDOW {
+            stats.missing += behind as u32 - 1;
+            self.window = (self.window << behind) | 1;
+            self.last_seq = Some(seq);
+        } else if ahead <= 0 && behind < RECEIVE_WINDOW {
+            let bit = 1 << behind;
+
+            if self.window & bit != 0 {
+                stats.duplicated += 1;
+                return false;
+            }
+
+            // Late, but not lost
+            self.window |= bit;
+            stats.missing = stats.missing.saturating_sub(1);
+        } else {
+            log::warn!("Activation {} out of sequence, gate restarted?", seq);
+            self.restart(seq, stats);
+            return true;
+        }
+
+        stats.received += 1;
+        true
+    }
+
+    fn restart(&mut self, seq: u16, stats: &mut ActivationStats) {
+        self.last_seq = Some(seq);
+        self.window = 1;
+        stats.received += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activations_are_sent_until_acknowledged() {
+        let addr = NodeAddress::from(1);
+        let mut sender = ActivationSender::default();
+        let now = Instant::now();
+
+        sender.push(addr, CoordinatedInstant::from_millis(1_000), false, false);
+        sender.push(addr, CoordinatedInstant::from_millis(1_100), true, false);
+
+        let first = sender.poll(now).unwrap();
+        assert_eq!(first.time, CoordinatedInstant::from_millis(1_000));
+
+        // Waiting for the acknowledgement
+        let later = now + Duration::from_millis(50);
+        assert_eq!(sender.poll(later), None);
+        assert_eq!(sender.poll(now + RETRANSMIT_PERIOD), Some(first));
+
+        sender.on_ack(&ActivationAck {
+            addr,
+            seq: first.seq,
+        });
+
+        let second = sender.poll(now + RETRANSMIT_PERIOD).unwrap();
+        assert_eq!(second.seq, first.seq.wrapping_add(1));
+        assert!(second.continued);
+        assert_eq!(sender.pending_count(), 1);
+    }
+
+    #[test]
+    fn test_receiver_detects_duplicates_and_gaps() {
+        let mut receiver = ActivationReceiver::default();
+        let mut stats = ActivationStats::default();
+
+        assert!(receiver.receive(65_535, &mut stats));
+        assert!(!receiver.receive(65_535, &mut stats));
+
+        // Two activations are missing, one of them arrives later
+        assert!(receiver.receive(2, &mut stats));
+        assert_eq!(stats.missing, 2);
+        assert!(receiver.receive(0, &mut stats));
+        assert!(!receiver.receive(0, &mut stats));
+
+        assert_eq!(
+            stats,
+            ActivationStats {
+                received: 3,
+                duplicated: 2,
+                missing: 1,
+            }
+        );
+
+        // The gate restarted with another sequence
+        assert!(receiver.receive(30_000, &mut stats));
+        assert!(receiver.receive(30_001, &mut stats));
+        assert_eq!(stats.received, 5);
+    }
+}
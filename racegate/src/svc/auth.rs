@@ -0,0 +1,252 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use sha2::Sha256;
+use siphasher::sip::SipHasher24;
+
+use crate::svc::race_node::FrameData;
+
+/// Senders remembered to detect replays. Each node has one sender per boot.
+const MAX_SENDERS: usize = 16;
+
+/// Key derivation is slow on purpose, to make guessing the passphrase of
+/// captured frames expensive. It only runs at boot.
+const KDF_ROUNDS: u32 = 4096;
+
+/// Keys derived for racegate are not usable for anything else
+const KDF_SALT: &[u8] = b"racegate network key";
+
+/// Operator tokens are derived from the same passphrase, but can't be used
+/// to sign frames
+const TOKEN_SALT: &[u8] = b"racegate operator token";
+
+/// Secret shared by all the nodes of a system
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct NetworkKey([u64; 2]);
+
+impl NetworkKey {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut bytes = [0u8; 16];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), KDF_SALT, KDF_ROUNDS, &mut bytes);
+
+        let (k0, k1) = bytes.split_at(8);
+        Self([
+            u64::from_le_bytes(k0.try_into().unwrap()),
+            u64::from_le_bytes(k1.try_into().unwrap()),
+        ])
+    }
+}
+
+impl std::fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NetworkKey(..)")
+    }
+}
+
+/// Proves that operator commands are sent by somebody knowing the network key
+#[derive(Clone, Eq, PartialEq)]
+pub struct OperatorToken(String);
+
+impl OperatorToken {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut bytes = [0u8; 16];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), TOKEN_SALT, KDF_ROUNDS, &mut bytes);
+
+        Self(bytes.iter().map(|x| format!("{x:02x}")).collect())
+    }
+
+    /// Value of the `Authorization` header of operator commands
+    pub fn authorization(&self) -> String {
+        format!("Bearer {}", self.0)
+    }
+
+    /// The `Authorization` header of a request carries this token
+    pub fn verify(&self, authorization: Option<&str>) -> bool {
+        let Some(token) = authorization.and_then(|x| x.strip_prefix("Bearer ")) else {
+            return false;
+        };
+
+        // Compared in constant time, not to tell how much of it is right
+        token.len() == self.0.len()
+            && token
+                .bytes()
+                .zip(self.0.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl std::fmt::Debug for OperatorToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OperatorToken(..)")
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AuthError {
+    /// Frame not sent by a node with the same key
+    BadMac,
+    /// Frame already received, or older than the last one of its sender
+    Replayed,
+}
+
+/// Signs sent frames and verifies received ones with a MAC. Replays are
+/// detected because every sender numbers its frames.
+///
+/// Replay protection is limited: only the last `MAX_SENDERS` senders are
+/// remembered and nothing survives a reboot of the receiver. Frames captured
+/// before a reboot, or from a sender forgotten since, are accepted again.
+pub struct Authenticator {
+    key: NetworkKey,
+    boot_id: u32,
+    counter: u32,
+    /// Last counter of recent senders, the most recent last
+    senders: Vec<(u32, u32)>,
+}
+
+impl Authenticator {
+    pub fn new(key: NetworkKey) -> Self {
+        // Another boot id on each boot, so counters can restart from zero
+        let boot_id = RandomState::new().build_hasher().finish() as u32;
+        Self::new_with_boot_id(key, boot_id)
+    }
+
+    fn new_with_boot_id(key: NetworkKey, boot_id: u32) -> Self {
+        Self {
+            key,
+            boot_id,
+            counter: 0,
+            senders: Vec::with_capacity(MAX_SENDERS),
+        }
+    }
+
+    pub fn sign(&mut self, data: &mut FrameData) {
+        self.counter = self.counter.wrapping_add(1);
+        data.set_counter(self.boot_id, self.counter);
+        data.set_mac(siphash24(&self.key.0, data.authenticated_bytes()));
+    }
+
+    pub fn verify(&mut self, data: &FrameData) -> Result<(), AuthError> {
+        let auth = data.auth();
+
+        if auth.mac != siphash24(&self.key.0, data.authenticated_bytes()) {
+            return Err(AuthError::BadMac);
+        }
+
+        let index = self.senders.iter().position(|x| x.0 == auth.boot_id);
+
+        if let Some(index) = index {
+            let (_, last_counter) = self.senders.remove(index);
+            if auth.counter <= last_counter {
+                self.senders.push((auth.boot_id, last_counter));
+                return Err(AuthError::Replayed);
+            }
+        } else if self.senders.len() == MAX_SENDERS {
+            // Forget the sender not heard for the longest time
+            self.senders.remove(0);
+        }
+
+        self.senders.push((auth.boot_id, auth.counter));
+
+        Ok(())
+    }
+}
+
+/// SipHash-2-4, a keyed hash made for short messages
+fn siphash24(key: &[u64; 2], data: &[u8]) -> u64 {
+    let mut hasher = SipHasher24::new_with_keys(key[0], key[1]);
+    hasher.write(data);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::race_node::{CoordinatorBeacon, HardwareId, RaceNodeMessage};
+    use crate::svc::CoordinatedInstant;
+
+    fn make_frame() -> FrameData {
+        RaceNodeMessage::from(CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(1_000),
+            hardware_id: HardwareId::default(),
+        })
+        .data()
+    }
+
+    #[test]
+    fn test_siphash_reference_vectors() {
+        let key = [
+            u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]),
+            u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]),
+        ];
+        let message: Vec<u8> = (0..15).collect();
+
+        assert_eq!(siphash24(&key, &[]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(&key, &message), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn test_frames_signed_with_another_key_are_rejected() {
+        let mut sender = Authenticator::new_with_boot_id(NetworkKey::from_passphrase("a"), 1);
+        let mut receiver = Authenticator::new_with_boot_id(NetworkKey::from_passphrase("b"), 2);
+
+        let mut data = make_frame();
+        sender.sign(&mut data);
+
+        assert_eq!(receiver.verify(&data), Err(AuthError::BadMac));
+        assert_eq!(receiver.verify(&make_frame()), Err(AuthError::BadMac));
+    }
+
+    #[test]
+    fn test_replayed_frames_are_rejected() {
+        let key = NetworkKey::from_passphrase("secret");
+        let mut sender = Authenticator::new_with_boot_id(key, 1);
+        let mut receiver = Authenticator::new_with_boot_id(key, 2);
+
+        let mut first = make_frame();
+        sender.sign(&mut first);
+        let mut second = make_frame();
+        sender.sign(&mut second);
+
+        assert_eq!(receiver.verify(&first), Ok(()));
+        assert_eq!(receiver.verify(&second), Ok(()));
+        assert_eq!(receiver.verify(&second), Err(AuthError::Replayed));
+        assert_eq!(receiver.verify(&first), Err(AuthError::Replayed));
+
+        // The sender rebooted
+        let mut sender = Authenticator::new_with_boot_id(key, 3);
+        let mut third = make_frame();
+        sender.sign(&mut third);
+        assert_eq!(receiver.verify(&third), Ok(()));
+        assert!(RaceNodeMessage::try_from(third).is_ok());
+    }
+
+    #[test]
+    fn test_operator_token() {
+        let token = OperatorToken::from_passphrase("secret");
+
+        assert!(token.verify(Some(&token.authorization())));
+        assert!(!token.verify(None));
+        assert!(!token.verify(Some("Bearer ")));
+
+        let other = OperatorToken::from_passphrase("forged");
+        assert!(!token.verify(Some(&other.authorization())));
+    }
+
+    #[test]
+    fn test_tampered_frames_are_rejected() {
+        let key = NetworkKey::from_passphrase("secret");
+        let mut sender = Authenticator::new_with_boot_id(key, 1);
+        let mut receiver = Authenticator::new_with_boot_id(key, 2);
+
+        let mut data = make_frame();
+        sender.sign(&mut data);
+
+        // A forged counter, as if the frame was new
+        let auth = data.auth();
+        data.set_counter(auth.boot_id, auth.counter + 1);
+        data.set_mac(auth.mac);
+
+        assert_eq!(receiver.verify(&data), Err(AuthError::BadMac));
+    }
+}
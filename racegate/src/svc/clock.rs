@@ -1,12 +1,27 @@
+use std::ops::{Add, Sub};
+use std::time::Duration;
+
+use crate::svc::drift::Drift;
+
+/// Local monotonic time, in microseconds since the local clock started
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
-pub struct LocalInstant(i32);
+pub struct LocalInstant(i64);
 
 impl LocalInstant {
     pub fn from_millis(ms: i32) -> Self {
-        Self(ms)
+        Self(ms as i64 * 1_000)
+    }
+
+    pub fn from_micros(us: i64) -> Self {
+        Self(us)
     }
 
-    pub fn as_millis(&self) -> i32 {
+    /// Milliseconds, rounded down
+    pub fn as_millis(&self) -> i64 {
+        self.0.div_euclid(1_000)
+    }
+
+    pub fn as_micros(&self) -> i64 {
         self.0
     }
 }
@@ -16,28 +31,20 @@ pub struct LocalClock {
     start: std::time::Instant,
 }
 
-impl Default for LocalClock {
-    fn default() -> Self {
-        Self {
-            start: std::time::Instant::now(),
-        }
+impl LocalClock {
+    pub fn new(start: std::time::Instant) -> Self {
+        Self { start }
     }
-}
 
-impl LocalClock {
-    pub fn now(&self) -> Option<LocalInstant> {
-        let t = std::time::Instant::now().checked_duration_since(self.start)?;
-        let t_ms = t.as_millis();
-
-        // milliseconds, 32 bits, max 24 days (enough!)
-        if t_ms < (i32::MAX as u128) {
-            Some(LocalInstant(t_ms as i32))
-        } else {
-            None
-        }
+    /// Local time of an instant, like the current time or an interrupt
+    pub fn at(&self, instant: std::time::Instant) -> Option<LocalInstant> {
+        let t = instant.checked_duration_since(self.start)?;
+        let t_us = i64::try_from(t.as_micros()).ok()?;
+        Some(LocalInstant(t_us))
     }
 }
 
+/// Time shared by all nodes, in microseconds since the coordinator started
 #[derive(
     Default,
     Debug,
@@ -50,32 +57,161 @@ impl LocalClock {
     serde::Serialize,
     serde::Deserialize,
 )]
-pub struct CoordinatedInstant(i32);
+pub struct CoordinatedInstant(i64);
 
 impl CoordinatedInstant {
     pub fn from_millis(ms: i32) -> Self {
-        Self(ms)
+        Self(ms as i64 * 1_000)
     }
 
-    pub fn as_millis(&self) -> i32 {
+    pub fn from_micros(us: i64) -> Self {
+        Self(us)
+    }
+
+    /// Milliseconds, rounded down
+    pub fn as_millis(&self) -> i64 {
+        self.0.div_euclid(1_000)
+    }
+
+    pub fn as_micros(&self) -> i64 {
         self.0
     }
+
+    /// Time elapsed from `earlier` to `self`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: CoordinatedInstant) -> Duration {
+        let diff = self.0.saturating_sub(earlier.0).max(0);
+        Duration::from_micros(diff as u64)
+    }
+}
+
+impl Add<Duration> for CoordinatedInstant {
+    type Output = CoordinatedInstant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        CoordinatedInstant(self.0 + rhs.as_micros() as i64)
+    }
+}
+
+impl Sub<Duration> for CoordinatedInstant {
+    type Output = CoordinatedInstant;
+
+    fn sub(self, rhs: Duration) -> Self::Output {
+        CoordinatedInstant(self.0 - rhs.as_micros() as i64)
+    }
+}
+
+/// Drift bound assumed until it is estimated, two crystals with 25ppm
+/// tolerance each
+pub(crate) const DEFAULT_DRIFT_BOUND_PPB: i64 = 50_000;
+
+/// Corrections larger than this are stepped instead of slewed
+const MAX_SLEW: Duration = Duration::from_millis(10);
+
+/// Rate corrections are slewed at, in parts per million. It is small enough to
+/// keep the clock monotonic and not to distort measured durations.
+const SLEW_RATE_PPM: i64 = 500;
+
+/// Correction still to be applied to the clock
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Slew {
+    start: LocalInstant,
+    correction_us: i64,
+}
+
+impl Slew {
+    fn remaining_us(&self, t: LocalInstant) -> i64 {
+        let elapsed = (t.as_micros() - self.start.as_micros()).max(0);
+        let applied = elapsed as i128 * SLEW_RATE_PPM as i128 / 1_000_000;
+        let remaining = (self.correction_us.unsigned_abs() as i128 - applied).max(0) as i64;
+        remaining * self.correction_us.signum()
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct CoordinatedClock {
     clock: LocalClock,
     offset: LocalOffset,
+    /// Local time when the offset was calculated
+    reference: LocalInstant,
+    /// Error at the reference time
+    error: Duration,
+    drift: Option<Drift>,
+    slew: Option<Slew>,
 }
 
 impl CoordinatedClock {
     pub fn new(clock: LocalClock, offset: LocalOffset) -> Self {
-        Self { clock, offset }
+        Self {
+            clock,
+            offset,
+            reference: LocalInstant::default(),
+            error: Duration::ZERO,
+            drift: None,
+            slew: None,
+        }
+    }
+
+    /// Offset calculated at `reference` with the given error. The estimated
+    /// drift keeps the clock accurate when no synchronization is done.
+    pub fn with_sync(self, reference: LocalInstant, error: Duration, drift: Option<Drift>) -> Self {
+        Self {
+            reference,
+            error,
+            drift,
+            ..self
+        }
+    }
+
+    pub fn at(&self, instant: std::time::Instant) -> Option<CoordinatedInstant> {
+        let t = self.clock.at(instant)?;
+        Some(self.at_local(t))
+    }
+
+    pub fn at_local(&self, t: LocalInstant) -> CoordinatedInstant {
+        let elapsed = t.as_micros() - self.reference.as_micros();
+        let drift_ppb = self.drift.map(|x| x.ppb).unwrap_or(0);
+        let correction = (elapsed as i128 * drift_ppb as i128 / 1_000_000_000) as i64;
+        let slew = self.slew.map(|x| x.remaining_us(t)).unwrap_or(0);
+        CoordinatedInstant::from_micros(t.as_micros() + self.offset.as_micros() + correction + slew)
+    }
+
+    /// Replace this clock with a new estimation at local time `t`. Small
+    /// corrections are applied slowly, so the time does not jump.
+    pub fn slew_to(&self, target: CoordinatedClock, t: LocalInstant) -> CoordinatedClock {
+        let correction_us = self.at_local(t).as_micros() - target.at_local(t).as_micros();
+
+        if correction_us.unsigned_abs() > MAX_SLEW.as_micros() as u64 {
+            log::warn!("Clock stepped by {}us", -correction_us);
+            return target;
+        }
+
+        CoordinatedClock {
+            slew: Some(Slew {
+                start: t,
+                correction_us,
+            }),
+            ..target
+        }
+    }
+
+    /// Maximum error of the coordinated time, growing with the time since the
+    /// last synchronization, including the correction still being slewed
+    pub fn uncertainty_at(&self, t: LocalInstant) -> Duration {
+        let slew_error = self.slew.map(|x| x.remaining_us(t)).unwrap_or(0);
+        self.sync_uncertainty_at(t) + Duration::from_micros(slew_error.unsigned_abs())
     }
 
-    pub fn now(&self) -> CoordinatedInstant {
-        let t = self.clock.now().expect("Cannot get time");
-        CoordinatedInstant::from_millis(t.as_millis() + self.offset.as_millis())
+    /// Maximum error of the estimation of the coordinated time, growing with
+    /// the time since the last synchronization. A slewed correction is known
+    /// and shrinking, so it is not part of it.
+    pub fn sync_uncertainty_at(&self, t: LocalInstant) -> Duration {
+        let elapsed = (t.as_micros() - self.reference.as_micros()).unsigned_abs();
+        let drift_bound_ppb = self
+            .drift
+            .map(|x| x.uncertainty_ppb)
+            .unwrap_or(DEFAULT_DRIFT_BOUND_PPB);
+        let drift_error = elapsed as u128 * drift_bound_ppb as u128 / 1_000_000_000;
+        self.error + Duration::from_micros(drift_error as u64)
     }
 
     pub fn offset(&self) -> LocalOffset {
@@ -83,15 +219,25 @@ impl CoordinatedClock {
     }
 }
 
+/// Difference between coordinated and local time, in microseconds
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
-pub struct LocalOffset(i32);
+pub struct LocalOffset(i64);
 
 impl LocalOffset {
     pub fn from_millis(ms: i32) -> Self {
-        Self(ms)
+        Self(ms as i64 * 1_000)
     }
 
-    pub fn as_millis(&self) -> i32 {
+    pub fn from_micros(us: i64) -> Self {
+        Self(us)
+    }
+
+    /// Milliseconds, rounded down
+    pub fn as_millis(&self) -> i64 {
+        self.0.div_euclid(1_000)
+    }
+
+    pub fn as_micros(&self) -> i64 {
         self.0
     }
 }
@@ -100,9 +246,52 @@ pub fn calculate_clock_offset(
     coordinator_time: CoordinatedInstant,
     local_time: LocalInstant,
 ) -> LocalOffset {
-    let c = coordinator_time.as_millis();
-    let l = local_time.as_millis();
-    LocalOffset::from_millis(c - l)
+    let c = coordinator_time.as_micros();
+    let l = local_time.as_micros();
+    LocalOffset::from_micros(c - l)
+}
+
+/// Coordinated time estimated with a round trip exchange with the coordinator
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ClockSync {
+    /// Coordinated time at `instant`
+    pub time: CoordinatedInstant,
+    /// When the response was received
+    pub instant: std::time::Instant,
+    /// Maximum error, when all the delay is on one way only
+    pub error: Duration,
+}
+
+/// Synchronization at a local time
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ClockSample {
+    pub local: LocalInstant,
+    pub time: CoordinatedInstant,
+    pub error: Duration,
+}
+
+/// Estimate the coordinated time when a sync response is received, assuming
+/// the same delay on both ways, like NTP does.
+///
+/// ```text
+/// gate         request_instant                      response_instant
+///                    \                                   /
+/// coordinator         rx_time -- turnaround -- tx_time
+/// ```
+pub fn calculate_clock_sync(
+    request_instant: std::time::Instant,
+    response_instant: std::time::Instant,
+    rx_time: CoordinatedInstant,
+    turnaround: Duration,
+) -> Option<ClockSync> {
+    let round_trip = response_instant.checked_duration_since(request_instant)?;
+    let delay = round_trip.saturating_sub(turnaround) / 2;
+
+    Some(ClockSync {
+        time: rx_time + turnaround + delay,
+        instant: response_instant,
+        error: delay,
+    })
 }
 
 #[cfg(test)]
@@ -124,4 +313,128 @@ mod tests {
         let offset = calculate_clock_offset(coord_time, local_time);
         assert_eq!(offset, LocalOffset::from_millis(-50_000));
     }
+
+    #[test]
+    fn test_coordinated_instant_has_microsecond_resolution() {
+        let t0 = CoordinatedInstant::from_micros(1_000_250);
+        let t1 = t0 + Duration::from_micros(12_345);
+        assert_eq!(t1.as_micros(), 1_012_595);
+        assert_eq!(t1.as_millis(), 1_012);
+        assert_eq!(t1.duration_since(t0), Duration::from_micros(12_345));
+        assert_eq!(CoordinatedInstant::from_micros(-1).as_millis(), -1);
+    }
+
+    #[test]
+    fn test_calculate_clock_sync_compensates_delay() {
+        let request_instant = std::time::Instant::now();
+        // 3ms to coordinator, 1ms turnaround, 3ms back
+        let response_instant = request_instant + Duration::from_millis(7);
+        let rx_time = CoordinatedInstant::from_millis(60_000);
+        let turnaround = Duration::from_millis(1);
+
+        let sync =
+            calculate_clock_sync(request_instant, response_instant, rx_time, turnaround).unwrap();
+
+        assert_eq!(sync.time, CoordinatedInstant::from_millis(60_004));
+        assert_eq!(sync.instant, response_instant);
+        assert_eq!(sync.error, Duration::from_millis(3));
+    }
+
+    #[test]
+    fn test_calculate_clock_sync_with_response_before_request() {
+        let response_instant = std::time::Instant::now();
+        let request_instant = response_instant + Duration::from_millis(1);
+        let rx_time = CoordinatedInstant::from_millis(60_000);
+
+        let sync = calculate_clock_sync(request_instant, response_instant, rx_time, Duration::ZERO);
+
+        assert_eq!(sync, None);
+    }
+
+    #[test]
+    fn test_coordinated_clock_compensates_drift() {
+        let reference = LocalInstant::from_millis(10_000);
+        let drift = Drift {
+            ppb: 20_000,
+            uncertainty_ppb: 1_000,
+        };
+        let clock = CoordinatedClock::new(
+            LocalClock::new(std::time::Instant::now()),
+            LocalOffset::from_millis(50_000),
+        )
+        .with_sync(reference, Duration::from_micros(300), Some(drift));
+
+        // 20ppm in 30s is 600us
+        let t = LocalInstant::from_millis(40_000);
+        assert_eq!(
+            clock.at_local(t),
+            CoordinatedInstant::from_micros(90_000_600)
+        );
+        // 1ppm in 30s is 30us
+        assert_eq!(clock.uncertainty_at(t), Duration::from_micros(330));
+    }
+
+    #[test]
+    fn test_coordinated_clock_uncertainty_without_drift_estimation() {
+        let reference = LocalInstant::from_millis(10_000);
+        let clock = CoordinatedClock::new(
+            LocalClock::new(std::time::Instant::now()),
+            LocalOffset::from_millis(0),
+        )
+        .with_sync(reference, Duration::from_micros(300), None);
+
+        let t = LocalInstant::from_millis(20_000);
+        assert_eq!(clock.at_local(t), CoordinatedInstant::from_millis(20_000));
+        assert_eq!(clock.uncertainty_at(t), Duration::from_micros(800));
+    }
+
+    #[test]
+    fn test_coordinated_clock_slews_small_corrections() {
+        let previous = CoordinatedClock::new(
+            LocalClock::new(std::time::Instant::now()),
+            LocalOffset::from_millis(0),
+        );
+        let target = CoordinatedClock::new(
+            LocalClock::new(std::time::Instant::now()),
+            LocalOffset::from_millis(-1),
+        );
+
+        let t = LocalInstant::from_millis(10_000);
+        let clock = previous.slew_to(target, t);
+
+        assert_eq!(clock.at_local(t), CoordinatedInstant::from_millis(10_000));
+        // 500ppm in 1s is 500us
+        assert_eq!(
+            clock.at_local(LocalInstant::from_millis(11_000)),
+            CoordinatedInstant::from_micros(10_999_500)
+        );
+        assert_eq!(
+            clock.at_local(LocalInstant::from_millis(13_000)),
+            CoordinatedInstant::from_millis(12_999)
+        );
+
+        let mut last = clock.at_local(t);
+        for ms in (10_000..13_000).step_by(100) {
+            let time = clock.at_local(LocalInstant::from_millis(ms));
+            assert!(time >= last);
+            last = time;
+        }
+    }
+
+    #[test]
+    fn test_coordinated_clock_steps_large_corrections() {
+        let previous = CoordinatedClock::new(
+            LocalClock::new(std::time::Instant::now()),
+            LocalOffset::from_millis(0),
+        );
+        let target = CoordinatedClock::new(
+            LocalClock::new(std::time::Instant::now()),
+            LocalOffset::from_millis(-100),
+        );
+
+        let t = LocalInstant::from_millis(10_000);
+        let clock = previous.slew_to(target, t);
+
+        assert_eq!(clock.at_local(t), CoordinatedInstant::from_millis(9_900));
+    }
 }
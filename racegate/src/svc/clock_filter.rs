@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use crate::svc::clock::DEFAULT_DRIFT_BOUND_PPB;
+use crate::svc::ClockSample;
+
+/// Number of synchronizations the best one is selected from
+const WINDOW_SIZE: usize = 8;
+
+/// Selects the synchronization with the minimum delay among the latest ones,
+/// so the coordinated time does not follow the jitter of the network latency.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ClockFilter {
+    samples: [Option<ClockSample>; WINDOW_SIZE],
+    next: usize,
+}
+
+impl ClockFilter {
+    pub fn add(&mut self, sample: ClockSample) {
+        self.samples[self.next] = Some(sample);
+        self.next = (self.next + 1) % WINDOW_SIZE;
+    }
+
+    /// Sample with the least error at local time `now`. Older samples are
+    /// penalized by the drift they may have accumulated.
+    pub fn best(&self, now: &ClockSample) -> Option<ClockSample> {
+        self.samples
+            .iter()
+            .flatten()
+            .min_by_key(|x| aged_error(x, now))
+            .copied()
+    }
+}
+
+fn aged_error(sample: &ClockSample, now: &ClockSample) -> Duration {
+    let age = (now.local.as_micros() - sample.local.as_micros()).unsigned_abs();
+    let drift_error = age as u128 * DEFAULT_DRIFT_BOUND_PPB as u128 / 1_000_000_000;
+    sample.error + Duration::from_micros(drift_error as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::{CoordinatedInstant, LocalInstant};
+
+    fn make_sample(local_ms: i32, error_us: u64) -> ClockSample {
+        ClockSample {
+            local: LocalInstant::from_millis(local_ms),
+            time: CoordinatedInstant::from_millis(local_ms + 50_000),
+            error: Duration::from_micros(error_us),
+        }
+    }
+
+    #[test]
+    fn test_best_sample_has_minimum_delay() {
+        let mut filter = ClockFilter::default();
+
+        filter.add(make_sample(1_000, 900));
+        filter.add(make_sample(1_200, 150));
+        filter.add(make_sample(1_400, 2_500));
+        let now = make_sample(1_600, 600);
+        filter.add(now);
+
+        assert_eq!(filter.best(&now), Some(make_sample(1_200, 150)));
+    }
+
+    #[test]
+    fn test_old_samples_leave_the_window() {
+        let mut filter = ClockFilter::default();
+
+        filter.add(make_sample(1_000, 100));
+        for i in 1..=WINDOW_SIZE as i32 {
+            filter.add(make_sample(1_000 + i * 200, 400));
+        }
+        let now = make_sample(2_800, 400);
+
+        assert_eq!(
+            filter.best(&now).map(|x| x.error),
+            Some(Duration::from_micros(400))
+        );
+    }
+
+    #[test]
+    fn test_old_samples_are_penalized() {
+        let mut filter = ClockFilter::default();
+
+        // 50ppm in 1s is 50us
+        filter.add(make_sample(1_000, 100));
+        let now = make_sample(2_000, 140);
+        filter.add(now);
+
+        assert_eq!(filter.best(&now), Some(now));
+    }
+}
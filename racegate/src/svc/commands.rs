@@ -0,0 +1,172 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::svc::race_node::{CommandAck, CoordinatorCommand, GateCommand, NodeAddress};
+
+/// Time to wait for the acknowledgement before sending a command again
+const RETRANSMIT_PERIOD: Duration = Duration::from_millis(100);
+
+/// A command not acknowledged after this many attempts is dropped, the gate
+/// is probably not reachable
+const MAX_ATTEMPTS: u32 = 30;
+
+/// Commands waiting for acknowledgement. When full, the oldest one is
+/// dropped.
+const MAX_PENDING: usize = 32;
+
+struct PendingCommand {
+    command: CoordinatorCommand,
+    /// Last time it was sent
+    sent: Option<Instant>,
+    attempts: u32,
+}
+
+/// Numbers the commands sent by the coordinator and sends them again until
+/// they are acknowledged. Commands to the same gate are sent one at a time,
+/// so they are applied in order.
+pub(crate) struct CommandSender {
+    next_seq: u16,
+    pending: VecDeque<PendingCommand>,
+}
+
+impl Default for CommandSender {
+    fn default() -> Self {
+        // A random start, so a gate doesn't take the commands of a restarted
+        // coordinator for duplicates
+        let next_seq = RandomState::new().build_hasher().finish() as u16;
+
+        Self {
+            next_seq,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl CommandSender {
+    pub(crate) fn push(&mut self, addr: NodeAddress, command: GateCommand) {
+        let command = CoordinatorCommand {
+            addr,
+            seq: self.next_seq,
+            command,
+        };
+
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        if self.pending.len() == MAX_PENDING {
+            log::error!("Too many commands not acknowledged, dropping the oldest");
+            self.pending.pop_front();
+        }
+
+        self.pending.push_back(PendingCommand {
+            command,
+            sent: None,
+            attempts: 0,
+        });
+    }
+
+    /// Commands to send now: the oldest one of each gate, if it is new or not
+    /// acknowledged in time
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<CoordinatorCommand> {
+        self.pending.retain(|x| {
+            let given_up = x.attempts >= MAX_ATTEMPTS;
+
+            if given_up {
+                log::warn!("Command not acknowledged: {:?}", x.command);
+            }
+
+            !given_up
+        });
+
+        let mut addrs = Vec::new();
+        let mut commands = Vec::new();
+
+        for pending in self.pending.iter_mut() {
+            let addr = pending.command.addr;
+
+            if addrs.contains(&addr) {
+                continue;
+            }
+
+            addrs.push(addr);
+
+            let due = pending
+                .sent
+                .map(|x| now.saturating_duration_since(x) >= RETRANSMIT_PERIOD)
+                .unwrap_or(true);
+
+            if due {
+                pending.sent = Some(now);
+                pending.attempts += 1;
+                commands.push(pending.command);
+            }
+        }
+
+        commands
+    }
+
+    pub(crate) fn on_ack(&mut self, ack: &CommandAck) {
+        self.pending
+            .retain(|x| x.command.addr != ack.addr || x.command.seq != ack.seq);
+    }
+}
+
+/// Detects commands received again by a gate, because the acknowledgement
+/// was lost
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct CommandReceiver {
+    last_seq: Option<u16>,
+}
+
+impl CommandReceiver {
+    /// Returns true if the command is new, so it has to be applied
+    pub(crate) fn receive(&mut self, seq: u16) -> bool {
+        self.last_seq.replace(seq) != Some(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_to_a_gate_are_sent_in_order() {
+        let gate_1 = NodeAddress::from(1);
+        let gate_2 = NodeAddress::from(2);
+        let mut sender = CommandSender::default();
+        let now = Instant::now();
+
+        sender.push(gate_1, GateCommand::Identify);
+        sender.push(gate_1, GateCommand::Disarm);
+        sender.push(gate_2, GateCommand::Reboot);
+
+        let sent = sender.poll(now);
+        let commands: Vec<_> = sent.iter().map(|x| x.command).collect();
+        assert_eq!(commands, vec![GateCommand::Identify, GateCommand::Reboot]);
+
+        sender.on_ack(&CommandAck {
+            addr: gate_1,
+            seq: sent[0].seq,
+        });
+
+        // The next command of gate 1 goes immediately, gate 2 is waiting
+        let sent = sender.poll(now + Duration::from_millis(10));
+        let commands: Vec<_> = sent.iter().map(|x| x.command).collect();
+        assert_eq!(commands, vec![GateCommand::Disarm]);
+
+        // Commands never acknowledged are dropped
+        for i in 1..=MAX_ATTEMPTS + 1 {
+            sender.poll(now + RETRANSMIT_PERIOD * i);
+        }
+        assert!(sender.pending.is_empty());
+    }
+
+    #[test]
+    fn test_receiver_detects_duplicates() {
+        let mut receiver = CommandReceiver::default();
+        assert!(receiver.receive(65_535));
+        assert!(!receiver.receive(65_535));
+        assert!(receiver.receive(0));
+    }
+}
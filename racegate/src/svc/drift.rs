@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use crate::svc::ClockSample;
+
+/// Shortest time between two synchronizations to estimate the drift
+const MIN_BASELINE: Duration = Duration::from_secs(10);
+
+/// The reference synchronization is replaced after this time, so the estimation
+/// follows changes of the drift, like the ones due to temperature
+const MAX_BASELINE: Duration = Duration::from_secs(120);
+
+/// Crystals can't drift more than this, unless the coordinator restarted
+const MAX_DRIFT_PPB: i64 = 500_000;
+
+/// Estimations less accurate than the crystal tolerance are useless
+const MAX_UNCERTAINTY_PPB: i64 = 50_000;
+
+/// Rate of the coordinator clock relative to the local one
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Drift {
+    /// Parts per billion, positive when the coordinator clock is faster
+    pub ppb: i64,
+    /// Maximum error of the estimation, in parts per billion
+    pub uncertainty_ppb: i64,
+}
+
+/// Estimates the drift comparing successive synchronizations with the
+/// coordinator
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct DriftEstimator {
+    reference: Option<ClockSample>,
+    drift: Option<Drift>,
+}
+
+impl DriftEstimator {
+    pub fn update(&mut self, sample: ClockSample) {
+        let Some(reference) = self.reference else {
+            self.reference = Some(sample);
+            return;
+        };
+
+        let local_delta = sample.local.as_micros() - reference.local.as_micros();
+        let coordinated_delta = sample.time.as_micros() - reference.time.as_micros();
+
+        if local_delta < MIN_BASELINE.as_micros() as i64 {
+            return;
+        }
+
+        let ppb = (coordinated_delta - local_delta) as i128 * 1_000_000_000 / local_delta as i128;
+        let ppb = ppb as i64;
+
+        if ppb.abs() > MAX_DRIFT_PPB {
+            log::warn!("Drift out of range, restarting estimation");
+            self.reference = Some(sample);
+            self.drift = None;
+            return;
+        }
+
+        let error = (reference.error + sample.error).as_micros() as i128;
+        let uncertainty_ppb = (error * 1_000_000_000 / local_delta as i128) as i64;
+
+        if uncertainty_ppb < MAX_UNCERTAINTY_PPB {
+            self.drift = Some(Drift {
+                ppb,
+                uncertainty_ppb,
+            });
+        }
+
+        if local_delta > MAX_BASELINE.as_micros() as i64 {
+            self.reference = Some(sample);
+        }
+    }
+
+    pub fn drift(&self) -> Option<Drift> {
+        self.drift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::{CoordinatedInstant, LocalInstant};
+
+    fn make_sample(local_ms: i32, time_us: i64, error_us: u64) -> ClockSample {
+        ClockSample {
+            local: LocalInstant::from_millis(local_ms),
+            time: CoordinatedInstant::from_micros(time_us),
+            error: Duration::from_micros(error_us),
+        }
+    }
+
+    #[test]
+    fn test_drift_is_estimated_after_min_baseline() {
+        let mut estimator = DriftEstimator::default();
+
+        estimator.update(make_sample(1_000, 51_000_000, 200));
+        estimator.update(make_sample(5_000, 55_000_040, 200));
+        assert_eq!(estimator.drift(), None);
+
+        // Coordinator is 10ppm faster: 200us in 20s
+        estimator.update(make_sample(21_000, 71_000_200, 200));
+        assert_eq!(
+            estimator.drift(),
+            Some(Drift {
+                ppb: 10_000,
+                uncertainty_ppb: 20_000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_drift_estimation_restarts_when_coordinator_restarts() {
+        let mut estimator = DriftEstimator::default();
+
+        estimator.update(make_sample(1_000, 51_000_000, 200));
+        estimator.update(make_sample(21_000, 71_000_200, 200));
+        assert!(estimator.drift().is_some());
+
+        estimator.update(make_sample(41_000, 5_000_000, 200));
+        assert_eq!(estimator.drift(), None);
+    }
+}
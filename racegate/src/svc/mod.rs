@@ -1,15 +1,29 @@
-use crate::app::SystemState;
+use crate::app::{OperatorCommand, SystemState};
+pub use activations::ActivationStats;
+pub use auth::{NetworkKey, OperatorToken};
 pub use clock::{
-    calculate_clock_offset, CoordinatedClock, CoordinatedInstant, LocalClock, LocalInstant,
-    LocalOffset,
+    calculate_clock_offset, calculate_clock_sync, ClockSample, ClockSync, CoordinatedClock,
+    CoordinatedInstant, LocalClock, LocalInstant, LocalOffset,
 };
+pub use clock_filter::ClockFilter;
+pub use drift::{Drift, DriftEstimator};
 pub use race_node::RaceNode;
-pub use std_race_node::StdRaceNode;
+pub use std_race_node::{NodeConfig, StdRaceNode};
+pub use transport::Transport;
 
+pub(crate) mod activations;
+pub mod auth;
 mod clock;
+mod clock_filter;
+mod commands;
+mod drift;
+pub(crate) mod node_protocol;
 pub mod race_node;
 mod std_race_node;
+pub mod transport;
 
 pub trait HttpServer {
     fn set_system_state(&self, status: &SystemState);
+
+    fn take_operator_command(&self) -> Option<OperatorCommand>;
 }
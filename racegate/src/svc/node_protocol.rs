@@ -0,0 +1,633 @@
+use std::time::{Duration, Instant};
+
+use crate::app::gates::Gates;
+use crate::app::Roster;
+use crate::hal::gate::GateState;
+use crate::svc::activations::{ActivationReceiver, ActivationSender};
+use crate::svc::commands::{CommandReceiver, CommandSender};
+use crate::svc::race_node::{
+    ActivationAck, CommandAck, GateActivation, GateBeacon, GateCommand, GateDiagnostics,
+    NodeAddress, NodeStats, RaceNodeMessage, SyncRequest, SyncResponse, SystemId,
+};
+use crate::svc::{calculate_clock_sync, ClockSync, CoordinatedInstant};
+
+// This must be very strict (less than the acceptable error) because the application must switch
+// to clock dead reckoning.
+const COORDINATOR_BEACON_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Systems not heard for this time are not reported anymore
+const FOREIGN_SYSTEM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Period of clock synchronization requests sent by gates
+const SYNC_REQUEST_PERIOD: Duration = Duration::from_millis(200);
+
+/// What a node knows about the other nodes
+#[derive(Default)]
+pub(crate) struct NodesState {
+    coordinator_time: ExpOpt<CoordinatedInstant>,
+    coordinator_beacon_time: Option<Instant>,
+    /// Only on the coordinator, to timestamp sync requests
+    coordinator_clock: Option<CoordinatorClock>,
+    /// Only on gates
+    clock_sync: Option<ClockSync>,
+    /// Only on gates
+    sync_loss_percent: u8,
+    gates: Gates,
+    /// Other systems on the same network, with the last time they were heard
+    foreign_systems: Vec<(SystemId, Instant)>,
+    /// Only on gates
+    activation_sender: ActivationSender,
+    /// Only on the coordinator, indexed like gates
+    activation_receivers: Vec<ActivationReceiver>,
+    /// Only on the coordinator, activations not taken by the application yet
+    activations: Vec<GateActivation>,
+    /// Only on the coordinator
+    command_sender: CommandSender,
+    /// Only on gates
+    command_receiver: CommandReceiver,
+    /// Only on gates, commands not taken by the application yet
+    commands: Vec<GateCommand>,
+    /// Only on gates, diagnostics to send on the next poll
+    diagnostics: Option<GateDiagnostics>,
+    /// Counted by the node thread
+    stats: NodeStats,
+    /// Only on the coordinator
+    roster: Roster,
+}
+
+impl NodesState {
+    pub(crate) fn set_coordinator_time(&mut self, t: CoordinatedInstant, now: Instant) {
+        // This timeout must be very strict, because set_coordinator_time is
+        // called when the node is a coordinator.
+        const TIMEOUT: Duration = Duration::from_millis(100);
+
+        if let Some(expiration) = self.coordinator_time.expiration {
+            if now > expiration {
+                log::warn!("set_coordinator_time called too late");
+            }
+        }
+
+        self.coordinator_time = ExpOpt::<CoordinatedInstant>::new_with_duration(t, TIMEOUT, now);
+        self.coordinator_clock = Some(CoordinatorClock {
+            time: t,
+            instant: now,
+        });
+    }
+
+    pub(crate) fn coordinator_time(&self, now: Instant) -> Option<CoordinatedInstant> {
+        self.coordinator_time.into_option(now)
+    }
+
+    pub(crate) fn clock_sync(&self) -> Option<ClockSync> {
+        self.clock_sync
+    }
+
+    pub(crate) fn sync_loss_percent(&self) -> u8 {
+        self.sync_loss_percent
+    }
+
+    pub(crate) fn gates(&self) -> Gates {
+        self.gates.clone()
+    }
+
+    pub(crate) fn roster(&self) -> Roster {
+        self.roster.clone()
+    }
+
+    /// Send an activation of this gate, until the coordinator acknowledges it
+    pub(crate) fn send_activation(
+        &mut self,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+        continued: bool,
+        recovered: bool,
+    ) {
+        self.activation_sender
+            .push(addr, time, continued, recovered);
+    }
+
+    pub(crate) fn pending_activations(&self) -> usize {
+        self.activation_sender.pending_count()
+    }
+
+    pub(crate) fn take_activations(&mut self) -> Vec<GateActivation> {
+        std::mem::take(&mut self.activations)
+    }
+
+    /// Send a command to a gate, until the gate acknowledges it
+    pub(crate) fn send_command(&mut self, addr: NodeAddress, command: GateCommand) {
+        self.command_sender.push(addr, command);
+    }
+
+    pub(crate) fn take_commands(&mut self) -> Vec<GateCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub(crate) fn send_diagnostics(&mut self, diagnostics: GateDiagnostics) {
+        self.diagnostics = Some(diagnostics);
+    }
+
+    pub(crate) fn stats(&self) -> NodeStats {
+        self.stats
+    }
+
+    pub(crate) fn set_stats(&mut self, stats: NodeStats) {
+        self.stats = stats;
+    }
+
+    /// A frame from another system was received
+    pub(crate) fn on_foreign_frame(&mut self, system_id: SystemId, now: Instant) {
+        self.foreign_systems.retain(|x| x.0 != system_id);
+        self.foreign_systems.push((system_id, now));
+    }
+
+    pub(crate) fn foreign_systems(&self, now: Instant) -> Vec<SystemId> {
+        self.foreign_systems
+            .iter()
+            .filter(|x| now.saturating_duration_since(x.1) < FOREIGN_SYSTEM_TIMEOUT)
+            .map(|x| x.0)
+            .collect()
+    }
+
+    pub(crate) fn time_since_coordinator_beacon(&self, now: Instant) -> Duration {
+        self.coordinator_beacon_time
+            .and_then(|instant| now.checked_duration_since(instant))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// Handles the messages exchanged between nodes, whatever the network
+#[derive(Default)]
+pub(crate) struct NodeProtocol {
+    sync: SyncRequester,
+    /// Role and address from the last published beacon, kept while none is
+    /// available, e.g. before the first one or when the application is
+    /// replacing it
+    is_coordinator: bool,
+    gate_addr: Option<NodeAddress>,
+}
+
+impl NodeProtocol {
+    /// Messages to send periodically, starting from the one published by the
+    /// application
+    pub(crate) fn poll(
+        &mut self,
+        state: &mut NodesState,
+        tx_msg: Option<&RaceNodeMessage>,
+        now: Instant,
+    ) -> Vec<RaceNodeMessage> {
+        let mut messages: Vec<RaceNodeMessage> = tx_msg.into_iter().cloned().collect();
+
+        match tx_msg {
+            Some(RaceNodeMessage::CoordinatorBeacon(_)) => {
+                self.is_coordinator = true;
+                self.gate_addr = None;
+            }
+            Some(RaceNodeMessage::GateBeacon(beacon)) => {
+                self.is_coordinator = false;
+                self.gate_addr = Some(beacon.addr);
+            }
+            _ => {}
+        }
+
+        // The coordinator is in its own roster, to detect another coordinator
+        if let Some(RaceNodeMessage::CoordinatorBeacon(beacon)) = tx_msg {
+            state
+                .roster
+                .update(beacon.hardware_id, NodeAddress::coordinator(), beacon.time);
+        }
+
+        // Only gates synchronize their clock with the coordinator
+        if let Some(RaceNodeMessage::GateBeacon(beacon)) = tx_msg {
+            if let Some(request) = self.sync.request(beacon.addr, now) {
+                messages.push(request.into());
+                state.sync_loss_percent = self.sync.loss_percent();
+            }
+        }
+
+        if let Some(activation) = state.activation_sender.poll(now) {
+            messages.push(activation.into());
+        }
+
+        let commands = state.command_sender.poll(now);
+        messages.extend(commands.into_iter().map(RaceNodeMessage::from));
+
+        if let Some(diagnostics) = state.diagnostics.take() {
+            messages.push(diagnostics.into());
+        }
+
+        messages
+    }
+
+    /// Handle a message received at `rx_instant`, returning the response to
+    /// send, if any
+    pub(crate) fn receive(
+        &mut self,
+        state: &mut NodesState,
+        msg: RaceNodeMessage,
+        rx_instant: Instant,
+        now: Instant,
+    ) -> Option<RaceNodeMessage> {
+        match msg {
+            RaceNodeMessage::GateBeacon(beacon) => {
+                let coordinator_time = state.coordinator_time.into_option(rx_instant);
+                update_gate(&mut state.gates, &beacon, coordinator_time);
+
+                if let Some(time) = coordinator_time.filter(|_| self.is_coordinator) {
+                    state.roster.update(beacon.hardware_id, beacon.addr, time);
+                }
+            }
+            RaceNodeMessage::CoordinatorBeacon(beacon) => {
+                // Another coordinator, or this one heard back
+                if let Some(clock) = state.coordinator_clock.filter(|_| self.is_coordinator) {
+                    let time = clock.at(rx_instant);
+                    state
+                        .roster
+                        .update(beacon.hardware_id, NodeAddress::coordinator(), time);
+                }
+
+                state.coordinator_time = ExpOpt::<CoordinatedInstant>::new_with_duration(
+                    beacon.time,
+                    COORDINATOR_BEACON_TIMEOUT,
+                    rx_instant,
+                );
+                state.coordinator_beacon_time = Some(rx_instant);
+            }
+            RaceNodeMessage::SyncRequest(request) if self.is_coordinator => {
+                let rx_time = state.coordinator_clock?.at(rx_instant);
+
+                let response = SyncResponse {
+                    addr: request.addr,
+                    seq: request.seq,
+                    rx_time,
+                    turnaround: now.saturating_duration_since(rx_instant),
+                };
+
+                return Some(response.into());
+            }
+            RaceNodeMessage::SyncResponse(response) => {
+                if let Some(clock_sync) = self.sync.on_response(&response, rx_instant) {
+                    state.clock_sync = Some(clock_sync);
+                }
+            }
+            RaceNodeMessage::GateActivation(activation) if self.is_coordinator => {
+                receive_activation(state, activation);
+
+                let ack = ActivationAck {
+                    addr: activation.addr,
+                    seq: activation.seq,
+                };
+
+                return Some(ack.into());
+            }
+            RaceNodeMessage::ActivationAck(ack) => {
+                state.activation_sender.on_ack(&ack);
+            }
+            RaceNodeMessage::CoordinatorCommand(command)
+                if self.gate_addr == Some(command.addr) =>
+            {
+                if state.command_receiver.receive(command.seq) {
+                    state.commands.push(command.command);
+                } else {
+                    log::debug!("Duplicated command {:?}", command);
+                }
+
+                let ack = CommandAck {
+                    addr: command.addr,
+                    seq: command.seq,
+                };
+
+                return Some(ack.into());
+            }
+            RaceNodeMessage::CommandAck(ack) => {
+                state.command_sender.on_ack(&ack);
+            }
+            RaceNodeMessage::GateDiagnostics(diagnostics) if self.is_coordinator => {
+                if let Some(gate) = state.gates.get_mut_from_addr(diagnostics.addr) {
+                    gate.diagnostics = Some(diagnostics);
+                }
+            }
+            RaceNodeMessage::SyncRequest(_)
+            | RaceNodeMessage::GateActivation(_)
+            | RaceNodeMessage::CoordinatorCommand(_)
+            | RaceNodeMessage::GateDiagnostics(_) => {}
+        }
+
+        None
+    }
+}
+
+/// Coordinated time set by the coordinator application, extrapolated to
+/// timestamp received messages
+#[derive(Copy, Clone)]
+struct CoordinatorClock {
+    time: CoordinatedInstant,
+    instant: Instant,
+}
+
+impl CoordinatorClock {
+    fn at(&self, instant: Instant) -> CoordinatedInstant {
+        self.time + instant.saturating_duration_since(self.instant)
+    }
+}
+
+/// Sends periodic sync requests from a gate and matches the responses
+#[derive(Default)]
+struct SyncRequester {
+    seq: u16,
+    /// Last request sent, with the time it was sent
+    pending: Option<(SyncRequest, Instant)>,
+    /// A response to the last request was received
+    answered: bool,
+    /// One bit for each of the latest requests, set when it was lost
+    lost: u16,
+    /// Number of requests in `lost`
+    count: u32,
+}
+
+impl SyncRequester {
+    fn request(&mut self, addr: NodeAddress, now: Instant) -> Option<SyncRequest> {
+        let due = self
+            .pending
+            .map(|(_, sent)| now.saturating_duration_since(sent) >= SYNC_REQUEST_PERIOD)
+            .unwrap_or(true);
+
+        if !due {
+            return None;
+        }
+
+        if self.pending.is_some() {
+            self.lost = (self.lost << 1) | u16::from(!self.answered);
+            self.count = (self.count + 1).min(u16::BITS);
+        }
+
+        self.answered = false;
+        self.seq = self.seq.wrapping_add(1);
+
+        let request = SyncRequest {
+            addr,
+            seq: self.seq,
+        };
+
+        self.pending = Some((request, now));
+
+        Some(request)
+    }
+
+    fn on_response(&mut self, response: &SyncResponse, rx_instant: Instant) -> Option<ClockSync> {
+        let (request, sent) = self.pending?;
+
+        if request.addr != response.addr || request.seq != response.seq {
+            return None;
+        }
+
+        self.answered = true;
+
+        calculate_clock_sync(sent, rx_instant, response.rx_time, response.turnaround)
+    }
+
+    fn loss_percent(&self) -> u8 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        (self.lost.count_ones() * 100 / self.count) as u8
+    }
+}
+
+fn update_gate(gates: &mut Gates, gate: &GateBeacon, coordinated_time: Option<CoordinatedInstant>) {
+    let &GateBeacon {
+        addr,
+        state,
+        sync,
+        role,
+        ..
+    } = gate;
+    if let Some(gate) = gates.get_mut_from_addr(addr) {
+        gate.active = state == GateState::Active;
+        gate.last_beacon_time = coordinated_time;
+        gate.sync = sync;
+        gate.role = role;
+    }
+}
+
+/// Keep a new activation for the application, ignoring duplicates
+fn receive_activation(state: &mut NodesState, activation: GateActivation) {
+    let Some(index) = activation.addr.as_gate_index() else {
+        return;
+    };
+
+    let Some(gate) = state.gates.get_mut_from_addr(activation.addr) else {
+        return;
+    };
+
+    if index >= state.activation_receivers.len() {
+        state
+            .activation_receivers
+            .resize_with(index + 1, Default::default);
+    }
+
+    if state.activation_receivers[index].receive(activation.seq, &mut gate.activations) {
+        gate.last_activation_time = Some(activation.time);
+        state.activations.push(activation);
+    } else {
+        log::debug!("Duplicated activation {:?}", activation);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+struct ExpOpt<T> {
+    value: Option<T>,
+    expiration: Option<std::time::Instant>,
+}
+
+impl<T> ExpOpt<T> {
+    fn new_with_expiration(value: T, expiration: std::time::Instant) -> Self {
+        Self {
+            value: Some(value),
+            expiration: Some(expiration),
+        }
+    }
+    fn new_with_duration(value: T, duration: std::time::Duration, now: Instant) -> Self {
+        Self::new_with_expiration(value, now + duration)
+    }
+
+    fn into_option(self, now: Instant) -> Option<T> {
+        let expired = if let Some(expiration) = self.expiration {
+            expiration < now
+        } else {
+            false
+        };
+
+        if expired {
+            log::trace!(
+                "Expired {}ms ago",
+                now.duration_since(self.expiration.unwrap()).as_millis()
+            );
+            None
+        } else {
+            self.value
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::svc::race_node::{CoordinatorBeacon, FirmwareVersion, HardwareId};
+
+    use super::*;
+
+    #[test]
+    fn test_sync_requests_without_response_are_lost() {
+        let mut sync = SyncRequester::default();
+        let addr = NodeAddress::from(1);
+        let start = Instant::now();
+
+        let request = sync.request(addr, start).unwrap();
+        let response = SyncResponse {
+            addr,
+            seq: request.seq,
+            rx_time: CoordinatedInstant::from_millis(1_000),
+            turnaround: Duration::ZERO,
+        };
+        let rx_instant = start + Duration::from_millis(3);
+        assert!(sync.on_response(&response, rx_instant).is_some());
+
+        assert!(sync.request(addr, start + SYNC_REQUEST_PERIOD).is_some());
+        assert_eq!(sync.loss_percent(), 0);
+
+        assert!(sync
+            .request(addr, start + SYNC_REQUEST_PERIOD * 2)
+            .is_some());
+        assert_eq!(sync.loss_percent(), 50);
+    }
+
+    #[test]
+    fn test_coordinator_acknowledges_duplicated_activations() {
+        let mut state = NodesState::default();
+        let mut protocol = NodeProtocol::default();
+        let now = Instant::now();
+
+        let beacon = CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(1_000),
+            hardware_id: HardwareId::default(),
+        };
+        protocol.poll(&mut state, Some(&beacon.into()), now);
+
+        let activation = GateActivation {
+            addr: NodeAddress::from(1),
+            seq: 7,
+            time: CoordinatedInstant::from_millis(900),
+            continued: false,
+            recovered: false,
+        };
+        let ack = RaceNodeMessage::from(ActivationAck {
+            addr: activation.addr,
+            seq: activation.seq,
+        });
+
+        for _ in 0..2 {
+            let response = protocol.receive(&mut state, activation.into(), now, now);
+            assert_eq!(response, Some(ack.clone()));
+        }
+
+        assert_eq!(state.take_activations(), vec![activation]);
+
+        let gate = state.gates().get(activation.addr).cloned().unwrap();
+        assert_eq!(gate.activations.received, 1);
+        assert_eq!(gate.activations.duplicated, 1);
+    }
+
+    #[test]
+    fn test_coordinator_keeps_its_role_without_published_message() {
+        let mut state = NodesState::default();
+        let mut protocol = NodeProtocol::default();
+        let now = Instant::now();
+
+        let beacon = CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(1_000),
+            hardware_id: HardwareId::default(),
+        };
+        protocol.poll(&mut state, Some(&beacon.into()), now);
+
+        // The published message is not available, e.g. it is being replaced
+        protocol.poll(&mut state, None, now);
+
+        let activation = GateActivation {
+            addr: NodeAddress::from(1),
+            seq: 1,
+            time: CoordinatedInstant::from_millis(900),
+            continued: false,
+            recovered: false,
+        };
+        let response = protocol.receive(&mut state, activation.into(), now, now);
+        assert!(matches!(response, Some(RaceNodeMessage::ActivationAck(_))));
+        assert_eq!(state.take_activations(), vec![activation]);
+    }
+
+    #[test]
+    fn test_coordinator_keeps_gate_diagnostics() {
+        let mut state = NodesState::default();
+        let mut protocol = NodeProtocol::default();
+        let now = Instant::now();
+
+        let diagnostics = GateDiagnostics {
+            addr: NodeAddress::from(2),
+            firmware_version: FirmwareVersion::current(),
+            uptime: Duration::from_secs(60),
+            rssi: Some(-55),
+            supply_voltage_mv: None,
+            loop_overruns: 3,
+            dropped_gate_events: 0,
+            stats: NodeStats::default(),
+        };
+
+        // Sent once by the gate
+        state.send_diagnostics(diagnostics);
+        let messages = protocol.poll(&mut state, None, now);
+        assert_eq!(messages, vec![RaceNodeMessage::from(diagnostics)]);
+        assert!(protocol.poll(&mut state, None, now).is_empty());
+
+        let beacon = CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(1_000),
+            hardware_id: HardwareId::default(),
+        };
+        let mut state = NodesState::default();
+        protocol.poll(&mut state, Some(&beacon.into()), now);
+
+        let response = protocol.receive(&mut state, diagnostics.into(), now, now);
+        assert_eq!(response, None);
+
+        let gate = state.gates().get(diagnostics.addr).cloned().unwrap();
+        assert_eq!(gate.diagnostics, Some(diagnostics));
+    }
+
+    #[test]
+    fn test_coordinator_detects_another_coordinator() {
+        let mut state = NodesState::default();
+        let mut protocol = NodeProtocol::default();
+        let now = Instant::now();
+
+        let beacon = CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(1_000),
+            hardware_id: HardwareId([0, 0, 0, 0, 0, 1]),
+        };
+        state.set_coordinator_time(beacon.time, now);
+        protocol.poll(&mut state, Some(&beacon.into()), now);
+
+        // Its own beacon, heard back
+        protocol.receive(&mut state, beacon.into(), now, now);
+        assert_eq!(state.roster().entries().len(), 1);
+        assert!(!state.roster().has_conflicts());
+
+        let other = CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(7_000),
+            hardware_id: HardwareId([0, 0, 0, 0, 0, 2]),
+        };
+        protocol.receive(&mut state, other.into(), now, now);
+
+        let roster = state.roster();
+        assert_eq!(roster.conflicts().len(), 1);
+        assert_eq!(roster.conflicts()[0].addr, NodeAddress::coordinator());
+    }
+}
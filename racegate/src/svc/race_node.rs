@@ -1,11 +1,22 @@
 use crate::app::gates::Gates;
+use crate::app::{GateRole, Roster};
 use crate::hal::gate::GateState;
-use crate::svc::CoordinatedInstant;
+use crate::svc::{ClockSync, CoordinatedInstant};
 use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Error {
-    Unknown,
+    /// Not a race node frame, like other traffic on the same port
+    BadMagic,
+    /// Frame sent by a node with another protocol version
+    UnsupportedVersion(u8),
+    /// Frame corrupted on the way
+    BadChecksum,
+    UnknownMessage(u8),
+    /// A field has a value out of its range
+    InvalidField(&'static str),
+    /// The message doesn't fit the frame
+    Truncated,
 }
 
 pub trait RaceNode {
@@ -13,33 +24,104 @@ pub trait RaceNode {
 
     fn coordinator_time(&self) -> Option<CoordinatedInstant>;
 
+    /// Last round trip clock synchronization with the coordinator, only on
+    /// gates
+    fn clock_sync(&self) -> Option<ClockSync>;
+
+    /// Recent clock synchronization requests not answered by the
+    /// coordinator, in percent. Only on gates.
+    fn sync_loss_percent(&self) -> u8;
+
     fn publish(&self, msg: RaceNodeMessage) -> anyhow::Result<()>;
 
     fn gates(&self) -> Gates;
 
+    /// Nodes heard recently, only on the coordinator
+    fn roster(&self) -> Roster;
+
     fn time_since_coordinator_beacon(&self) -> Duration;
+
+    /// Other systems recently heard on the same network
+    fn foreign_systems(&self) -> Vec<SystemId>;
+
+    /// Report an activation of this gate to the coordinator. It is kept and
+    /// sent again until the coordinator acknowledges it.
+    fn send_activation(
+        &self,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+        continued: bool,
+        recovered: bool,
+    );
+
+    /// Activations of this gate not acknowledged by the coordinator yet
+    fn pending_activations(&self) -> usize;
+
+    /// Activations received by the coordinator since the last call, without
+    /// duplicates
+    fn take_activations(&self) -> Vec<GateActivation>;
+
+    /// Send a command from the coordinator to a gate. It is sent again until
+    /// the gate acknowledges it.
+    fn send_command(&self, addr: NodeAddress, command: GateCommand);
+
+    /// Commands received by this gate since the last call, without
+    /// duplicates
+    fn take_commands(&self) -> Vec<GateCommand>;
+
+    /// Frames sent and received by this node since it started
+    fn stats(&self) -> NodeStats;
+
+    /// Send diagnostics of this gate to the coordinator, once. They are not
+    /// acknowledged, the next ones replace them anyway.
+    fn send_diagnostics(&self, diagnostics: GateDiagnostics);
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Identifies the nodes of an installation, so many systems can share the
+/// same network
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SystemId(pub u16);
+
+impl SystemId {
+    pub fn from_env_var() -> Option<Self> {
+        option_env!("RACEGATE_SYSTEM_ID")?.parse().ok().map(Self)
+    }
+}
+
+/// Unique identifier of a node board, like its MAC address. Unlike the
+/// address, it can't be set by hand, so it tells nodes with the same address
+/// apart.
+#[derive(
+    Debug,
+    Default,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct HardwareId(pub [u8; 6]);
+
+impl std::fmt::Display for HardwareId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct NodeAddress(u8);
 
 const COORDINATOR_ADDRESS: NodeAddress = NodeAddress(0);
-const START_ADDRESS: NodeAddress = NodeAddress(1);
-const FINISH_ADDRESS: NodeAddress = NodeAddress(4);
 
 impl NodeAddress {
     pub const fn coordinator() -> Self {
         COORDINATOR_ADDRESS
     }
 
-    pub const fn start() -> Self {
-        START_ADDRESS
-    }
-
-    pub const fn finish() -> Self {
-        FINISH_ADDRESS
-    }
-
     pub const fn is_coordinator(&self) -> bool {
         self.0 == COORDINATOR_ADDRESS.0
     }
@@ -48,12 +130,8 @@ impl NodeAddress {
         self.0 != COORDINATOR_ADDRESS.0
     }
 
-    pub const fn is_start(&self) -> bool {
-        self.0 == START_ADDRESS.0
-    }
-
-    pub const fn is_finish(&self) -> bool {
-        self.0 == FINISH_ADDRESS.0
+    pub const fn from_gate_index(index: usize) -> Self {
+        Self((index + 1) as u8)
     }
 
     pub fn as_gate_index(&self) -> Option<usize> {
@@ -79,40 +157,275 @@ impl From<u8> for NodeAddress {
     }
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FrameData([u8; RaceNodeMessage::FRAME_SIZE]);
 
 impl FrameData {
     pub fn as_bytes(&self) -> &[u8] {
         self.0.as_slice()
     }
+
+    fn checked_bytes(&self) -> &[u8] {
+        &self.0[..CRC_OFFSET]
+    }
+
+    /// The frame is from a race node and not corrupted. Nothing else in the
+    /// frame can be trusted before this.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.0[0..2] != RaceNodeMessage::MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        // Checked before the version, so a corrupted version is not reported
+        // as a mismatch
+        if deserialize_crc(self) != crc16(self.checked_bytes()) {
+            return Err(Error::BadChecksum);
+        }
+
+        Ok(())
+    }
+
+    /// System of the sender
+    pub fn system_id(&self) -> SystemId {
+        SystemId(u16::from_be_bytes([self.0[3], self.0[4]]))
+    }
+
+    /// Set the system of the sender, before signing the frame
+    pub fn set_system_id(&mut self, system_id: SystemId) {
+        self.0[3..5].copy_from_slice(&system_id.0.to_be_bytes());
+        serialize_crc(self);
+    }
+
+    /// Bytes covered by the MAC: all but the MAC itself and the CRC
+    pub(crate) fn authenticated_bytes(&self) -> &[u8] {
+        &self.0[..MAC_OFFSET]
+    }
+
+    pub(crate) fn auth(&self) -> FrameAuth {
+        let u32_at = |x: usize| u32::from_be_bytes(self.0[x..(x + 4)].try_into().unwrap());
+
+        FrameAuth {
+            boot_id: u32_at(AUTH_OFFSET),
+            counter: u32_at(AUTH_OFFSET + 4),
+            mac: u64::from_be_bytes(self.0[MAC_OFFSET..CRC_OFFSET].try_into().unwrap()),
+        }
+    }
+
+    /// Set the sender and counter, to be followed by [`Self::set_mac`]
+    pub(crate) fn set_counter(&mut self, boot_id: u32, counter: u32) {
+        self.0[AUTH_OFFSET..(AUTH_OFFSET + 4)].copy_from_slice(&boot_id.to_be_bytes());
+        self.0[(AUTH_OFFSET + 4)..MAC_OFFSET].copy_from_slice(&counter.to_be_bytes());
+    }
+
+    pub(crate) fn set_mac(&mut self, mac: u64) {
+        self.0[MAC_OFFSET..CRC_OFFSET].copy_from_slice(&mac.to_be_bytes());
+        serialize_crc(self);
+    }
+}
+
+/// Fields authenticating a frame
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct FrameAuth {
+    /// Random number chosen by the sender at startup
+    pub boot_id: u32,
+    /// Incremented by the sender on each frame, against replays
+    pub counter: u32,
+    pub mac: u64,
 }
 
-impl From<[u8; 16]> for FrameData {
-    fn from(value: [u8; 16]) -> Self {
+impl From<[u8; RaceNodeMessage::FRAME_SIZE]> for FrameData {
+    fn from(value: [u8; RaceNodeMessage::FRAME_SIZE]) -> Self {
         Self(value)
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct GateBeacon {
     pub addr: NodeAddress,
+    pub hardware_id: HardwareId,
     pub state: GateState,
-    pub last_activation_time: Option<CoordinatedInstant>,
+    /// Clock synchronization, if the gate is synchronized
+    pub sync: Option<SyncQuality>,
+    /// Role set by the coordinator, if any
+    pub role: Option<GateRole>,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// How well a gate clock is synchronized with the coordinator
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SyncQuality {
+    /// Coordinated time minus local time, in microseconds
+    pub offset_us: i64,
+    /// Maximum error of the coordinated time
+    pub error: Duration,
+    /// Synchronization requests not answered by the coordinator, in percent
+    pub loss_percent: u8,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct CoordinatorBeacon {
     pub time: CoordinatedInstant,
+    pub hardware_id: HardwareId,
+}
+
+/// Sent by a gate to start a round trip clock synchronization
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SyncRequest {
+    pub addr: NodeAddress,
+    /// Matches the response with the request
+    pub seq: u16,
+}
+
+/// Sent by the coordinator as soon as a [SyncRequest] is received
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SyncResponse {
+    /// Address of the gate which sent the request
+    pub addr: NodeAddress,
+    pub seq: u16,
+    /// Coordinated time when the request was received
+    pub rx_time: CoordinatedInstant,
+    /// Time between request received and response sent
+    pub turnaround: Duration,
+}
+
+/// Beam interruption detected by a gate, sent until the coordinator
+/// acknowledges it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GateActivation {
+    pub addr: NodeAddress,
+    /// Incremented by the gate on each activation
+    pub seq: u16,
+    pub time: CoordinatedInstant,
+    /// The beam was already interrupted, so this is the end of the same
+    /// interruption
+    pub continued: bool,
+    /// Taken while the gate had lost the coordinator, and forwarded when the
+    /// link was restored
+    pub recovered: bool,
+}
+
+/// Sent by the coordinator for each [GateActivation] received, even if
+/// duplicated, because the previous acknowledgement may be lost
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ActivationAck {
+    pub addr: NodeAddress,
+    pub seq: u16,
+}
+
+/// What the coordinator can ask a gate to do
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum GateCommand {
+    /// Blink the LED, to find the gate on the course
+    Identify,
+    Reboot,
+    /// Tell the gate what it is used for in the course
+    SetRole {
+        role: GateRole,
+    },
+    /// Report activations again
+    Arm,
+    /// Stop reporting activations, e.g. while the course is set up
+    Disarm,
+    /// Show this color on the LED, or the gate state again when `None`
+    SetLed {
+        color: Option<u32>,
+    },
+}
+
+/// Command to a gate, sent by the coordinator until the gate acknowledges it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CoordinatorCommand {
+    /// Address of the gate
+    pub addr: NodeAddress,
+    /// Incremented by the coordinator on each command
+    pub seq: u16,
+    pub command: GateCommand,
+}
+
+/// Sent by a gate for each [CoordinatorCommand] received, even if duplicated
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CommandAck {
+    pub addr: NodeAddress,
+    pub seq: u16,
+}
+
+/// Version of the firmware running on a node
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl FirmwareVersion {
+    /// Version of this build
+    pub fn current() -> Self {
+        let parse = |x: &str| x.parse().unwrap_or_default();
+
+        Self {
+            major: parse(env!("CARGO_PKG_VERSION_MAJOR")),
+            minor: parse(env!("CARGO_PKG_VERSION_MINOR")),
+            patch: parse(env!("CARGO_PKG_VERSION_PATCH")),
+        }
+    }
+}
+
+impl std::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Frames sent and received by a node since it started
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NodeStats {
+    pub tx_count: u32,
+    pub rx_count: u32,
+    /// Frames dropped: corrupted, not authenticated, replayed, or of another
+    /// protocol version or system
+    pub rx_rejected_count: u32,
 }
 
-#[derive(Debug, Clone)]
+/// Health of a gate, sent at a low rate to the coordinator
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GateDiagnostics {
+    pub addr: NodeAddress,
+    pub firmware_version: FirmwareVersion,
+    /// Time since the gate started, in whole seconds
+    pub uptime: Duration,
+    /// Signal strength of the Wi-Fi access point, in dBm
+    pub rssi: Option<i8>,
+    /// Supply voltage, in millivolts, if the board can measure it
+    pub supply_voltage_mv: Option<u16>,
+    /// Application updates started late, because the previous one took too
+    /// long
+    pub loop_overruns: u32,
+    /// Gate state changes lost, because they were not taken in time
+    pub dropped_gate_events: u32,
+    pub stats: NodeStats,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum RaceNodeMessage {
     GateBeacon(GateBeacon),
     CoordinatorBeacon(CoordinatorBeacon),
+    SyncRequest(SyncRequest),
+    SyncResponse(SyncResponse),
+    GateActivation(GateActivation),
+    ActivationAck(ActivationAck),
+    CoordinatorCommand(CoordinatorCommand),
+    CommandAck(CommandAck),
+    GateDiagnostics(GateDiagnostics),
 }
 
 impl RaceNodeMessage {
-    pub const FRAME_SIZE: usize = 16;
+    pub const FRAME_SIZE: usize = 48;
+
+    /// Frames start with this, to tell them from other traffic
+    pub const MAGIC: [u8; 2] = *b"RG";
+
+    /// Nodes with different protocol versions can't talk. This must be
+    /// incremented on any change of the frame format.
+    pub const PROTOCOL_VERSION: u8 = 10;
 
     pub fn data(&self) -> FrameData {
         FrameData::from(self)
@@ -123,11 +436,24 @@ impl TryFrom<FrameData> for RaceNodeMessage {
     type Error = Error;
 
     fn try_from(data: FrameData) -> Result<RaceNodeMessage, Error> {
-        let msg_id = data.0.first().ok_or(Error::Unknown)?;
-        match msg_id {
+        data.check()?;
+
+        let version = data.0[2];
+        if version != RaceNodeMessage::PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        match deserialize_u8(&data, 0)? {
             1 => Ok(GateBeacon::try_from(data)?.into()),
             2 => Ok(CoordinatorBeacon::try_from(data)?.into()),
-            _ => Err(Error::Unknown),
+            3 => Ok(SyncRequest::try_from(data)?.into()),
+            4 => Ok(SyncResponse::try_from(data)?.into()),
+            5 => Ok(GateActivation::try_from(data)?.into()),
+            6 => Ok(ActivationAck::try_from(data)?.into()),
+            7 => Ok(CoordinatorCommand::try_from(data)?.into()),
+            8 => Ok(CommandAck::try_from(data)?.into()),
+            9 => Ok(GateDiagnostics::try_from(data)?.into()),
+            x => Err(Error::UnknownMessage(x)),
         }
     }
 }
@@ -136,27 +462,160 @@ impl TryFrom<FrameData> for GateBeacon {
     type Error = Error;
 
     fn try_from(data: FrameData) -> Result<GateBeacon, Error> {
-        let addr = data.0.get(1).ok_or(Error::Unknown)?;
-        let addr = NodeAddress(*addr);
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
 
-        let gate_state = match data.0.get(2) {
-            Some(0) => GateState::Inactive,
-            Some(1) => GateState::Active,
-            _ => GateState::Inactive,
+        let gate_state = match deserialize_u8(&data, 2)? {
+            0 => GateState::Inactive,
+            1 => GateState::Active,
+            _ => return Err(Error::InvalidField("gate state")),
         };
 
-        let last_activation_time = deserialize_u32(&data, 3).ok_or(Error::Unknown)?;
+        let offset_us = deserialize_u64(&data, 7)? as i64;
+        let loss_percent = deserialize_u8(&data, 15)?;
 
-        let last_activation_time = if last_activation_time == 0 {
-            None
-        } else {
-            Some(CoordinatedInstant::from_millis(last_activation_time as i32))
+        if loss_percent > 100 {
+            return Err(Error::InvalidField("loss percent"));
+        }
+
+        let sync = match deserialize_u32(&data, 3)? {
+            UNKNOWN_SYNC_ERROR => None,
+            x => Some(SyncQuality {
+                offset_us,
+                error: Duration::from_micros(x as u64),
+                loss_percent,
+            }),
         };
 
         Ok(GateBeacon {
             addr,
+            hardware_id: HardwareId(deserialize_bytes(&data, 18)?),
             state: gate_state,
-            last_activation_time,
+            sync,
+            role: deserialize_role(&data, 16)?,
+        })
+    }
+}
+
+impl TryFrom<FrameData> for GateActivation {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<GateActivation, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+        let seq = deserialize_u16(&data, 2)?;
+        let time = CoordinatedInstant::from_micros(deserialize_u64(&data, 4)? as i64);
+
+        let continued = match deserialize_u8(&data, 12)? {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::InvalidField("continued")),
+        };
+
+        let recovered = match deserialize_u8(&data, 13)? {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::InvalidField("recovered")),
+        };
+
+        Ok(GateActivation {
+            addr,
+            seq,
+            time,
+            continued,
+            recovered,
+        })
+    }
+}
+
+impl TryFrom<FrameData> for ActivationAck {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<ActivationAck, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+        let seq = deserialize_u16(&data, 2)?;
+        Ok(ActivationAck { addr, seq })
+    }
+}
+
+impl TryFrom<FrameData> for CoordinatorCommand {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<CoordinatorCommand, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+        let seq = deserialize_u16(&data, 2)?;
+
+        let command = match deserialize_u8(&data, 4)? {
+            1 => GateCommand::Identify,
+            2 => GateCommand::Reboot,
+            3 => {
+                let role = deserialize_role(&data, 5)?.ok_or(Error::InvalidField("role"))?;
+                GateCommand::SetRole { role }
+            }
+            4 => GateCommand::Arm,
+            5 => GateCommand::Disarm,
+            6 => {
+                let color = match deserialize_u8(&data, 5)? {
+                    0 => None,
+                    1 => Some(deserialize_u32(&data, 6)?),
+                    _ => return Err(Error::InvalidField("color")),
+                };
+                GateCommand::SetLed { color }
+            }
+            _ => return Err(Error::InvalidField("command")),
+        };
+
+        Ok(CoordinatorCommand { addr, seq, command })
+    }
+}
+
+impl TryFrom<FrameData> for CommandAck {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<CommandAck, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+        let seq = deserialize_u16(&data, 2)?;
+        Ok(CommandAck { addr, seq })
+    }
+}
+
+impl TryFrom<FrameData> for GateDiagnostics {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<GateDiagnostics, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+
+        let firmware_version = FirmwareVersion {
+            major: deserialize_u8(&data, 2)?,
+            minor: deserialize_u8(&data, 3)?,
+            patch: deserialize_u8(&data, 4)?,
+        };
+
+        let uptime = Duration::from_secs(deserialize_u32(&data, 5)? as u64);
+
+        let rssi = match deserialize_u8(&data, 9)? as i8 {
+            UNKNOWN_RSSI => None,
+            x => Some(x),
+        };
+
+        let supply_voltage_mv = match deserialize_u16(&data, 10)? {
+            0 => None,
+            x => Some(x),
+        };
+
+        let stats = NodeStats {
+            tx_count: deserialize_u32(&data, 14)?,
+            rx_count: deserialize_u32(&data, 18)?,
+            rx_rejected_count: deserialize_u16(&data, 22)? as u32,
+        };
+
+        Ok(GateDiagnostics {
+            addr,
+            firmware_version,
+            uptime,
+            rssi,
+            supply_voltage_mv,
+            loop_overruns: deserialize_u16(&data, 12)? as u32,
+            dropped_gate_events: deserialize_u8(&data, 24)? as u32,
+            stats,
         })
     }
 }
@@ -165,11 +624,39 @@ impl TryFrom<FrameData> for CoordinatorBeacon {
     type Error = Error;
 
     fn try_from(data: FrameData) -> Result<CoordinatorBeacon, Error> {
-        let time = CoordinatedInstant::from_millis(
-            deserialize_u32(&data, 1).ok_or(Error::Unknown)? as i32,
-        );
+        let time = CoordinatedInstant::from_micros(deserialize_u64(&data, 1)? as i64);
+
+        let hardware_id = HardwareId(deserialize_bytes(&data, 9)?);
+
+        Ok(CoordinatorBeacon { time, hardware_id })
+    }
+}
 
-        Ok(CoordinatorBeacon { time })
+impl TryFrom<FrameData> for SyncRequest {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<SyncRequest, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+        let seq = deserialize_u16(&data, 2)?;
+        Ok(SyncRequest { addr, seq })
+    }
+}
+
+impl TryFrom<FrameData> for SyncResponse {
+    type Error = Error;
+
+    fn try_from(data: FrameData) -> Result<SyncResponse, Error> {
+        let addr = NodeAddress(deserialize_u8(&data, 1)?);
+        let seq = deserialize_u16(&data, 2)?;
+        let rx_time = deserialize_u64(&data, 4)?;
+        let turnaround = deserialize_u32(&data, 12)?;
+
+        Ok(SyncResponse {
+            addr,
+            seq,
+            rx_time: CoordinatedInstant::from_micros(rx_time as i64),
+            turnaround: Duration::from_micros(turnaround as u64),
+        })
     }
 }
 
@@ -185,56 +672,306 @@ impl From<GateBeacon> for RaceNodeMessage {
     }
 }
 
+impl From<SyncRequest> for RaceNodeMessage {
+    fn from(x: SyncRequest) -> Self {
+        RaceNodeMessage::SyncRequest(x)
+    }
+}
+
+impl From<SyncResponse> for RaceNodeMessage {
+    fn from(x: SyncResponse) -> Self {
+        RaceNodeMessage::SyncResponse(x)
+    }
+}
+
+impl From<GateActivation> for RaceNodeMessage {
+    fn from(x: GateActivation) -> Self {
+        RaceNodeMessage::GateActivation(x)
+    }
+}
+
+impl From<ActivationAck> for RaceNodeMessage {
+    fn from(x: ActivationAck) -> Self {
+        RaceNodeMessage::ActivationAck(x)
+    }
+}
+
+impl From<CoordinatorCommand> for RaceNodeMessage {
+    fn from(x: CoordinatorCommand) -> Self {
+        RaceNodeMessage::CoordinatorCommand(x)
+    }
+}
+
+impl From<CommandAck> for RaceNodeMessage {
+    fn from(x: CommandAck) -> Self {
+        RaceNodeMessage::CommandAck(x)
+    }
+}
+
+impl From<GateDiagnostics> for RaceNodeMessage {
+    fn from(x: GateDiagnostics) -> Self {
+        RaceNodeMessage::GateDiagnostics(x)
+    }
+}
+
+/// Magic, protocol version and system id
+const HEADER_SIZE: usize = 5;
+
+/// The frame ends with the CRC of all the previous bytes
+const CRC_OFFSET: usize = RaceNodeMessage::FRAME_SIZE - 2;
+
+/// Messages are followed by boot id, counter and MAC
+const AUTH_OFFSET: usize = MAC_OFFSET - 8;
+
+const MAC_OFFSET: usize = CRC_OFFSET - 8;
+
+/// Encoded sync error when the gate is not synchronized
+const UNKNOWN_SYNC_ERROR: u32 = u32::MAX;
+
+/// Encoded RSSI when the gate is not connected to an access point
+const UNKNOWN_RSSI: i8 = i8::MIN;
+
 fn serialize_system_state(x: &GateBeacon, data: &mut FrameData) {
-    data.0[1] = x.addr.0;
-    data.0[2] = x.state as u8;
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u8(x.state as u8, data, 2);
+
+    let sync_error = x
+        .sync
+        .map(|x| x.error.as_micros().min(UNKNOWN_SYNC_ERROR as u128 - 1) as u32)
+        .unwrap_or(UNKNOWN_SYNC_ERROR);
+    serialize_u32(sync_error, data, 3);
 
-    if let Some(last_activation_time) = x.last_activation_time {
-        serialize_u32(last_activation_time.as_millis() as u32, data, 3);
-    } else {
-        serialize_u32(0, data, 3);
+    if let Some(sync) = x.sync {
+        serialize_u64(sync.offset_us as u64, data, 7);
+        serialize_u8(sync.loss_percent, data, 15);
     }
+
+    serialize_role(x.role, data, 16);
+    serialize_bytes(&x.hardware_id.0, data, 18);
+}
+
+/// Role id, followed by the split number
+fn serialize_role(x: Option<GateRole>, data: &mut FrameData, offset: usize) {
+    let (role_id, split) = match x {
+        None => (0, 0),
+        Some(GateRole::Start) => (1, 0),
+        Some(GateRole::Split(n)) => (2, n),
+        Some(GateRole::Finish) => (3, 0),
+        Some(GateRole::StartFinish) => (4, 0),
+        Some(GateRole::Lap) => (5, 0),
+    };
+
+    serialize_u8(role_id, data, offset);
+    serialize_u8(split, data, offset + 1);
+}
+
+fn deserialize_role(data: &FrameData, offset: usize) -> Result<Option<GateRole>, Error> {
+    let role = match deserialize_u8(data, offset)? {
+        0 => None,
+        1 => Some(GateRole::Start),
+        2 => Some(GateRole::Split(deserialize_u8(data, offset + 1)?)),
+        3 => Some(GateRole::Finish),
+        4 => Some(GateRole::StartFinish),
+        5 => Some(GateRole::Lap),
+        _ => return Err(Error::InvalidField("role")),
+    };
+
+    Ok(role)
+}
+
+fn serialize_gate_activation(x: &GateActivation, data: &mut FrameData) {
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u16(x.seq, data, 2);
+    serialize_u64(x.time.as_micros() as u64, data, 4);
+    serialize_u8(x.continued as u8, data, 12);
+    serialize_u8(x.recovered as u8, data, 13);
+}
+
+fn serialize_activation_ack(x: &ActivationAck, data: &mut FrameData) {
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u16(x.seq, data, 2);
+}
+
+fn serialize_coordinator_command(x: &CoordinatorCommand, data: &mut FrameData) {
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u16(x.seq, data, 2);
+
+    match x.command {
+        GateCommand::Identify => serialize_u8(1, data, 4),
+        GateCommand::Reboot => serialize_u8(2, data, 4),
+        GateCommand::SetRole { role } => {
+            serialize_u8(3, data, 4);
+            serialize_role(Some(role), data, 5);
+        }
+        GateCommand::Arm => serialize_u8(4, data, 4),
+        GateCommand::Disarm => serialize_u8(5, data, 4),
+        GateCommand::SetLed { color } => {
+            serialize_u8(6, data, 4);
+            serialize_u8(color.is_some() as u8, data, 5);
+            serialize_u32(color.unwrap_or_default(), data, 6);
+        }
+    }
+}
+
+fn serialize_command_ack(x: &CommandAck, data: &mut FrameData) {
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u16(x.seq, data, 2);
+}
+
+/// Counters are saturated to fit the frame
+fn serialize_gate_diagnostics(x: &GateDiagnostics, data: &mut FrameData) {
+    let saturate_u8 = |x: u32| x.min(u8::MAX as u32) as u8;
+    let saturate_u16 = |x: u32| x.min(u16::MAX as u32) as u16;
+
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u8(x.firmware_version.major, data, 2);
+    serialize_u8(x.firmware_version.minor, data, 3);
+    serialize_u8(x.firmware_version.patch, data, 4);
+    serialize_u32(x.uptime.as_secs().min(u32::MAX as u64) as u32, data, 5);
+    serialize_u8(x.rssi.unwrap_or(UNKNOWN_RSSI) as u8, data, 9);
+    serialize_u16(x.supply_voltage_mv.unwrap_or_default(), data, 10);
+    serialize_u16(saturate_u16(x.loop_overruns), data, 12);
+    serialize_u32(x.stats.tx_count, data, 14);
+    serialize_u32(x.stats.rx_count, data, 18);
+    serialize_u16(saturate_u16(x.stats.rx_rejected_count), data, 22);
+    serialize_u8(saturate_u8(x.dropped_gate_events), data, 24);
 }
 
 fn serialize_coordinator_beacon(x: &CoordinatorBeacon, data: &mut FrameData) {
-    serialize_u32(x.time.as_millis() as u32, data, 1);
+    serialize_u64(x.time.as_micros() as u64, data, 1);
+    serialize_bytes(&x.hardware_id.0, data, 9);
+}
+
+fn serialize_sync_request(x: &SyncRequest, data: &mut FrameData) {
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u16(x.seq, data, 2);
+}
+
+fn serialize_sync_response(x: &SyncResponse, data: &mut FrameData) {
+    serialize_u8(x.addr.0, data, 1);
+    serialize_u16(x.seq, data, 2);
+    serialize_u64(x.rx_time.as_micros() as u64, data, 4);
+    let turnaround = x.turnaround.as_micros().min(u32::MAX as u128) as u32;
+    serialize_u32(turnaround, data, 12);
 }
 
 fn serialize_msg_id(msg: &RaceNodeMessage, data: &mut FrameData) {
     let msg_id = match msg {
         RaceNodeMessage::GateBeacon(_) => 1,
         RaceNodeMessage::CoordinatorBeacon(_) => 2,
+        RaceNodeMessage::SyncRequest(_) => 3,
+        RaceNodeMessage::SyncResponse(_) => 4,
+        RaceNodeMessage::GateActivation(_) => 5,
+        RaceNodeMessage::ActivationAck(_) => 6,
+        RaceNodeMessage::CoordinatorCommand(_) => 7,
+        RaceNodeMessage::CommandAck(_) => 8,
+        RaceNodeMessage::GateDiagnostics(_) => 9,
     };
 
-    data.0[0] = msg_id;
+    serialize_u8(msg_id, data, 0);
+}
+
+fn serialize_header(data: &mut FrameData) {
+    data.0[0..2].copy_from_slice(&RaceNodeMessage::MAGIC);
+    data.0[2] = RaceNodeMessage::PROTOCOL_VERSION;
+}
+
+fn serialize_crc(data: &mut FrameData) {
+    let crc = crc16(data.checked_bytes());
+    data.0[CRC_OFFSET..].copy_from_slice(&crc.to_be_bytes());
+}
+
+fn deserialize_crc(data: &FrameData) -> u16 {
+    u16::from_be_bytes([data.0[CRC_OFFSET], data.0[CRC_OFFSET + 1]])
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Messages are serialized after the header. Offsets of the serialize and
+/// deserialize functions are relative to the message, whose first byte is the
+/// message id.
+fn message_range(offset: usize, len: usize) -> std::ops::Range<usize> {
+    (HEADER_SIZE + offset)..(HEADER_SIZE + offset + len)
+}
+
+fn serialize_bytes(bytes: &[u8], data: &mut FrameData, offset: usize) {
+    data.0[message_range(offset, bytes.len())].copy_from_slice(bytes);
+}
+
+fn deserialize_bytes<const N: usize>(data: &FrameData, offset: usize) -> Result<[u8; N], Error> {
+    let range = message_range(offset, N);
+
+    if range.end > AUTH_OFFSET {
+        return Err(Error::Truncated);
+    }
+
+    Ok(data.0[range].try_into().unwrap())
+}
+
+fn serialize_u8(x: u8, data: &mut FrameData, offset: usize) {
+    serialize_bytes(&[x], data, offset);
+}
+
+fn deserialize_u8(data: &FrameData, offset: usize) -> Result<u8, Error> {
+    Ok(deserialize_bytes::<1>(data, offset)?[0])
+}
+
+fn serialize_u16(x: u16, data: &mut FrameData, offset: usize) {
+    serialize_bytes(&x.to_be_bytes(), data, offset);
+}
+
+fn deserialize_u16(data: &FrameData, offset: usize) -> Result<u16, Error> {
+    Ok(u16::from_be_bytes(deserialize_bytes(data, offset)?))
 }
 
 fn serialize_u32(x: u32, data: &mut FrameData, offset: usize) {
-    data.0[offset] = ((x >> 24) & 0xFF) as u8;
-    data.0[offset + 1] = ((x >> 16) & 0xFF) as u8;
-    data.0[offset + 2] = ((x >> 8) & 0xFF) as u8;
-    data.0[offset + 3] = ((x) & 0xFF) as u8;
+    serialize_bytes(&x.to_be_bytes(), data, offset);
+}
+
+fn deserialize_u32(data: &FrameData, offset: usize) -> Result<u32, Error> {
+    Ok(u32::from_be_bytes(deserialize_bytes(data, offset)?))
 }
 
-fn deserialize_u32(data: &FrameData, offset: usize) -> Option<u32> {
-    let d0 = *data.0.get(offset)?;
-    let d1 = *data.0.get(offset + 1)?;
-    let d2 = *data.0.get(offset + 2)?;
-    let d3 = *data.0.get(offset + 3)?;
-    Some(((d0 as u32) << 24) | ((d1 as u32) << 16) | ((d2 as u32) << 8) | (d3 as u32))
+fn serialize_u64(x: u64, data: &mut FrameData, offset: usize) {
+    serialize_bytes(&x.to_be_bytes(), data, offset);
+}
+
+fn deserialize_u64(data: &FrameData, offset: usize) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(deserialize_bytes(data, offset)?))
 }
 
 impl From<&RaceNodeMessage> for FrameData {
     fn from(msg: &RaceNodeMessage) -> Self {
-        let mut data = FrameData::from([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut data = FrameData::from([0; RaceNodeMessage::FRAME_SIZE]);
 
+        serialize_header(&mut data);
         serialize_msg_id(msg, &mut data);
 
         match msg {
             RaceNodeMessage::GateBeacon(x) => serialize_system_state(x, &mut data),
             RaceNodeMessage::CoordinatorBeacon(x) => serialize_coordinator_beacon(x, &mut data),
+            RaceNodeMessage::SyncRequest(x) => serialize_sync_request(x, &mut data),
+            RaceNodeMessage::SyncResponse(x) => serialize_sync_response(x, &mut data),
+            RaceNodeMessage::GateActivation(x) => serialize_gate_activation(x, &mut data),
+            RaceNodeMessage::ActivationAck(x) => serialize_activation_ack(x, &mut data),
+            RaceNodeMessage::CoordinatorCommand(x) => serialize_coordinator_command(x, &mut data),
+            RaceNodeMessage::CommandAck(x) => serialize_command_ack(x, &mut data),
+            RaceNodeMessage::GateDiagnostics(x) => serialize_gate_diagnostics(x, &mut data),
         };
 
+        serialize_crc(&mut data);
+
         data
     }
 }
@@ -250,9 +987,15 @@ mod tests {
     #[test]
     fn test_serialize_system_state() {
         let x = GateBeacon {
-            addr: NodeAddress::start(),
+            addr: NodeAddress::from(1),
+            hardware_id: HardwareId([0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]),
             state: GateState::Active,
-            last_activation_time: Some(CoordinatedInstant::from_millis(12345)),
+            sync: Some(SyncQuality {
+                offset_us: -1_234_567,
+                error: Duration::from_micros(420),
+                loss_percent: 5,
+            }),
+            role: Some(GateRole::Split(2)),
         };
 
         let msg = RaceNodeMessage::GateBeacon(x);
@@ -266,6 +1009,7 @@ mod tests {
     fn test_serialize_coordinator_beacon() {
         let x = CoordinatorBeacon {
             time: CoordinatedInstant::from_millis(2_123_456_789),
+            hardware_id: HardwareId([0x24, 0x0a, 0xc4, 0xab, 0xcd, 0xef]),
         };
 
         let msg = RaceNodeMessage::CoordinatorBeacon(x);
@@ -274,4 +1018,231 @@ mod tests {
         assert_debug_snapshot!(data.as_bytes());
         assert_debug_snapshot!(RaceNodeMessage::try_from(data).unwrap());
     }
+
+    #[test]
+    fn test_serialize_sync_request() {
+        let x = SyncRequest {
+            addr: NodeAddress::from(3),
+            seq: 513,
+        };
+
+        let data = RaceNodeMessage::from(x).data();
+
+        match RaceNodeMessage::try_from(data) {
+            Ok(RaceNodeMessage::SyncRequest(y)) => assert_eq!(x, y),
+            _ => panic!(),
+        }
+    }
+
+    fn make_frame() -> FrameData {
+        RaceNodeMessage::from(SyncRequest {
+            addr: NodeAddress::from(3),
+            seq: 513,
+        })
+        .data()
+    }
+
+    #[test]
+    fn test_frame_from_other_traffic_is_rejected() {
+        let mut data = make_frame();
+        data.0[0] = 1;
+        serialize_crc(&mut data);
+
+        assert_eq!(RaceNodeMessage::try_from(data), Err(Error::BadMagic));
+    }
+
+    #[test]
+    fn test_corrupted_frame_is_rejected() {
+        let mut data = make_frame();
+        data.0[HEADER_SIZE + 2] ^= 0x10;
+
+        assert_eq!(RaceNodeMessage::try_from(data), Err(Error::BadChecksum));
+    }
+
+    #[test]
+    fn test_frame_with_other_version_is_rejected() {
+        let older = RaceNodeMessage::PROTOCOL_VERSION - 1;
+        let newer = RaceNodeMessage::PROTOCOL_VERSION + 1;
+
+        for version in [older, newer] {
+            let mut data = make_frame();
+            data.0[2] = version;
+            serialize_crc(&mut data);
+
+            assert_eq!(
+                RaceNodeMessage::try_from(data),
+                Err(Error::UnsupportedVersion(version))
+            );
+        }
+    }
+
+    #[test]
+    fn test_system_id_is_in_the_header() {
+        let mut data = make_frame();
+        assert_eq!(data.system_id(), SystemId::default());
+
+        data.set_system_id(SystemId(0x1234));
+        assert_eq!(data.system_id(), SystemId(0x1234));
+        assert!(RaceNodeMessage::try_from(data).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_fields_are_rejected() {
+        let mut data = make_frame();
+        serialize_u8(10, &mut data, 0);
+        serialize_crc(&mut data);
+        assert_eq!(
+            RaceNodeMessage::try_from(data),
+            Err(Error::UnknownMessage(10))
+        );
+
+        let mut data = RaceNodeMessage::from(GateBeacon {
+            addr: NodeAddress::from(1),
+            hardware_id: HardwareId::default(),
+            state: GateState::Active,
+            sync: None,
+            role: None,
+        })
+        .data();
+        serialize_u8(7, &mut data, 2);
+        serialize_crc(&mut data);
+        assert_eq!(
+            RaceNodeMessage::try_from(data),
+            Err(Error::InvalidField("gate state"))
+        );
+    }
+
+    #[test]
+    fn test_serialize_gate_activation() {
+        let x = GateActivation {
+            addr: NodeAddress::from(4),
+            seq: 65_000,
+            time: CoordinatedInstant::from_micros(2_123_456_789_012),
+            continued: true,
+            recovered: true,
+        };
+
+        match RaceNodeMessage::try_from(RaceNodeMessage::from(x).data()) {
+            Ok(RaceNodeMessage::GateActivation(y)) => assert_eq!(x, y),
+            _ => panic!(),
+        }
+
+        let x = ActivationAck {
+            addr: NodeAddress::from(4),
+            seq: 65_000,
+        };
+
+        match RaceNodeMessage::try_from(RaceNodeMessage::from(x).data()) {
+            Ok(RaceNodeMessage::ActivationAck(y)) => assert_eq!(x, y),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_serialize_coordinator_command() {
+        let commands = [
+            GateCommand::Identify,
+            GateCommand::Reboot,
+            GateCommand::SetRole {
+                role: GateRole::Split(3),
+            },
+            GateCommand::Arm,
+            GateCommand::Disarm,
+            GateCommand::SetLed {
+                color: Some(0xFF8800),
+            },
+            GateCommand::SetLed { color: None },
+        ];
+
+        for command in commands {
+            let x = CoordinatorCommand {
+                addr: NodeAddress::from(2),
+                seq: 1_234,
+                command,
+            };
+
+            match RaceNodeMessage::try_from(RaceNodeMessage::from(x).data()) {
+                Ok(RaceNodeMessage::CoordinatorCommand(y)) => assert_eq!(x, y),
+                _ => panic!(),
+            }
+        }
+
+        let x = CommandAck {
+            addr: NodeAddress::from(2),
+            seq: 1_234,
+        };
+
+        match RaceNodeMessage::try_from(RaceNodeMessage::from(x).data()) {
+            Ok(RaceNodeMessage::CommandAck(y)) => assert_eq!(x, y),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_serialize_gate_diagnostics() {
+        let x = GateDiagnostics {
+            addr: NodeAddress::from(3),
+            firmware_version: FirmwareVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            },
+            uptime: Duration::from_secs(86_400),
+            rssi: Some(-67),
+            supply_voltage_mv: Some(4_950),
+            loop_overruns: 12,
+            dropped_gate_events: 2,
+            stats: NodeStats {
+                tx_count: 1_000_000,
+                rx_count: 2_000_000,
+                rx_rejected_count: 42,
+            },
+        };
+
+        let data = RaceNodeMessage::from(x).data();
+        insta::assert_debug_snapshot!(data.as_bytes());
+
+        match RaceNodeMessage::try_from(data) {
+            Ok(RaceNodeMessage::GateDiagnostics(y)) => assert_eq!(x, y),
+            _ => panic!(),
+        }
+
+        // Unknown values and saturated counters
+        let x = GateDiagnostics {
+            rssi: None,
+            supply_voltage_mv: None,
+            loop_overruns: 100_000,
+            dropped_gate_events: 1_000,
+            ..x
+        };
+
+        match RaceNodeMessage::try_from(RaceNodeMessage::from(x).data()) {
+            Ok(RaceNodeMessage::GateDiagnostics(y)) => {
+                assert_eq!(y.rssi, None);
+                assert_eq!(y.supply_voltage_mv, None);
+                assert_eq!(y.loop_overruns, u16::MAX as u32);
+                assert_eq!(y.dropped_gate_events, u8::MAX as u32);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_serialize_sync_response() {
+        let x = SyncResponse {
+            addr: NodeAddress::from(3),
+            seq: 513,
+            rx_time: CoordinatedInstant::from_micros(2_123_456_789_012),
+            turnaround: Duration::from_micros(345),
+        };
+
+        let data = RaceNodeMessage::from(x).data();
+
+        assert_debug_snapshot!(data.as_bytes());
+
+        match RaceNodeMessage::try_from(data) {
+            Ok(RaceNodeMessage::SyncResponse(y)) => assert_eq!(x, y),
+            _ => panic!(),
+        }
+    }
 }
@@ -1,25 +1,66 @@
-use std::net::{SocketAddr, UdpSocket};
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::{sleep, JoinHandle};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 
 use crate::app::gates::Gates;
-use crate::hal::gate::GateState;
-use crate::svc::race_node::{FrameData, GateBeacon, RaceNode, RaceNodeMessage};
-use crate::svc::CoordinatedInstant;
-
-// This must be very strict (less than the acceptable error) because the application must switch
-// to clock dead reckoning.
-const COORDINATOR_BEACON_TIMEOUT: Duration = Duration::from_millis(50);
+use crate::app::Roster;
+use crate::hal::clock::{Clock, SystemClock};
+use crate::svc::auth::{AuthError, Authenticator, NetworkKey};
+use crate::svc::node_protocol::{NodeProtocol, NodesState};
+use crate::svc::race_node::{
+    Error, GateActivation, GateCommand, GateDiagnostics, NodeAddress, NodeStats, RaceNode,
+    RaceNodeMessage, SystemId,
+};
+use crate::svc::transport::{Transport, UdpBroadcastTransport};
+use crate::svc::{ClockSync, CoordinatedInstant};
+
+/// Clock shared with the node thread
+pub type SharedClock = Arc<dyn Clock + Send + Sync>;
 
 #[derive(Default, Debug)]
 struct Stats {
     tx_count: usize,
     rx_count: usize,
+    /// Frames that can't be parsed
+    rx_error_count: usize,
+    /// Frames from nodes with another protocol version
+    rx_version_mismatch_count: usize,
+    /// Frames not signed with the network key, maybe forged
+    rx_unauthenticated_count: usize,
+    /// Frames already received
+    rx_replay_count: usize,
+    /// Frames from other systems on the same network
+    rx_foreign_count: usize,
+}
+
+impl Stats {
+    /// Totals reported in the gate diagnostics
+    fn summary(&self) -> NodeStats {
+        let rx_rejected_count = self.rx_error_count
+            + self.rx_version_mismatch_count
+            + self.rx_unauthenticated_count
+            + self.rx_replay_count
+            + self.rx_foreign_count;
+
+        NodeStats {
+            tx_count: self.tx_count as u32,
+            rx_count: self.rx_count as u32,
+            rx_rejected_count: rx_rejected_count as u32,
+        }
+    }
+}
+
+/// Settings shared by all the nodes of a system
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NodeConfig {
+    /// Only frames signed with this key are accepted
+    pub key: NetworkKey,
+    /// Frames of other systems are ignored
+    pub system_id: SystemId,
 }
 
 pub struct StdRaceNode {
@@ -29,59 +70,52 @@ pub struct StdRaceNode {
     continue_running: Arc<AtomicBool>,
     // Note: not using mpsc because it causes weird bugs (maybe esp-idf implementation is buggy)
     tx: Arc<Mutex<Option<RaceNodeMessage>>>,
-}
-
-struct StdRaceNodeConfig {
-    sender_addr: SocketAddr,
-    receiver_addr: SocketAddr,
-    broadcast_addr: SocketAddr,
-}
-
-impl Default for StdRaceNodeConfig {
-    fn default() -> Self {
-        Self {
-            sender_addr: "0.0.0.0:0".parse().unwrap(),
-            receiver_addr: "0.0.0.0:6699".parse().unwrap(),
-            broadcast_addr: "255.255.255.255:6699".parse().unwrap(),
-        }
-    }
+    clock: SharedClock,
 }
 
 impl StdRaceNode {
-    pub fn new() -> anyhow::Result<Self> {
-        Self::new_with_config(StdRaceNodeConfig::default())
+    /// Node on UDP broadcast, with the system clock
+    pub fn new(config: NodeConfig) -> anyhow::Result<Self> {
+        Self::new_with_clock(config, Arc::new(SystemClock))
     }
 
-    fn new_with_config(config: StdRaceNodeConfig) -> anyhow::Result<Self> {
-        let StdRaceNodeConfig {
-            sender_addr,
-            receiver_addr,
-            broadcast_addr,
-        } = config;
+    /// Messages and timeouts are timestamped with `clock`, which must be the
+    /// same clock used by the application
+    pub fn new_with_clock(config: NodeConfig, clock: SharedClock) -> anyhow::Result<Self> {
+        Ok(Self::new_with_transport(
+            Box::new(UdpBroadcastTransport::new()?),
+            config,
+            clock,
+        ))
+    }
 
+    pub fn new_with_transport(
+        transport: Box<dyn Transport>,
+        config: NodeConfig,
+        clock: SharedClock,
+    ) -> Self {
         let state = SharedNodeState::default();
 
         log::info!("Starting race node");
 
-        let sender = make_sender(sender_addr)?;
-        let receiver = make_receiver(receiver_addr)?;
-
         let continue_running = Arc::new(AtomicBool::new(true));
 
         let (thread, tx) = spawn_thread(
-            broadcast_addr,
+            transport,
+            config.system_id,
+            Authenticator::new(config.key),
             state.clone(),
-            sender,
-            receiver,
             continue_running.clone(),
+            clock.clone(),
         );
 
-        Ok(StdRaceNode {
+        StdRaceNode {
             thread: Some(thread),
             state,
             continue_running,
             tx,
-        })
+            clock,
+        }
     }
 
     fn stop(&mut self) -> Option<Stats> {
@@ -104,11 +138,12 @@ impl Drop for StdRaceNode {
 }
 
 fn spawn_thread(
-    broadcast_addr: SocketAddr,
+    mut transport: Box<dyn Transport>,
+    system_id: SystemId,
+    mut auth: Authenticator,
     state: SharedNodeState,
-    sender: UdpSocket,
-    mut receiver: UdpSocket,
     continue_running: Arc<AtomicBool>,
+    clock: SharedClock,
 ) -> (JoinHandle<Stats>, Arc<Mutex<Option<RaceNodeMessage>>>) {
     const TASK_WAKEUP_PERIOD: Duration = Duration::from_millis(20);
 
@@ -120,42 +155,103 @@ fn spawn_thread(
         .stack_size(64 * 1024)
         .spawn(move || {
             let mut stats = Stats::default();
+            let mut protocol = NodeProtocol::default();
 
+            // The thread is paced by the system time, while messages are
+            // timestamped with the application clock.
             loop {
                 let start = Instant::now();
 
-                // log::info!("node update");
-
                 let next_wakeup = Instant::now() + TASK_WAKEUP_PERIOD;
 
-                if let Some(tx_msg) = tx_copy.try_lock().ok().and_then(|x| x.clone()) {
-                    if sender
-                        .send_to(tx_msg.data().as_bytes(), broadcast_addr)
-                        .is_ok()
-                    {
-                        stats.tx_count += 1;
-                    }
+                let tx_msg = tx_copy.try_lock().ok().and_then(|x| x.clone());
+
+                let messages = state
+                    .try_modify(|x| protocol.poll(x, tx_msg.as_ref(), clock.now()))
+                    .unwrap_or_else(|| tx_msg.iter().cloned().collect());
+
+                for msg in &messages {
+                    send(transport.as_mut(), system_id, &mut auth, msg, &mut stats);
                 }
 
-                // receive_message should lock for the timeout set to the udp socket
-                while let Ok(rx_msg) = receive_message(&mut receiver) {
+                // Messages are received as soon as they arrive, so they can
+                // be timestamped for clock synchronization.
+                while let Some(timeout) = next_wakeup.checked_duration_since(Instant::now()) {
+                    let Ok(Some(data)) = transport.receive(timeout) else {
+                        continue;
+                    };
+
+                    let rx_instant = clock.now();
+
+                    // Other traffic on the same port is not a foreign system
+                    if let Err(e) = data.check() {
+                        log::debug!("Invalid frame: {:?}", e);
+                        stats.rx_error_count += 1;
+                        continue;
+                    }
+
+                    if data.system_id() != system_id {
+                        if stats.rx_foreign_count == 0 {
+                            log::warn!("Ignoring frames of system {:?}", data.system_id());
+                        }
+                        stats.rx_foreign_count += 1;
+                        state.try_modify(|x| x.on_foreign_frame(data.system_id(), rx_instant));
+                        continue;
+                    }
+
+                    match auth.verify(&data) {
+                        Ok(()) => {}
+                        Err(AuthError::BadMac) => {
+                            stats.rx_unauthenticated_count += 1;
+                            continue;
+                        }
+                        Err(AuthError::Replayed) => {
+                            stats.rx_replay_count += 1;
+                            continue;
+                        }
+                    }
+
+                    let rx_msg = match RaceNodeMessage::try_from(data) {
+                        Ok(x) => x,
+                        Err(Error::UnsupportedVersion(version)) => {
+                            // Reported once, the other node keeps sending
+                            if stats.rx_version_mismatch_count == 0 {
+                                log::warn!(
+                                    "Ignoring node with protocol version {}, expected {}",
+                                    version,
+                                    RaceNodeMessage::PROTOCOL_VERSION
+                                );
+                            }
+                            stats.rx_version_mismatch_count += 1;
+                            continue;
+                        }
+                        Err(e) => {
+                            log::debug!("Invalid frame: {:?}", e);
+                            stats.rx_error_count += 1;
+                            continue;
+                        }
+                    };
+
                     log::debug!("{:?}", rx_msg);
                     stats.rx_count += 1;
 
-                    match rx_msg {
-                        RaceNodeMessage::GateBeacon(beacon) => state.try_modify(|x| {
-                            update_gate(&mut x.gates, &beacon, x.coordinator_time.into_option())
-                        }),
-                        RaceNodeMessage::CoordinatorBeacon(beacon) => state.try_modify(|x| {
-                            x.coordinator_time = ExpOpt::<CoordinatedInstant>::new_with_duration(
-                                beacon.time,
-                                COORDINATOR_BEACON_TIMEOUT,
-                            );
-                            x.coordinator_beacon_time = Some(Instant::now());
-                        }),
+                    let response = state
+                        .try_modify(|x| protocol.receive(x, rx_msg, rx_instant, clock.now()))
+                        .flatten();
+
+                    if let Some(response) = response {
+                        send(
+                            transport.as_mut(),
+                            system_id,
+                            &mut auth,
+                            &response,
+                            &mut stats,
+                        );
                     }
                 }
 
+                state.try_modify(|x| x.set_stats(stats.summary()));
+
                 if !continue_running.load(Ordering::Acquire) {
                     break;
                 }
@@ -164,13 +260,6 @@ fn spawn_thread(
                     "node update took {}ms",
                     (Instant::now() - start).as_millis()
                 );
-
-                // Ensure this task is not spinning
-                if let Some(delay) = next_wakeup.checked_duration_since(Instant::now()) {
-                    sleep(delay);
-                } else {
-                    log::error!("no delay");
-                }
             }
             stats
         })
@@ -179,67 +268,39 @@ fn spawn_thread(
     (thread, tx)
 }
 
-fn make_receiver(receiver_addr: SocketAddr) -> anyhow::Result<UdpSocket> {
-    let receiver = UdpSocket::bind(receiver_addr)?;
-    receiver.set_broadcast(true)?;
-
-    // This must be non blocking, otherwise the thread may be locked.
-    // It is not important that all messages are successfully sent.
-    receiver.set_nonblocking(true)?;
-
-    log::info!("receiver {:?}", receiver.local_addr());
-    Ok(receiver)
-}
-
-fn make_sender(sender_addr: SocketAddr) -> anyhow::Result<UdpSocket> {
-    let sender = UdpSocket::bind(sender_addr)?;
-    sender.set_broadcast(true)?;
-
-    // This must be non blocking, otherwise the thread may be locked.
-    // It is not important that all messages are successfully sent.
-    sender.set_nonblocking(true)?;
-
-    log::info!("sender {:?}", sender.local_addr());
-    Ok(sender)
-}
-
-fn receive_message(receiver: &mut UdpSocket) -> anyhow::Result<RaceNodeMessage> {
-    let mut buf = [0u8; RaceNodeMessage::FRAME_SIZE];
-
-    if let Ok((number_of_bytes, _src_addr)) = receiver.recv_from(&mut buf) {
-        if number_of_bytes == RaceNodeMessage::FRAME_SIZE {
-            let data = FrameData::from(buf);
-            RaceNodeMessage::try_from(data).map_err(|_| anyhow!("Cannot parse"))
-        } else {
-            Err(anyhow!("Wrong number of bytes"))
-        }
-    } else {
-        Err(anyhow!("No messages"))
+fn send(
+    transport: &mut dyn Transport,
+    system_id: SystemId,
+    auth: &mut Authenticator,
+    msg: &RaceNodeMessage,
+    stats: &mut Stats,
+) {
+    let mut data = msg.data();
+    data.set_system_id(system_id);
+    auth.sign(&mut data);
+
+    if transport.send(&data).is_ok() {
+        stats.tx_count += 1;
     }
 }
 
 impl RaceNode for StdRaceNode {
     fn set_coordinator_time(&self, t: CoordinatedInstant) {
-        self.state.try_modify(|x| {
-            // This timeout must be very strict, because set_coordinator_time is
-            // called when the node is a coordinator.
-            const TIMEOUT: Duration = Duration::from_millis(100);
-
-            if let Some(expiration) = x.coordinator_time.expiration {
-                let now = Instant::now();
-                if now > expiration {
-                    log::warn!("set_coordinator_time called too late");
-                }
-            }
-
-            x.coordinator_time = ExpOpt::<CoordinatedInstant>::new_with_duration(t, TIMEOUT)
-        })
+        let now = self.clock.now();
+        self.state.try_modify(|x| x.set_coordinator_time(t, now));
     }
 
     fn coordinator_time(&self) -> Option<CoordinatedInstant> {
-        self.state
-            .read(|x| x.coordinator_time.into_option())
-            .flatten()
+        let now = self.clock.now();
+        self.state.read(|x| x.coordinator_time(now)).flatten()
+    }
+
+    fn clock_sync(&self) -> Option<ClockSync> {
+        self.state.read(|x| x.clock_sync()).flatten()
+    }
+
+    fn sync_loss_percent(&self) -> u8 {
+        self.state.read(|x| x.sync_loss_percent()).unwrap_or(100)
     }
 
     fn publish(&self, msg: RaceNodeMessage) -> anyhow::Result<()> {
@@ -249,24 +310,77 @@ impl RaceNode for StdRaceNode {
             .map_err(|_| anyhow!("Cannot publish"))
     }
 
+    fn roster(&self) -> Roster {
+        self.state.read(|x| x.roster()).unwrap_or_default()
+    }
+
     fn gates(&self) -> Gates {
-        self.state.read(|x| x.gates.to_owned()).unwrap()
+        self.state.read(|x| x.gates()).unwrap()
     }
 
     fn time_since_coordinator_beacon(&self) -> Duration {
+        let now = self.clock.now();
         self.state
-            .read(|x| x.coordinator_beacon_time)
-            .flatten()
-            .and_then(|instant| Instant::now().checked_duration_since(instant))
+            .read(|x| x.time_since_coordinator_beacon(now))
             .unwrap_or(Duration::MAX)
     }
-}
 
-#[derive(Default)]
-struct NodesState {
-    coordinator_time: ExpOpt<CoordinatedInstant>,
-    coordinator_beacon_time: Option<Instant>,
-    gates: Gates,
+    fn foreign_systems(&self) -> Vec<SystemId> {
+        let now = self.clock.now();
+        self.state
+            .read(|x| x.foreign_systems(now))
+            .unwrap_or_default()
+    }
+
+    fn send_activation(
+        &self,
+        addr: NodeAddress,
+        time: CoordinatedInstant,
+        continued: bool,
+        recovered: bool,
+    ) {
+        // Waiting for the lock, activations must not be lost
+        if self
+            .state
+            .modify(|x| x.send_activation(addr, time, continued, recovered))
+            .is_none()
+        {
+            log::error!("Cannot send activation");
+        }
+    }
+
+    fn pending_activations(&self) -> usize {
+        self.state.read(|x| x.pending_activations()).unwrap_or(0)
+    }
+
+    fn send_command(&self, addr: NodeAddress, command: GateCommand) {
+        // Waiting for the lock, commands must not be lost
+        if self
+            .state
+            .modify(|x| x.send_command(addr, command))
+            .is_none()
+        {
+            log::error!("Cannot send command");
+        }
+    }
+
+    fn take_commands(&self) -> Vec<GateCommand> {
+        self.state.modify(|x| x.take_commands()).unwrap_or_default()
+    }
+
+    fn take_activations(&self) -> Vec<GateActivation> {
+        self.state
+            .modify(|x| x.take_activations())
+            .unwrap_or_default()
+    }
+
+    fn stats(&self) -> NodeStats {
+        self.state.read(|x| x.stats()).unwrap_or_default()
+    }
+
+    fn send_diagnostics(&self, diagnostics: GateDiagnostics) {
+        self.state.try_modify(|x| x.send_diagnostics(diagnostics));
+    }
 }
 
 #[derive(Clone)]
@@ -279,12 +393,19 @@ impl Default for SharedNodeState {
 }
 
 impl SharedNodeState {
-    fn try_modify<F>(&self, f: F)
+    fn try_modify<F, T>(&self, f: F) -> Option<T>
     where
-        F: FnOnce(&mut NodesState),
+        F: FnOnce(&mut NodesState) -> T,
     {
         // Ignore errors
-        self.0.try_lock().map(|mut x| f(x.deref_mut())).ok();
+        self.0.try_lock().map(|mut x| f(x.deref_mut())).ok()
+    }
+
+    fn modify<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut NodesState) -> T,
+    {
+        self.0.lock().map(|mut x| f(x.deref_mut())).ok()
     }
 
     fn read<F, T>(&self, f: F) -> Option<T>
@@ -295,87 +416,77 @@ impl SharedNodeState {
     }
 }
 
-fn update_gate(gates: &mut Gates, gate: &GateBeacon, coordinated_time: Option<CoordinatedInstant>) {
-    let &GateBeacon {
-        addr,
-        state,
-        last_activation_time,
-    } = gate;
-    if let Some(gate) = gates.get_mut_from_addr(addr) {
-        gate.active = state == GateState::Active;
-        gate.last_activation_time = last_activation_time;
-        gate.last_beacon_time = coordinated_time;
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::hal::clock::SystemClock;
+    use crate::hal::gate::GateState;
+    use crate::svc::race_node::{
+        CoordinatorBeacon, FrameData, GateBeacon, HardwareId, NodeAddress, RaceNode,
+        RaceNodeMessage, SystemId,
+    };
+    use crate::svc::transport::{ChannelNetwork, Transport, UdpBroadcastTransport};
+    use crate::svc::{CoordinatedInstant, NetworkKey, NodeConfig, StdRaceNode};
+
+    fn make_node(transport: impl Transport + 'static) -> StdRaceNode {
+        make_node_with_key(transport, "racegate")
     }
-}
 
-#[derive(Default, Copy, Clone)]
-struct ExpOpt<T> {
-    value: Option<T>,
-    expiration: Option<std::time::Instant>,
-}
-
-impl<T> ExpOpt<T> {
-    fn new_with_expiration(value: T, expiration: std::time::Instant) -> Self {
-        Self {
-            value: Some(value),
-            expiration: Some(expiration),
-        }
+    fn make_node_with_key(transport: impl Transport + 'static, passphrase: &str) -> StdRaceNode {
+        make_node_with_config(
+            transport,
+            NodeConfig {
+                key: NetworkKey::from_passphrase(passphrase),
+                system_id: SystemId::default(),
+            },
+        )
     }
-    fn new_with_duration(value: T, duration: std::time::Duration) -> Self {
-        Self::new_with_expiration(value, std::time::Instant::now() + duration)
-    }
-
-    fn into_option(self) -> Option<T> {
-        let now = std::time::Instant::now();
 
-        let expired = if let Some(expiration) = self.expiration {
-            expiration < now
-        } else {
-            false
-        };
-
-        if expired {
-            log::trace!(
-                "Expired {}ms ago",
-                now.duration_since(self.expiration.unwrap()).as_millis()
-            );
-            None
-        } else {
-            self.value
-        }
+    fn make_node_with_config(
+        transport: impl Transport + 'static,
+        config: NodeConfig,
+    ) -> StdRaceNode {
+        StdRaceNode::new_with_transport(Box::new(transport), config, Arc::new(SystemClock))
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
-
-    use crate::svc::race_node::{CoordinatorBeacon, RaceNode};
-    use crate::svc::std_race_node::StdRaceNodeConfig;
-    use crate::svc::{CoordinatedInstant, StdRaceNode};
 
     fn make_coordinator_node() -> StdRaceNode {
         // Broadcast does not work on localhost, so we just use different ports
+        let transport = UdpBroadcastTransport::new_with_addrs(
+            "0.0.0.0:0".parse().unwrap(),
+            "127.0.0.10:6699".parse().unwrap(),
+            "127.0.0.10:6698".parse().unwrap(),
+        )
+        .unwrap();
 
-        let cfg = StdRaceNodeConfig {
-            sender_addr: "0.0.0.0:0".parse().unwrap(),
-            receiver_addr: "127.0.0.10:6699".parse().unwrap(),
-            broadcast_addr: "127.0.0.10:6698".parse().unwrap(),
-        };
-
-        StdRaceNode::new_with_config(cfg).unwrap()
+        make_node(transport)
     }
 
     fn make_start_node() -> StdRaceNode {
         // Broadcast does not work on localhost, so we just use different ports
+        let transport = UdpBroadcastTransport::new_with_addrs(
+            "0.0.0.0:0".parse().unwrap(),
+            "127.0.0.10:6698".parse().unwrap(),
+            "127.0.0.10:6699".parse().unwrap(),
+        )
+        .unwrap();
+
+        make_node(transport)
+    }
 
-        let cfg = StdRaceNodeConfig {
-            sender_addr: "0.0.0.0:0".parse().unwrap(),
-            receiver_addr: "127.0.0.10:6698".parse().unwrap(),
-            broadcast_addr: "127.0.0.10:6699".parse().unwrap(),
-        };
+    /// Wait until `f` is true, for at most one second
+    fn wait_until(f: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
 
-        StdRaceNode::new_with_config(cfg).unwrap()
+        while Instant::now() < deadline {
+            if f() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        false
     }
 
     #[ignore]
@@ -389,6 +500,7 @@ mod tests {
             .publish(
                 CoordinatorBeacon {
                     time: CoordinatedInstant::from_millis(123),
+                    hardware_id: HardwareId::default(),
                 }
                 .into(),
             )
@@ -405,4 +517,134 @@ mod tests {
         assert!(start_stats.rx_count > 0);
         assert_eq!(start_stats.tx_count, 0);
     }
+
+    #[test_log::test]
+    fn test_nodes_talk_over_channels() {
+        let network = ChannelNetwork::default();
+        let coordinator_node = make_node(network.connect());
+        let start_node = make_node(network.connect());
+
+        let addr = NodeAddress::from(1);
+
+        coordinator_node
+            .publish(
+                CoordinatorBeacon {
+                    time: CoordinatedInstant::from_millis(123),
+                    hardware_id: HardwareId::default(),
+                }
+                .into(),
+            )
+            .unwrap();
+
+        start_node
+            .publish(
+                GateBeacon {
+                    addr,
+                    hardware_id: HardwareId::default(),
+                    state: GateState::Active,
+                    sync: None,
+                    role: None,
+                }
+                .into(),
+            )
+            .unwrap();
+
+        assert!(wait_until(|| start_node.coordinator_time().is_some()));
+        assert!(wait_until(|| coordinator_node
+            .gates()
+            .get(addr)
+            .map(|x| x.is_active())
+            .unwrap_or(false)));
+    }
+
+    #[test_log::test]
+    fn test_nodes_with_another_key_are_ignored() {
+        let network = ChannelNetwork::default();
+        let coordinator_node = make_node_with_key(network.connect(), "secret");
+        let mut start_node = make_node_with_key(network.connect(), "forged");
+
+        coordinator_node
+            .publish(
+                CoordinatorBeacon {
+                    time: CoordinatedInstant::from_millis(123),
+                    hardware_id: HardwareId::default(),
+                }
+                .into(),
+            )
+            .unwrap();
+
+        assert!(!wait_until(|| start_node.coordinator_time().is_some()));
+
+        let stats = start_node.stop().unwrap();
+        assert_eq!(stats.rx_count, 0);
+        assert!(stats.rx_unauthenticated_count > 0);
+    }
+
+    #[test_log::test]
+    fn test_frames_of_other_systems_are_ignored() {
+        let network = ChannelNetwork::default();
+        let key = NetworkKey::from_passphrase("racegate");
+
+        let coordinator_node = make_node_with_config(
+            network.connect(),
+            NodeConfig {
+                key,
+                system_id: SystemId(1),
+            },
+        );
+        let mut start_node = make_node_with_config(
+            network.connect(),
+            NodeConfig {
+                key,
+                system_id: SystemId(2),
+            },
+        );
+
+        coordinator_node
+            .publish(
+                CoordinatorBeacon {
+                    time: CoordinatedInstant::from_millis(123),
+                    hardware_id: HardwareId::default(),
+                }
+                .into(),
+            )
+            .unwrap();
+
+        assert!(wait_until(
+            || start_node.foreign_systems() == vec![SystemId(1)]
+        ));
+        assert!(start_node.coordinator_time().is_none());
+
+        let stats = start_node.stop().unwrap();
+        assert_eq!(stats.rx_count, 0);
+        assert!(stats.rx_foreign_count > 0);
+    }
+
+    #[test_log::test]
+    fn test_other_traffic_is_not_a_foreign_system() {
+        let network = ChannelNetwork::default();
+        let mut other = network.connect();
+        let mut node = make_node(network.connect());
+
+        let data = FrameData::from([0x55; RaceNodeMessage::FRAME_SIZE]);
+        other.send(&data).unwrap();
+
+        let mut corrupted = RaceNodeMessage::from(CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(123),
+            hardware_id: HardwareId::default(),
+        })
+        .data();
+        corrupted.set_system_id(SystemId(1));
+        let mut bytes = [0; RaceNodeMessage::FRAME_SIZE];
+        bytes.copy_from_slice(corrupted.as_bytes());
+        bytes[10] ^= 0x10;
+        other.send(&FrameData::from(bytes)).unwrap();
+
+        std::thread::sleep(Duration::from_millis(200));
+
+        assert!(node.foreign_systems().is_empty());
+        let stats = node.stop().unwrap();
+        assert_eq!(stats.rx_foreign_count, 0);
+        assert_eq!(stats.rx_error_count, 2);
+    }
 }
@@ -0,0 +1,241 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use crate::svc::race_node::{FrameData, RaceNodeMessage};
+
+/// Link used by a race node to exchange frames with the other nodes. Frames are
+/// sent to all the other nodes and may be lost.
+pub trait Transport: Send {
+    fn send(&mut self, data: &FrameData) -> anyhow::Result<()>;
+
+    /// Wait a frame for at most `timeout`. The caller timestamps the frame as
+    /// soon as this returns, so it must not be delayed.
+    fn receive(&mut self, timeout: Duration) -> anyhow::Result<Option<FrameData>>;
+}
+
+/// UDP broadcast on the local network, the default transport
+pub struct UdpBroadcastTransport {
+    sender: UdpSocket,
+    receiver: UdpSocket,
+    broadcast_addr: SocketAddr,
+}
+
+impl UdpBroadcastTransport {
+    pub const PORT: u16 = 6699;
+
+    pub fn new() -> anyhow::Result<Self> {
+        Self::new_with_addrs(
+            "0.0.0.0:0".parse()?,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, Self::PORT).into(),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, Self::PORT).into(),
+        )
+    }
+
+    pub fn new_with_addrs(
+        sender_addr: SocketAddr,
+        receiver_addr: SocketAddr,
+        broadcast_addr: SocketAddr,
+    ) -> anyhow::Result<Self> {
+        let sender = make_sender(sender_addr)?;
+        sender.set_broadcast(true)?;
+
+        let receiver = make_receiver(receiver_addr)?;
+        receiver.set_broadcast(true)?;
+
+        Ok(Self {
+            sender,
+            receiver,
+            broadcast_addr,
+        })
+    }
+}
+
+impl Transport for UdpBroadcastTransport {
+    fn send(&mut self, data: &FrameData) -> anyhow::Result<()> {
+        self.sender.send_to(data.as_bytes(), self.broadcast_addr)?;
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> anyhow::Result<Option<FrameData>> {
+        receive_frame(&self.receiver, timeout)
+    }
+}
+
+/// UDP multicast, for networks where broadcast is filtered
+pub struct UdpMulticastTransport {
+    sender: UdpSocket,
+    receiver: UdpSocket,
+    group_addr: SocketAddr,
+}
+
+impl UdpMulticastTransport {
+    pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 66, 99);
+
+    pub fn new() -> anyhow::Result<Self> {
+        Self::new_with_group(
+            Self::GROUP,
+            UdpBroadcastTransport::PORT,
+            Ipv4Addr::UNSPECIFIED,
+        )
+    }
+
+    /// Join `group` on the network `interface`, unspecified to let the system
+    /// choose it
+    pub fn new_with_group(group: Ipv4Addr, port: u16, interface: Ipv4Addr) -> anyhow::Result<Self> {
+        if !group.is_multicast() {
+            return Err(anyhow!("{group} is not a multicast address"));
+        }
+
+        let sender = make_sender(SocketAddrV4::new(interface, 0).into())?;
+        // Frames sent by this node must not come back
+        sender.set_multicast_loop_v4(false)?;
+
+        let receiver = make_receiver(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+        receiver.join_multicast_v4(&group, &interface)?;
+
+        Ok(Self {
+            sender,
+            receiver,
+            group_addr: SocketAddrV4::new(group, port).into(),
+        })
+    }
+}
+
+impl Transport for UdpMulticastTransport {
+    fn send(&mut self, data: &FrameData) -> anyhow::Result<()> {
+        self.sender.send_to(data.as_bytes(), self.group_addr)?;
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> anyhow::Result<Option<FrameData>> {
+        receive_frame(&self.receiver, timeout)
+    }
+}
+
+/// Network of in-process channels, to run many nodes in the same process
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    nodes: Arc<Mutex<Vec<Sender<FrameData>>>>,
+}
+
+impl ChannelNetwork {
+    /// Add a node to the network
+    pub fn connect(&self) -> ChannelTransport {
+        let (sender, receiver) = channel();
+
+        let mut nodes = self.nodes.lock().unwrap();
+        let index = nodes.len();
+        nodes.push(sender);
+
+        ChannelTransport {
+            index,
+            network: self.clone(),
+            receiver,
+        }
+    }
+}
+
+pub struct ChannelTransport {
+    index: usize,
+    network: ChannelNetwork,
+    receiver: Receiver<FrameData>,
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, data: &FrameData) -> anyhow::Result<()> {
+        let nodes = self
+            .network
+            .nodes
+            .lock()
+            .map_err(|_| anyhow!("Cannot lock"))?;
+
+        for (index, node) in nodes.iter().enumerate() {
+            if index != self.index {
+                // Nodes that are gone just don't receive
+                let _ = node.send(data.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> anyhow::Result<Option<FrameData>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(data) => Ok(Some(data)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("Disconnected")),
+        }
+    }
+}
+
+fn make_receiver(receiver_addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let receiver = UdpSocket::bind(receiver_addr)?;
+
+    // Blocking with a timeout, so messages are received as soon as they
+    // arrive, without locking the thread forever.
+    receiver.set_nonblocking(false)?;
+
+    log::info!("receiver {:?}", receiver.local_addr());
+    Ok(receiver)
+}
+
+fn make_sender(sender_addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let sender = UdpSocket::bind(sender_addr)?;
+
+    // This must be non blocking, otherwise the thread may be locked.
+    // It is not important that all messages are successfully sent.
+    sender.set_nonblocking(true)?;
+
+    log::info!("sender {:?}", sender.local_addr());
+    Ok(sender)
+}
+
+/// Wait a frame for at most `timeout`
+fn receive_frame(receiver: &UdpSocket, timeout: Duration) -> anyhow::Result<Option<FrameData>> {
+    let mut buf = [0u8; RaceNodeMessage::FRAME_SIZE];
+
+    // Zero is not a valid timeout
+    receiver.set_read_timeout(Some(timeout.max(Duration::from_micros(1))))?;
+
+    let Ok((number_of_bytes, _src_addr)) = receiver.recv_from(&mut buf) else {
+        return Ok(None);
+    };
+
+    if number_of_bytes == RaceNodeMessage::FRAME_SIZE {
+        Ok(Some(FrameData::from(buf)))
+    } else {
+        Err(anyhow!("Wrong number of bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::race_node::{CoordinatorBeacon, HardwareId};
+    use crate::svc::CoordinatedInstant;
+
+    #[test]
+    fn test_channel_transport_sends_to_other_nodes() {
+        let network = ChannelNetwork::default();
+        let mut a = network.connect();
+        let mut b = network.connect();
+        let mut c = network.connect();
+
+        let msg: RaceNodeMessage = CoordinatorBeacon {
+            time: CoordinatedInstant::from_millis(123),
+            hardware_id: HardwareId::default(),
+        }
+        .into();
+
+        a.send(&msg.data()).unwrap();
+
+        let timeout = Duration::from_millis(10);
+        assert!(a.receive(timeout).unwrap().is_none());
+        assert_eq!(b.receive(timeout).unwrap(), Some(msg.data()));
+        assert_eq!(c.receive(timeout).unwrap(), Some(msg.data()));
+    }
+}